@@ -0,0 +1,110 @@
+use crate::s3::S3Location;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// A mapping from the buckets listed in an inventory to the buckets from which
+/// their objects should actually be downloaded
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct BucketMap(HashMap<String, String>);
+
+impl BucketMap {
+    /// If the bucket of `url` is mapped to another bucket, return a copy of
+    /// `url` (including its version ID) with the bucket replaced.
+    pub(crate) fn remap(&self, url: &S3Location) -> Option<S3Location> {
+        self.0
+            .get(url.bucket())
+            .map(|bucket| url.with_bucket(bucket.clone()))
+    }
+}
+
+impl FromIterator<BucketMapping> for BucketMap {
+    fn from_iter<I: IntoIterator<Item = BucketMapping>>(iter: I) -> BucketMap {
+        BucketMap(
+            iter.into_iter()
+                .map(|BucketMapping { source, target }| (source, target))
+                .collect(),
+        )
+    }
+}
+
+/// A single `{source}={target}` bucket mapping, as passed to
+/// `--source-bucket-map`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct BucketMapping {
+    /// The bucket listed in the inventory
+    source: String,
+
+    /// The bucket from which to download objects instead
+    target: String,
+}
+
+impl std::str::FromStr for BucketMapping {
+    type Err = ParseBucketMappingError;
+
+    fn from_str(s: &str) -> Result<BucketMapping, ParseBucketMappingError> {
+        let Some((source, target)) = s.split_once('=') else {
+            return Err(ParseBucketMappingError::NoEquals);
+        };
+        let (source, target) = (source.trim(), target.trim());
+        if source.is_empty() || target.is_empty() {
+            return Err(ParseBucketMappingError::Empty);
+        }
+        Ok(BucketMapping {
+            source: source.to_owned(),
+            target: target.to_owned(),
+        })
+    }
+}
+
+/// Error returned when parsing an invalid bucket mapping
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub(crate) enum ParseBucketMappingError {
+    /// The mapping did not contain an `=`
+    #[error("expected a mapping of the form SOURCE=TARGET")]
+    NoEquals,
+
+    /// One side of the mapping was empty
+    #[error("bucket names in mapping cannot be empty")]
+    Empty,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("primary=replica", "primary", "replica")]
+    #[case(" primary = replica ", "primary", "replica")]
+    fn parse_mapping(#[case] s: &str, #[case] source: &str, #[case] target: &str) {
+        assert_eq!(
+            s.parse::<BucketMapping>(),
+            Ok(BucketMapping {
+                source: source.into(),
+                target: target.into(),
+            })
+        );
+    }
+
+    #[rstest]
+    #[case("primary", ParseBucketMappingError::NoEquals)]
+    #[case("=replica", ParseBucketMappingError::Empty)]
+    #[case("primary=", ParseBucketMappingError::Empty)]
+    fn parse_mapping_err(#[case] s: &str, #[case] err: ParseBucketMappingError) {
+        assert_eq!(s.parse::<BucketMapping>(), Err(err));
+    }
+
+    #[test]
+    fn remap() {
+        let map = ["primary=replica".parse::<BucketMapping>().unwrap()]
+            .into_iter()
+            .collect::<BucketMap>();
+        let url = S3Location::new("primary".into(), "foo/bar.txt".into()).with_version_id("abc");
+        assert_eq!(
+            map.remap(&url).unwrap().to_string(),
+            "s3://replica/foo/bar.txt?versionId=abc"
+        );
+        let other = S3Location::new("other".into(), "foo/bar.txt".into());
+        assert_eq!(map.remap(&other), None);
+    }
+}
@@ -1,10 +1,47 @@
+use std::time::Duration;
+
 /// The name of the file in which metadata (version ID and etag) are stored for
 /// the latest versions of objects in each directory
 pub(crate) static METADATA_FILENAME: &str = ".s3invsync.versions.json";
 
+/// The name of the file in which the original key components of entries in
+/// each directory are stored for entries whose local names differ from them
+pub(crate) static NAMES_FILENAME: &str = ".s3invsync.names.json";
+
 /// Prefix for all special filenames created by s3invsync
 pub(crate) static RESERVED_PREFIX: &str = ".s3invsync";
 
-/// The number of initial bytes of an inventory csv.gz file to fetch when
-/// peeking at just the first entry
+/// Prefix for the local names of escaped key components
+pub(crate) static ESCAPE_PREFIX: &str = ".s3invsync.esc.";
+
+/// Prefix for the names of the per-run temporary directories created inside
+/// the directory given by `--tmpdir`
+pub(crate) static RUN_TMPDIR_PREFIX: &str = "s3invsync.";
+
+/// The maximum length in bytes of a local filename.  Longer names are
+/// shortened with [`crate::keypath::shorten_name()`].
+pub(crate) const MAX_NAME_LEN: usize = 255;
+
+/// The number of initial bytes of an inventory csv.gz file to initially fetch
+/// when peeking at just the first entry.  If this is not enough to decode the
+/// entry, the number is doubled until it is.
 pub(crate) const CSV_GZIP_PEEK_SIZE: usize = 1024;
+
+/// The maximum number of entries from an unsorted inventory list file to hold
+/// in memory at once when sorting the file
+pub(crate) const SORT_CHUNK_SIZE: usize = 100_000;
+
+/// The maximum number of runs recorded in the run history in the state file.
+/// When a run is recorded and the history is full, the oldest entry is
+/// discarded.
+pub(crate) const MAX_RUN_HISTORY: usize = 100;
+
+/// How often to redraw the progress line under `--progress tty`
+pub(crate) const PROGRESS_TTY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to log the progress of a run under `--progress log`
+pub(crate) const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The span of time over which the throughput shown by `--progress` is
+/// measured
+pub(crate) const THROUGHPUT_WINDOW: Duration = Duration::from_secs(30);
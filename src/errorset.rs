@@ -1,4 +1,5 @@
 use crate::s3::DownloadError;
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use thiserror::Error;
 
@@ -28,21 +29,18 @@ impl ErrorSet {
         e: &DownloadError,
         is_old_version: bool,
     ) -> Option<DownloadWarning> {
-        let DownloadError::Get(ref ge) = e else {
-            return None;
-        };
-        if ge.is_404() && self.missing_old_version && is_old_version {
+        if e.is_404() && self.missing_old_version && is_old_version {
             Some(DownloadWarning::MissingOldVersion)
-        } else if ge.is_403() && self.access_denied {
+        } else if e.is_403() && self.access_denied {
             Some(DownloadWarning::AccessDenied)
-        } else if ge.is_invalid_object_state() && self.invalid_object_state {
+        } else if e.is_invalid_object_state() && self.invalid_object_state {
             Some(DownloadWarning::InvalidObjectState)
         } else {
             None
         }
     }
 
-    fn all() -> ErrorSet {
+    pub(crate) fn all() -> ErrorSet {
         ErrorSet {
             access_denied: true,
             invalid_entry: true,
@@ -75,7 +73,23 @@ impl std::str::FromStr for ErrorSet {
 #[error("invalid error type {0:?}")]
 pub(crate) struct ParseErrorSetError(String);
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// A type of download error that was ignored due to `--ignore-errors`.  When
+/// serialized, the variants are named the same as in `--ignore-errors`.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    Hash,
+    strum::IntoStaticStr,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
 pub(crate) enum DownloadWarning {
     AccessDenied,
     InvalidObjectState,
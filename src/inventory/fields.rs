@@ -63,6 +63,16 @@ pub(crate) struct FileSchema {
 }
 
 impl FileSchema {
+    /// Given a row of strings from an inventory list CSV file, return the
+    /// decoded key, or `None` if the row lacks a valid key
+    pub(crate) fn csv_key(&self, values: &[String]) -> Option<String> {
+        let key = values.get(self.key_index)?;
+        percent_encoding::percent_decode_str(key)
+            .decode_utf8()
+            .ok()
+            .map(std::borrow::Cow::into_owned)
+    }
+
     /// Given a row of strings from an inventory list CSV file, parse them into
     /// an [`InventoryEntry`] according to the file schema
     pub(crate) fn parse_csv_fields(
@@ -92,6 +102,8 @@ impl FileSchema {
         let mut is_delete_marker = None;
         let mut size = None;
         let mut last_modified_date = None;
+        let mut storage_class = None;
+        let mut access_tier = None;
         let mut etag_is_md5 = true;
         for (&field, value) in std::iter::zip(&self.fields, values) {
             match field {
@@ -184,7 +196,11 @@ impl FileSchema {
                         etag_is_md5 = false;
                     }
                 }
-                InventoryField::StorageClass => (),
+                InventoryField::StorageClass => {
+                    if !value.is_empty() {
+                        storage_class = Some(value);
+                    }
+                }
                 InventoryField::ReplicationStatus => (),
                 InventoryField::EncryptionStatus => {
                     if !matches!(value.as_str(), "NOT-SSE" | "SSE-S3") {
@@ -194,7 +210,11 @@ impl FileSchema {
                 InventoryField::ObjectLockRetainUntilDate => (),
                 InventoryField::ObjectLockMode => (),
                 InventoryField::ObjectLockLegalHoldStatus => (),
-                InventoryField::IntelligentTieringAccessTier => (),
+                InventoryField::IntelligentTieringAccessTier => {
+                    if !value.is_empty() {
+                        access_tier = Some(value);
+                    }
+                }
                 InventoryField::BucketKeyStatus => (),
                 InventoryField::ChecksumAlgorithm => (),
                 InventoryField::ObjectAccessControlList => (),
@@ -222,6 +242,8 @@ impl FileSchema {
                 version_id,
                 is_latest,
                 last_modified_date,
+                storage_class,
+                access_tier,
                 details: ItemDetails::Deleted,
             }))
         } else {
@@ -234,6 +256,8 @@ impl FileSchema {
                 version_id,
                 is_latest,
                 last_modified_date,
+                storage_class,
+                access_tier,
                 details: ItemDetails::Present {
                     size,
                     etag,
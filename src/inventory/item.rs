@@ -1,6 +1,7 @@
-use crate::keypath::KeyPath;
+use crate::keypath::{local_name, KeyPath};
 use crate::s3::S3Location;
 use crate::util::make_old_filename;
+use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
 /// An entry in an inventory list file
@@ -24,15 +25,15 @@ impl InventoryEntry {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) struct Directory {
     /// The bucket on which the object is located
-    pub(super) bucket: String,
+    pub(crate) bucket: String,
 
     /// The object's key (ends in '/')
     // Not a KeyPath, as the key ends in '/':
-    pub(super) key: String,
+    pub(crate) key: String,
 
     /// The object's version ID (`None` if the object was created on an
     /// unversioned bucket)
-    pub(super) version_id: Option<String>,
+    pub(crate) version_id: Option<String>,
 }
 
 impl Directory {
@@ -67,6 +68,12 @@ pub(crate) struct InventoryItem {
     /// The object's date of last modification
     pub(crate) last_modified_date: Option<OffsetDateTime>,
 
+    /// The object's storage class (e.g., `STANDARD` or `GLACIER`), if known
+    pub(crate) storage_class: Option<String>,
+
+    /// The object's S3 Intelligent-Tiering access tier, if known
+    pub(crate) access_tier: Option<String>,
+
     /// Metadata about the object's content
     pub(crate) details: ItemDetails,
 }
@@ -87,14 +94,46 @@ impl InventoryItem {
         self.details == ItemDetails::Deleted
     }
 
+    /// If the object is archived — i.e., it cannot be downloaded without first
+    /// being restored — return the archive tier that it is stored in
+    pub(crate) fn archive_tier(&self) -> Option<ArchiveTier> {
+        if self.is_deleted() {
+            return None;
+        }
+        match self.storage_class.as_deref() {
+            Some("GLACIER") => Some(ArchiveTier::Glacier),
+            Some("DEEP_ARCHIVE") => Some(ArchiveTier::DeepArchive),
+            Some("INTELLIGENT_TIERING") => match self.access_tier.as_deref() {
+                Some("ARCHIVE_ACCESS") => Some(ArchiveTier::ArchiveAccess),
+                Some("DEEP_ARCHIVE_ACCESS") => Some(ArchiveTier::DeepArchiveAccess),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the object's size, if known
+    pub(crate) fn size(&self) -> Option<i64> {
+        match self.details {
+            ItemDetails::Present { size, .. } => size,
+            ItemDetails::Deleted => None,
+        }
+    }
+
     /// If the object is not a delete marker and is not the latest version of
-    /// the key, return the base filename at which it will be backed up.
-    pub(crate) fn old_filename(&self) -> Option<String> {
+    /// the key, return the base filename at which it will be backed up.  See
+    /// [`local_name()`] for the meaning of `portable`.
+    pub(crate) fn old_filename(&self, portable: bool) -> Option<String> {
         let ItemDetails::Present { ref etag, .. } = self.details else {
             return None;
         };
-        (!self.is_latest)
-            .then(|| make_old_filename(self.key.name(), self.version_id.as_deref(), etag))
+        (!self.is_latest).then(|| {
+            make_old_filename(
+                &local_name(self.key.name(), portable),
+                self.version_id.as_deref(),
+                etag,
+            )
+        })
     }
 }
 
@@ -132,11 +171,34 @@ impl ItemDetails {
     }
 }
 
+/// A storage class or Intelligent-Tiering access tier from which objects
+/// cannot be downloaded without first being restored
+#[derive(
+    Clone, Copy, Debug, Deserialize, strum::Display, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[strum(serialize_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum ArchiveTier {
+    /// The S3 Glacier Flexible Retrieval storage class
+    Glacier,
+
+    /// The S3 Glacier Deep Archive storage class
+    DeepArchive,
+
+    /// The Archive Access tier of the S3 Intelligent-Tiering storage class
+    ArchiveAccess,
+
+    /// The Deep Archive Access tier of the S3 Intelligent-Tiering storage
+    /// class
+    DeepArchiveAccess,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::inventory::{CsvReader, FileSchema};
     use assert_matches::assert_matches;
+    use rstest::rstest;
     use time::macros::datetime;
 
     fn parse_csv(s: &str) -> InventoryEntry {
@@ -153,6 +215,8 @@ mod tests {
             r#""dandiarchive","zarr/73fb586f-b58a-49fc-876e-282ba962d310/0/0/0/14/4/100","nuYD8l5blCvLV3DbAiN1IXuwo7aF3F98","true","false","1511723","2022-12-12T13:20:39.000Z","627c47efe292876b91978324485cd2ec","false""#,
         );
         assert_matches!(entry, InventoryEntry::Item(item) => {
+            assert_eq!(item.storage_class, None);
+            assert_eq!(item.archive_tier(), None);
             assert_eq!(item.bucket, "dandiarchive");
             assert_eq!(
                 item.key,
@@ -215,6 +279,43 @@ mod tests {
         });
     }
 
+    #[rstest]
+    #[case("STANDARD", "", None)]
+    #[case("GLACIER_IR", "", None)]
+    #[case("GLACIER", "", Some(ArchiveTier::Glacier))]
+    #[case("DEEP_ARCHIVE", "", Some(ArchiveTier::DeepArchive))]
+    #[case("INTELLIGENT_TIERING", "FREQUENT", None)]
+    #[case("INTELLIGENT_TIERING", "ARCHIVE_INSTANT_ACCESS", None)]
+    #[case(
+        "INTELLIGENT_TIERING",
+        "ARCHIVE_ACCESS",
+        Some(ArchiveTier::ArchiveAccess)
+    )]
+    #[case(
+        "INTELLIGENT_TIERING",
+        "DEEP_ARCHIVE_ACCESS",
+        Some(ArchiveTier::DeepArchiveAccess)
+    )]
+    fn parse_storage_class(
+        #[case] storage_class: &str,
+        #[case] access_tier: &str,
+        #[case] archive_tier: Option<ArchiveTier>,
+    ) {
+        let file_schema = "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, LastModifiedDate, ETag, StorageClass, IntelligentTieringAccessTier".parse::<FileSchema>().unwrap();
+        let line = format!(
+            r#""dandiarchive","foo/bar.txt","nuYD8l5blCvLV3DbAiN1IXuwo7aF3F98","true","false","1511723","2022-12-12T13:20:39.000Z","627c47efe292876b91978324485cd2ec","{storage_class}","{access_tier}""#
+        );
+        let entry = CsvReader::new(line.as_bytes(), file_schema)
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_matches!(entry, InventoryEntry::Item(item) => {
+            assert_eq!(item.storage_class.as_deref(), Some(storage_class));
+            assert_eq!(item.access_tier.as_deref(), (!access_tier.is_empty()).then_some(access_tier));
+            assert_eq!(item.archive_tier(), archive_tier);
+        });
+    }
+
     #[test]
     fn parse_directory() {
         let entry = parse_csv(
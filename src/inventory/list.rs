@@ -1,52 +1,314 @@
 use super::fields::{FileSchema, ParseEntryError};
 use super::item::InventoryEntry;
+use super::merge::KeyMerge;
+use crate::consts::SORT_CHUNK_SIZE;
 use crate::s3::S3Location;
 use flate2::bufread::GzDecoder;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{BufRead, BufReader, Read, Seek, Write};
 use std::path::PathBuf;
 use thiserror::Error;
 
-/// A handle for reading entries from an inventory list file
+/// A handle for reading entries from an inventory list file.  The file is not
+/// opened until its first entry is read.
 pub(crate) struct InventoryList {
     /// The local path at which the file is located.  Used to delete the file
     /// on drop.
     path: PathBuf,
 
+    /// Whether the file at `path` should be deleted once it's no longer
+    /// needed.  This is false for files in the inventory cache.
+    temporary: bool,
+
+    /// Whether the entries in the file are sorted by key
+    sorted: bool,
+
     /// The S3 URL from which the inventory list was downloaded
     url: S3Location,
 
-    /// The inner filehandle
-    inner: CsvReader<GzDecoder<BufReader<File>>>,
+    /// The inner reader
+    inner: ListReader,
 }
 
 impl InventoryList {
-    /// Construct an `InventoryList` from a `CsvReader` reading from the file
-    /// at path `path` that was downloaded from `url`
+    /// Construct an `InventoryList` for reading the csv.gz file at path `path`
+    /// that was downloaded from `url`.  `sorted` indicates whether the file's
+    /// entries are sorted by key, as determined by a [`SortCheck`] while the
+    /// file was downloaded.
     pub(crate) fn for_downloaded_csv(
         path: PathBuf,
         url: S3Location,
-        inner: CsvReader<GzDecoder<BufReader<File>>>,
+        file_schema: FileSchema,
+        sorted: bool,
+    ) -> InventoryList {
+        InventoryList {
+            path,
+            temporary: true,
+            sorted,
+            url,
+            inner: ListReader::Unopened(file_schema),
+        }
+    }
+
+    /// Construct an `InventoryList` for reading the csv.gz file at path `path`
+    /// in the inventory cache that was downloaded from `url`.  Unlike with
+    /// [`InventoryList::for_downloaded_csv()`], the file is not deleted on
+    /// drop.
+    pub(crate) fn for_cached_csv(
+        path: PathBuf,
+        url: S3Location,
+        file_schema: FileSchema,
+        sorted: bool,
     ) -> InventoryList {
-        InventoryList { path, url, inner }
+        InventoryList {
+            path,
+            temporary: false,
+            sorted,
+            url,
+            inner: ListReader::Unopened(file_schema),
+        }
+    }
+
+    /// Returns whether the entries in the downloaded file were sorted by key.
+    /// This does not change when the entries are sorted by
+    /// [`InventoryList::into_sorted()`].
+    pub(crate) fn was_sorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// If the entries in the list file are not sorted by key, sort them by
+    /// splitting the file into chunks of at most [`SORT_CHUNK_SIZE`] entries,
+    /// sorting each chunk in memory, writing the sorted chunks to temporary
+    /// files alongside the list file, and then reading the entries back via a
+    /// [`KeyMerge`] of the chunks.
+    pub(crate) fn into_sorted(mut self) -> Result<InventoryList, Box<InventoryListError>> {
+        if self.sorted {
+            return Ok(self);
+        }
+        tracing::warn!(url = %self.url, "Inventory list file is not sorted by key; sorting via temporary files");
+        let mut reader = match self.open() {
+            Ok(reader) => reader,
+            Err(e) => return Err(Box::new(self.error(e))),
+        };
+        let file_schema = reader.file_schema().clone();
+        let dir = self
+            .path
+            .parent()
+            .map_or_else(std::env::temp_dir, std::path::Path::to_path_buf);
+        let mut chunks = Vec::new();
+        loop {
+            let mut chunk = Vec::with_capacity(SORT_CHUNK_SIZE);
+            while chunk.len() < SORT_CHUNK_SIZE {
+                match reader.next_record() {
+                    Some(Ok(values)) => chunk.push((file_schema.csv_key(&values), values)),
+                    Some(Err(e)) => return Err(Box::new(self.error(e.into()))),
+                    None => break,
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            let done = chunk.len() < SORT_CHUNK_SIZE;
+            chunk.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+            let fp =
+                spill(&dir, chunk).map_err(|e| Box::new(self.error(CsvReaderError::Spill(e))))?;
+            chunks.push(CsvReader::new(BufReader::new(fp), file_schema.clone()));
+            if done {
+                break;
+            }
+        }
+        // The downloaded file is no longer needed, so delete it now rather
+        // than on drop.
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
+        self.inner = ListReader::Sorted(chunks.into_iter().collect());
+        Ok(self)
+    }
+
+    /// Open the as-yet unopened list file for reading.  The list's own
+    /// reader is left with no entries remaining, so if opening fails, the
+    /// error is only reported once.
+    fn open(&mut self) -> Result<CsvReader<GzDecoder<BufReader<File>>>, CsvReaderError> {
+        let ListReader::Unopened(file_schema) =
+            std::mem::replace(&mut self.inner, ListReader::Sorted(KeyMerge::new()))
+        else {
+            unreachable!("InventoryList::open() should only be called on unopened lists");
+        };
+        let fp = File::open(&self.path).map_err(CsvReaderError::Open)?;
+        Ok(CsvReader::from_gzipped_reader(
+            BufReader::new(fp),
+            file_schema,
+        ))
+    }
+
+    fn error(&self, source: CsvReaderError) -> InventoryListError {
+        InventoryListError {
+            url: self.url.clone(),
+            source,
+        }
     }
 }
 
+/// A writer that receives the gzipped contents of an inventory list file as
+/// the file is being downloaded and checks whether the file's entries are
+/// sorted by key, so that the file does not need to be read again just to find
+/// out.  Entries that cannot be parsed or lack valid keys are skipped, as any
+/// resulting errors will be reported when the entries are actually read.
+pub(crate) struct SortCheck {
+    /// The decoder through which the downloaded bytes are passed
+    decoder: flate2::write::GzDecoder<KeyOrder>,
+
+    /// Whether the downloaded bytes have failed to decode, in which case the
+    /// rest of the file is skipped
+    failed: bool,
+}
+
+impl SortCheck {
+    pub(crate) fn new(file_schema: FileSchema) -> SortCheck {
+        SortCheck {
+            decoder: flate2::write::GzDecoder::new(KeyOrder {
+                file_schema,
+                buf: Vec::new(),
+                prev: None,
+                sorted: true,
+            }),
+            failed: false,
+        }
+    }
+
+    /// Check the next bytes of the downloaded file
+    pub(crate) fn feed(&mut self, data: &[u8]) {
+        if !self.failed && self.decoder.get_ref().sorted {
+            self.failed = self.decoder.write_all(data).is_err();
+        }
+    }
+
+    /// Finish checking the file and return whether its entries are sorted by
+    /// key.  If the file could not be decoded, it is reported as sorted, and
+    /// the decoding error will be reported when the entries are read.
+    pub(crate) fn finish(mut self) -> bool {
+        if !self.decoder.get_ref().sorted {
+            return false;
+        }
+        if self.failed || self.decoder.try_finish().is_err() {
+            return true;
+        }
+        let order = self.decoder.get_mut();
+        let rest = std::mem::take(&mut order.buf);
+        order.check(&rest);
+        order.sorted
+    }
+}
+
+/// The destination of the decoded bytes in a [`SortCheck`]
+struct KeyOrder {
+    file_schema: FileSchema,
+
+    /// Decoded bytes after the last complete line received so far
+    buf: Vec<u8>,
+
+    /// The key of the last entry checked
+    prev: Option<String>,
+
+    /// Whether the entries checked so far are sorted
+    sorted: bool,
+}
+
+impl KeyOrder {
+    /// Check the keys of the CSV rows in `lines`, which must consist of
+    /// complete lines
+    fn check(&mut self, lines: &[u8]) {
+        let mut reader = CsvReader::new(lines, self.file_schema.clone());
+        while let Some(r) = reader.next_record() {
+            let Some(key) = r.ok().and_then(|values| self.file_schema.csv_key(&values)) else {
+                continue;
+            };
+            if self.prev.as_ref().is_some_and(|p| p > &key) {
+                self.sorted = false;
+                return;
+            }
+            self.prev = Some(key);
+        }
+    }
+}
+
+impl Write for KeyOrder {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.sorted {
+            self.buf.extend_from_slice(data);
+            // Keys in inventory list files are percent-encoded and thus
+            // cannot contain newlines, so the rows can be split at any
+            // newline.
+            if let Some(end) = self.buf.iter().rposition(|&b| b == b'\n') {
+                let rest = self.buf.split_off(end + 1);
+                let lines = std::mem::replace(&mut self.buf, rest);
+                self.check(&lines);
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write a chunk of sorted CSV rows to a new anonymous temporary file in `dir`
+/// and return the file, rewound to the beginning
+fn spill(
+    dir: &std::path::Path,
+    chunk: Vec<(Option<String>, Vec<String>)>,
+) -> std::io::Result<File> {
+    let mut writer = csv::Writer::from_writer(tempfile::tempfile_in(dir)?);
+    for (_, values) in chunk {
+        writer.write_record(values)?;
+    }
+    let mut fp = writer
+        .into_inner()
+        .map_err(csv::IntoInnerError::into_error)?;
+    fp.rewind()?;
+    Ok(fp)
+}
+
+/// The source of the entries in an [`InventoryList`]
+enum ListReader {
+    /// The downloaded csv.gz file has not been opened yet; it will be read
+    /// with the given schema
+    Unopened(FileSchema),
+
+    /// Read the entries directly from the downloaded csv.gz file
+    Gzip(Box<CsvReader<GzDecoder<BufReader<File>>>>),
+
+    /// Read the entries from sorted chunks of an unsorted list file
+    Sorted(KeyMerge<CsvReader<BufReader<File>>, CsvReaderError>),
+}
+
 impl Iterator for InventoryList {
     type Item = Result<InventoryEntry, InventoryListError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Some(self.inner.next()?.map_err(|source| InventoryListError {
-            url: self.url.clone(),
-            source,
-        }))
+        if matches!(self.inner, ListReader::Unopened(_)) {
+            match self.open() {
+                Ok(reader) => self.inner = ListReader::Gzip(Box::new(reader)),
+                Err(e) => return Some(Err(self.error(e))),
+            }
+        }
+        let r = match self.inner {
+            ListReader::Unopened(_) => unreachable!("list file should have been opened"),
+            ListReader::Gzip(ref mut reader) => reader.next()?,
+            ListReader::Sorted(ref mut merge) => merge.next()?,
+        };
+        Some(r.map_err(|source| self.error(source)))
     }
 }
 
 impl Drop for InventoryList {
-    /// Delete the local file on drop
+    /// Delete the local file on drop if it's temporary
     fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.path);
+        if self.temporary {
+            let _ = std::fs::remove_file(&self.path);
+        }
     }
 }
 
@@ -75,6 +337,16 @@ impl<R: Read> CsvReader<R> {
             file_schema,
         }
     }
+
+    /// Returns the schema with which entries are parsed
+    pub(crate) fn file_schema(&self) -> &FileSchema {
+        &self.file_schema
+    }
+
+    /// Read the next row of the CSV file without parsing it into an entry
+    pub(crate) fn next_record(&mut self) -> Option<Result<Vec<String>, csv::Error>> {
+        self.inner.next()
+    }
 }
 
 impl<R: BufRead> CsvReader<GzDecoder<R>> {
@@ -87,7 +359,7 @@ impl<R: Read> Iterator for CsvReader<R> {
     type Item = Result<InventoryEntry, CsvReaderError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.inner.next()? {
+        match self.next_record()? {
             Ok(values) => match self.file_schema.parse_csv_fields(values) {
                 Ok(entry) => Some(Ok(entry)),
                 Err(e) => Some(Err(e.into())),
@@ -103,4 +375,131 @@ pub(crate) enum CsvReaderError {
     Csv(#[from] csv::Error),
     #[error("failed to parse fields of CSV entry")]
     Parse(#[from] ParseEntryError),
+    #[error("failed to write sorted entries to temporary file")]
+    Spill(#[source] std::io::Error),
+    #[error("failed to open inventory list file")]
+    Open(#[source] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use rstest::rstest;
+
+    fn schema() -> FileSchema {
+        "Bucket, Key, VersionId, ETag"
+            .parse::<FileSchema>()
+            .unwrap()
+    }
+
+    fn gzip(csv: &str) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(csv.as_bytes()).unwrap();
+        enc.finish().unwrap()
+    }
+
+    /// Run a `SortCheck` on the gzipped CSV data, feeding it in pieces of
+    /// `size` bytes
+    fn check_sorted(data: &[u8], size: usize) -> bool {
+        let mut check = SortCheck::new(schema());
+        for piece in data.chunks(size) {
+            check.feed(piece);
+        }
+        check.finish()
+    }
+
+    fn gzipped_list(dir: &std::path::Path, csv: &str) -> InventoryList {
+        let path = dir.join("data.csv.gz");
+        let data = gzip(csv);
+        fs_err::write(&path, &data).unwrap();
+        InventoryList::for_downloaded_csv(
+            path,
+            S3Location::new("inventories".into(), "data.csv.gz".into()),
+            schema(),
+            check_sorted(&data, 7),
+        )
+    }
+
+    fn keys(list: InventoryList) -> Vec<String> {
+        list.map(|r| match r.unwrap() {
+            InventoryEntry::Item(item) => {
+                format!("{}@{}", item.key, item.version_id.unwrap_or_default())
+            }
+            InventoryEntry::Directory(d) => d.key,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn sorted_list_is_unchanged() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let list = gzipped_list(
+            tmpdir.path(),
+            "b,apple,1,abc\nb,banana,2,abc\nb,banana,1,abc\nb,cherry,1,abc\n",
+        )
+        .into_sorted()
+        .unwrap();
+        assert!(list.was_sorted());
+        assert!(matches!(list.inner, ListReader::Unopened(_)));
+        assert_eq!(keys(list), ["apple@1", "banana@2", "banana@1", "cherry@1"]);
+    }
+
+    #[test]
+    fn unsorted_list_is_sorted() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let list = gzipped_list(
+            tmpdir.path(),
+            "b,cherry,1,abc\nb,banana,2,abc\nb,apple,1,abc\nb,banana,1,abc\nb,%C3%A9clair,1,abc\n",
+        )
+        .into_sorted()
+        .unwrap();
+        assert!(!list.was_sorted());
+        assert!(matches!(list.inner, ListReader::Sorted(_)));
+        assert!(!tmpdir.path().join("data.csv.gz").exists());
+        assert_eq!(
+            keys(list),
+            ["apple@1", "banana@2", "banana@1", "cherry@1", "éclair@1"]
+        );
+    }
+
+    #[rstest]
+    #[case("", true)]
+    #[case(
+        "b,apple,1,abc\nb,banana,2,abc\nb,banana,1,abc\nb,cherry,1,abc\n",
+        true
+    )]
+    #[case("b,apple,1,abc\nb,banana,2,abc\nb,banana,1,abc\nb,cherry,1,abc", true)]
+    #[case("b,apple,1,abc\nb,%C3%A9clair,1,abc\nb,cherry,1,abc\n", false)]
+    #[case("b,apple,1,abc\nnot enough fields\nb,banana,1,abc\n", true)]
+    fn sort_check(#[case] csv: &str, #[case] sorted: bool) {
+        let data = gzip(csv);
+        for size in [1, 7, data.len()] {
+            assert_eq!(check_sorted(&data, size), sorted);
+        }
+    }
+
+    #[test]
+    fn sort_check_bad_gzip() {
+        assert!(check_sorted(b"this is not gzip data", 7));
+    }
+
+    #[test]
+    fn missing_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut list = InventoryList::for_cached_csv(
+            tmpdir.path().join("data.csv.gz"),
+            S3Location::new("inventories".into(), "data.csv.gz".into()),
+            schema(),
+            true,
+        );
+        assert!(matches!(
+            list.next(),
+            Some(Err(InventoryListError {
+                source: CsvReaderError::Open(_),
+                ..
+            }))
+        ));
+        assert!(list.next().is_none());
+    }
 }
@@ -0,0 +1,298 @@
+use super::item::InventoryEntry;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use thiserror::Error;
+
+/// An iterator that performs a streaming k-way merge of multiple sorted
+/// sources of inventory entries, yielding the entries in order by key.
+/// Entries with equal keys are yielded in the order in which their sources
+/// were added, and entries from the same source retain their relative order.
+///
+/// Only one entry per source is held in memory at a time.  Errors yielded by
+/// a source are passed through immediately, after which reading from that
+/// source resumes on the next call to `next()`.
+pub(crate) struct KeyMerge<I, E> {
+    /// The sources being merged, indexed by the order in which they were
+    /// added.  Sources are dropped once they're exhausted so that any
+    /// resources they hold are released.
+    sources: Vec<Option<I>>,
+
+    /// The next entry from each source that has not been exhausted
+    heads: BinaryHeap<Reverse<Head>>,
+
+    /// Errors that have been read from sources but not yet yielded, along
+    /// with the indices of their sources
+    pending: VecDeque<(E, usize)>,
+
+    /// The index of a source whose last yielded item was an error and which
+    /// thus needs to be advanced before continuing
+    stalled: Option<usize>,
+
+    /// The key of the most recently yielded entry
+    last_key: Option<String>,
+}
+
+impl<I, E> KeyMerge<I, E>
+where
+    I: Iterator<Item = Result<InventoryEntry, E>>,
+{
+    pub(crate) fn new() -> Self {
+        KeyMerge {
+            sources: Vec::new(),
+            heads: BinaryHeap::new(),
+            pending: VecDeque::new(),
+            stalled: None,
+            last_key: None,
+        }
+    }
+
+    /// Add a source to the merge.  If entries have already been yielded and
+    /// the first entry of the new source has a key less than that of the
+    /// last entry yielded, an error is returned, as the source's entries can
+    /// no longer be merged in order; in that case, the source is still
+    /// added, and its entries will be yielded once they're reached.
+    pub(crate) fn push(&mut self, source: I) -> Result<(), OutOfOrderError> {
+        let index = self.sources.len();
+        self.sources.push(Some(source));
+        self.advance(index);
+        if let Some(before) = self.last_key.as_ref() {
+            if let Some(Reverse(head)) = self.heads.iter().find(|Reverse(h)| h.source == index) {
+                if head.entry.key() < before.as_str() {
+                    return Err(OutOfOrderError {
+                        before: before.clone(),
+                        after: head.entry.key().to_owned(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the key of the next entry to be yielded, if known.  If a
+    /// source has a pending error or is awaiting re-reading after one, its
+    /// entries are not taken into account.
+    pub(crate) fn peek_key(&self) -> Option<&str> {
+        self.heads.peek().map(|Reverse(h)| h.entry.key())
+    }
+
+    /// Read the next entry from the source at index `index` and add it to
+    /// `heads`.  If an error is read instead, it is added to `pending`.  If
+    /// the source is exhausted, it is dropped.
+    fn advance(&mut self, index: usize) {
+        let Some(source) = self.sources[index].as_mut() else {
+            return;
+        };
+        match source.next() {
+            Some(Ok(entry)) => self.heads.push(Reverse(Head {
+                entry,
+                source: index,
+            })),
+            Some(Err(e)) => self.pending.push_back((e, index)),
+            None => self.sources[index] = None,
+        }
+    }
+}
+
+impl<I, E> Default for KeyMerge<I, E>
+where
+    I: Iterator<Item = Result<InventoryEntry, E>>,
+{
+    fn default() -> Self {
+        KeyMerge::new()
+    }
+}
+
+impl<I, E> FromIterator<I> for KeyMerge<I, E>
+where
+    I: Iterator<Item = Result<InventoryEntry, E>>,
+{
+    /// Construct a `KeyMerge` of the given sources.  As no entries have been
+    /// yielded yet, adding the sources cannot fail.
+    fn from_iter<T: IntoIterator<Item = I>>(iter: T) -> Self {
+        let mut merge = KeyMerge::new();
+        for source in iter {
+            let index = merge.sources.len();
+            merge.sources.push(Some(source));
+            merge.advance(index);
+        }
+        merge
+    }
+}
+
+impl<I, E> Iterator for KeyMerge<I, E>
+where
+    I: Iterator<Item = Result<InventoryEntry, E>>,
+{
+    type Item = Result<InventoryEntry, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(index) = self.stalled.take() {
+            self.advance(index);
+        }
+        if let Some((e, index)) = self.pending.pop_front() {
+            self.stalled = Some(index);
+            return Some(Err(e));
+        }
+        let Reverse(Head { entry, source }) = self.heads.pop()?;
+        self.advance(source);
+        self.last_key = Some(entry.key().to_owned());
+        Some(Ok(entry))
+    }
+}
+
+/// The next entry from a source in a [`KeyMerge`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Head {
+    /// The entry
+    entry: InventoryEntry,
+
+    /// The index of the source
+    source: usize,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        self.entry
+            .key()
+            .cmp(other.entry.key())
+            .then_with(|| self.source.cmp(&other.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Error returned by [`KeyMerge::push()`] when a source is added whose first
+/// entry precedes the entries already yielded
+#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[error("inventory list entry with key {after:?} cannot be merged after already-processed key {before:?}")]
+pub(crate) struct OutOfOrderError {
+    /// The key of the last entry yielded
+    pub(crate) before: String,
+
+    /// The key of the new source's first entry
+    pub(crate) after: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::{CsvReader, CsvReaderError, FileSchema};
+
+    fn reader(csv: &'static str) -> CsvReader<&'static [u8]> {
+        CsvReader::new(
+            csv.as_bytes(),
+            "Bucket, Key, VersionId, ETag"
+                .parse::<FileSchema>()
+                .unwrap(),
+        )
+    }
+
+    fn keys<I: Iterator<Item = Result<InventoryEntry, CsvReaderError>>>(
+        merge: KeyMerge<I, CsvReaderError>,
+    ) -> Vec<String> {
+        merge
+            .map(|r| match r {
+                Ok(InventoryEntry::Item(item)) => {
+                    format!("{}@{}", item.key, item.version_id.unwrap_or_default())
+                }
+                Ok(InventoryEntry::Directory(d)) => d.key,
+                Err(_) => String::from("ERROR"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interleaved() {
+        let merge = [
+            reader("b,apple,1,abc\nb,cherry,1,abc\nb,eggplant,1,abc\n"),
+            reader("b,banana,1,abc\nb,cherry,2,abc\nb,durian,1,abc\n"),
+            reader(""),
+            reader("b,aardvark,1,abc\nb,fig,1,abc\n"),
+        ]
+        .into_iter()
+        .collect::<KeyMerge<_, _>>();
+        assert_eq!(
+            keys(merge),
+            [
+                "aardvark@1",
+                "apple@1",
+                "banana@1",
+                "cherry@1",
+                "cherry@2",
+                "durian@1",
+                "eggplant@1",
+                "fig@1"
+            ]
+        );
+    }
+
+    #[test]
+    fn equal_keys_keep_source_order() {
+        let merge = [
+            reader("b,foo,3,abc\nb,foo,2,abc\n"),
+            reader("b,foo,1,abc\nb,quux,1,abc\n"),
+        ]
+        .into_iter()
+        .collect::<KeyMerge<_, _>>();
+        assert_eq!(keys(merge), ["foo@3", "foo@2", "foo@1", "quux@1"]);
+    }
+
+    #[test]
+    fn errors_pass_through() {
+        let merge = [
+            reader("b,apple,1,abc\nb,banana,1\nb,cherry,1,abc\n"),
+            reader("b,apricot,1,abc\nb,blueberry,1,abc\n"),
+        ]
+        .into_iter()
+        .collect::<KeyMerge<_, _>>();
+        assert_eq!(
+            keys(merge),
+            ["apple@1", "ERROR", "apricot@1", "blueberry@1", "cherry@1"]
+        );
+    }
+
+    #[test]
+    fn push_lazily() {
+        let mut merge = KeyMerge::new();
+        merge
+            .push(reader("b,apple,1,abc\nb,cherry,1,abc\n"))
+            .unwrap();
+        assert_eq!(merge.peek_key(), Some("apple"));
+        assert!(merge.next().is_some_and(|r| r.is_ok()));
+        merge.push(reader("b,banana,1,abc\n")).unwrap();
+        assert_eq!(merge.peek_key(), Some("banana"));
+        let e = merge.push(reader("b,aardvark,1,abc\n")).unwrap_err();
+        assert_eq!(
+            e,
+            OutOfOrderError {
+                before: "apple".into(),
+                after: "aardvark".into()
+            }
+        );
+        assert_eq!(keys(merge), ["aardvark@1", "banana@1", "cherry@1"]);
+    }
+
+    #[test]
+    fn drop_exhausted_sources() {
+        let held = std::rc::Rc::new(());
+        let tracked = |csv: &'static str| {
+            let held = held.clone();
+            reader(csv).inspect(move |_| {
+                let _ = &held;
+            })
+        };
+        let mut merge = [tracked("b,apple,1,abc\n"), tracked("b,banana,1,abc\n")]
+            .into_iter()
+            .collect::<KeyMerge<_, _>>();
+        assert_eq!(std::rc::Rc::strong_count(&held), 3);
+        assert!(merge.next().is_some_and(|r| r.is_ok()));
+        assert_eq!(std::rc::Rc::strong_count(&held), 2);
+        assert!(merge.next().is_some_and(|r| r.is_ok()));
+        assert_eq!(std::rc::Rc::strong_count(&held), 1);
+        assert!(merge.next().is_none());
+    }
+}
@@ -2,6 +2,8 @@
 mod fields;
 mod item;
 mod list;
+mod merge;
 pub(crate) use self::fields::*;
 pub(crate) use self::item::*;
 pub(crate) use self::list::*;
+pub(crate) use self::merge::*;
@@ -1,16 +1,63 @@
-use crate::consts::RESERVED_PREFIX;
+use crate::consts::{ESCAPE_PREFIX, MAX_NAME_LEN, RESERVED_PREFIX};
+use md5::{Digest, Md5};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::borrow::Cow;
 use thiserror::Error;
+use unicode_normalization::UnicodeNormalization;
 
-/// A nonempty, forward-slash-separated path that does not contain any of the
-/// following:
+/// The set of characters that are percent-encoded in escaped key components.
+/// This includes all characters that are not allowed in Windows filenames so
+/// that escaped names are always portable.
+const ESCAPE_SET: &AsciiSet = &CONTROLS
+    .add(b'%')
+    .add(b'.')
+    .add(b'/')
+    .add(b' ')
+    .add(b':')
+    .add(b'\\')
+    .add(b'*')
+    .add(b'?')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'|');
+
+/// Characters that are not allowed in Windows filenames, aside from control
+/// characters
+const UNPORTABLE_CHARS: &[char] = &[':', '\\', '*', '?', '"', '<', '>', '|'];
+
+/// Names of Windows devices, which cannot be used as filenames on Windows,
+/// regardless of case and even when followed by an extension
+const DEVICE_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The kinds of temporary files created by `s3invsync`, which are named
+/// `.s3invsync.{kind}.{random}`, where `{random}` is six random alphanumeric
+/// characters
+const TEMPFILE_KINDS: &[&str] = &[
+    "archived",
+    "download",
+    "failures",
+    "first-keys",
+    "names",
+    "recent",
+    "restores",
+    "state",
+    "versions",
+];
+
+/// The length of the suffix appended to names by [`shorten_name()`]: a tilde
+/// followed by a hex MD5 digest
+const SHORTEN_SUFFIX_LEN: usize = 33;
+
+/// A nonempty, forward-slash-separated S3 object key.
 ///
-/// - a `.` or `..` component
-/// - a leading or trailing forward slash
-/// - two or more consecutive forward slashes
-/// - NUL
-/// - a component that starts with [`RESERVED_PREFIX`] or that looks like
-///   `{filename}.old.{version_id}.{etag}` (specifically, of the form
-///   `{nonempty}.old.{nonempty}.{nonempty}`)
+/// Keys may contain components that cannot be used as local filenames as-is,
+/// such as empty, `.`, or `..` components or components that collide with
+/// names used by `s3invsync`; such components are escaped with
+/// [`local_name()`] when computing local paths.
 #[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub(crate) struct KeyPath(String);
 
@@ -23,11 +70,21 @@ impl KeyPath {
             .expect("path should be nonempty")
     }
 
-    /// Split the path into the directory component (if any) and filename
-    pub(crate) fn split(&self) -> (Option<&str>, &str) {
+    /// Split the local path at which the key is backed up (relative to the
+    /// backup root) into the directory component (if any) and filename.  See
+    /// [`local_name()`] for the meaning of `portable`.
+    pub(crate) fn local_split(&self, portable: bool) -> (Option<String>, Cow<'_, str>) {
         match self.0.rsplit_once('/') {
-            Some((pre, post)) => (Some(pre), post),
-            None => (None, &*self.0),
+            Some((pre, post)) => (
+                Some(
+                    pre.split('/')
+                        .map(|c| local_name(c, portable))
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                ),
+                local_name(post, portable),
+            ),
+            None => (None, local_name(&self.0, portable)),
         }
     }
 }
@@ -108,16 +165,6 @@ impl TryFrom<String> for KeyPath {
 pub(crate) enum ParseKeyPathError {
     #[error("paths cannot be empty")]
     Empty,
-    #[error("paths cannot start with a forward slash")]
-    StartsWithSlash,
-    #[error("paths cannot end with a forward slash")]
-    EndsWithSlash,
-    #[error("paths cannot contain NUL")]
-    Nul,
-    #[error("path is not normalized")]
-    NotNormalized,
-    #[error("path contains component name reserved by s3invsync")]
-    Special,
 }
 
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
@@ -130,30 +177,161 @@ pub(crate) struct KeyPathFromStringError {
 fn validate(s: &str) -> Result<(), ParseKeyPathError> {
     if s.is_empty() {
         Err(ParseKeyPathError::Empty)
-    } else if s.starts_with('/') {
-        Err(ParseKeyPathError::StartsWithSlash)
-    } else if s.ends_with('/') {
-        Err(ParseKeyPathError::EndsWithSlash)
-    } else if s.contains('\0') {
-        Err(ParseKeyPathError::Nul)
-    } else if s.split('/').any(|p| p.is_empty() || p == "." || p == "..") {
-        Err(ParseKeyPathError::NotNormalized)
-    } else if s.split('/').any(is_special_component) {
-        Err(ParseKeyPathError::Special)
     } else {
         Ok(())
     }
 }
 
+/// Returns the name under which a key component is stored locally.
+///
+/// Components that are empty, are `.` or `..`, contain NUL, or are special
+/// (see [`is_special_component()`]) — and, if `portable` is true, components
+/// that are not valid Windows filenames due to containing control characters
+/// or any of `:\*?"<>|`, due to ending in a period or space, or due to being a
+/// reserved device name like `CON` or `com1.txt` — are escaped
+/// by percent-encoding all characters in [`ESCAPE_SET`] in them and
+/// prepending [`ESCAPE_PREFIX`].
+/// As no unescaped component can start with [`RESERVED_PREFIX`], escaped names
+/// never collide with unescaped names, and the original component can be
+/// recovered by stripping the prefix and percent-decoding the remainder.
+/// All other components are stored as-is.
+///
+/// Finally, names longer than [`MAX_NAME_LEN`] bytes are shortened with
+/// [`shorten_name()`].
+pub(crate) fn local_name(component: &str, portable: bool) -> Cow<'_, str> {
+    let name = if needs_escape(component) || (portable && !is_portable(component)) {
+        Cow::Owned(escape_name(component))
+    } else {
+        Cow::Borrowed(component)
+    };
+    shorten_name(name, MAX_NAME_LEN)
+}
+
+/// Unconditionally escape `component` as described in [`local_name()`]
+pub(crate) fn escape_name(component: &str) -> String {
+    format!(
+        "{ESCAPE_PREFIX}{}",
+        utf8_percent_encode(component, ESCAPE_SET)
+    )
+}
+
+/// Returns the name under which the latest version of a file key component is
+/// stored locally when the same directory also contains a subdirectory with
+/// the same name.
+///
+/// Such files are escaped as though their name were followed by a forward
+/// slash, which keeps them distinct from the local name of any other entry.
+pub(crate) fn conflict_file_name(component: &str) -> String {
+    shorten_name(
+        Cow::Owned(escape_name(&format!("{component}/"))),
+        MAX_NAME_LEN,
+    )
+    .into_owned()
+}
+
+/// If `name` is longer than `maxlen` bytes, shorten it by truncating it and
+/// appending a tilde and the hex MD5 digest of the full name so that the
+/// result is at most `maxlen` bytes long; otherwise, return `name` unchanged.
+///
+/// Truncation is done on a character boundary.  As the result is not
+/// reversible, the original names of shortened entries are recorded in each
+/// directory's [`NAMES_FILENAME`][crate::consts::NAMES_FILENAME] file.
+pub(crate) fn shorten_name(name: Cow<'_, str>, maxlen: usize) -> Cow<'_, str> {
+    if name.len() <= maxlen {
+        return name;
+    }
+    let mut end = maxlen.saturating_sub(SHORTEN_SUFFIX_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let digest = Md5::digest(name.as_bytes());
+    Cow::Owned(format!("{}~{}", &name[..end], hex::encode(digest)))
+}
+
+/// Returns the form of `name` used to detect names that refer to the same file
+/// on case-insensitive or normalization-insensitive filesystems: the NFC
+/// normalization of the lowercase form of `name`
+pub(crate) fn fold_name(name: &str) -> String {
+    name.to_lowercase().nfc().collect()
+}
+
+/// Returns the local name for an entry with key component `component` and
+/// initial local name `local` that collides with an earlier entry in the same
+/// directory when compared with [`fold_name()`].
+///
+/// The result is `local` followed by a tilde and the hex MD5 digest of
+/// `component`, shortened with [`shorten_name()`] if necessary.
+pub(crate) fn disambiguate_name(local: &str, component: &str) -> String {
+    let digest = Md5::digest(component.as_bytes());
+    let name = format!("{local}~{}", hex::encode(digest));
+    shorten_name(Cow::Owned(name), MAX_NAME_LEN).into_owned()
+}
+
+/// Returns `true` if the key component cannot be used as a local filename
+/// as-is
+fn needs_escape(component: &str) -> bool {
+    component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains('\0')
+        || is_special_component(component)
+}
+
+/// Returns `true` if the key component is a valid filename on Windows
+fn is_portable(component: &str) -> bool {
+    !(component.contains(UNPORTABLE_CHARS)
+        || component.contains(|c: char| c.is_ascii_control())
+        || component.ends_with(['.', ' '])
+        || is_device_name(component))
+}
+
+/// Returns `true` if the key component refers to a Windows device, i.e., if
+/// the part before the first period (ignoring trailing spaces) is one of
+/// [`DEVICE_NAMES`], compared case-insensitively
+fn is_device_name(component: &str) -> bool {
+    let stem = component
+        .split_once('.')
+        .map_or(component, |(stem, _)| stem)
+        .trim_end_matches(' ');
+    DEVICE_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+/// Returns `true` if `name` is the name of a special file created by
+/// `s3invsync` rather than an escaped key component
+pub(crate) fn is_reserved_name(name: &str) -> bool {
+    name.starts_with(RESERVED_PREFIX) && !name.starts_with(ESCAPE_PREFIX)
+}
+
+/// Returns `true` if `name` is the name of a temporary file created by
+/// `s3invsync`, such as a partial download or an unpersisted database update
+pub(crate) fn is_temporary_name(name: &str) -> bool {
+    let Some((kind, suffix)) = name
+        .strip_prefix(RESERVED_PREFIX)
+        .and_then(|s| s.strip_prefix('.'))
+        .and_then(|s| s.split_once('.'))
+    else {
+        return false;
+    };
+    TEMPFILE_KINDS.contains(&kind)
+        && suffix.len() == 6
+        && suffix.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
 // Test for components that equal start with `RESERVED_PREFIX` or look like
 // `{filename}.old.{version_id}.{etag}` (specifically, that are of the form
 // `{nonempty}.old.{nonempty}.{nonempty}`)
 pub(crate) fn is_special_component(component: &str) -> bool {
-    if component.starts_with(RESERVED_PREFIX) {
-        return true;
-    }
-    if let Some(i) = component.find(".old.").filter(|&i| i > 0) {
-        let post_old = &component[(i + 5)..];
+    component.starts_with(RESERVED_PREFIX) || is_old_filename(component)
+}
+
+/// Test whether `name` looks like `{filename}.old.{version_id}.{etag}`
+/// (specifically, whether it is of the form
+/// `{nonempty}.old.{nonempty}.{nonempty}`)
+pub(crate) fn is_old_filename(name: &str) -> bool {
+    if let Some(i) = name.find(".old.").filter(|&i| i > 0) {
+        let post_old = &name[(i + 5)..];
         if post_old
             .find('.')
             .is_some_and(|j| (1..(post_old.len() - 1)).contains(&j))
@@ -181,38 +359,154 @@ mod tests {
     #[case("foo", None, "foo")]
     #[case("foo/bar", Some("foo"), "bar")]
     #[case("foo/bar/baz", Some("foo/bar"), "baz")]
-    fn test_split(#[case] p: KeyPath, #[case] dirname: Option<&str>, #[case] filename: &str) {
-        let (d, f) = p.split();
-        assert_eq!(d, dirname);
+    #[case("foo/./bar", Some("foo/.s3invsync.esc.%2E"), "bar")]
+    #[case("../foo/bar", Some(".s3invsync.esc.%2E%2E/foo"), "bar")]
+    #[case("foo//bar", Some("foo/.s3invsync.esc."), "bar")]
+    #[case("/foo", Some(".s3invsync.esc."), "foo")]
+    #[case("foo/", Some("foo"), ".s3invsync.esc.")]
+    #[case("foo/bar.old.1.2", Some("foo"), ".s3invsync.esc.bar%2Eold%2E1%2E2")]
+    #[case(
+        "foo/.s3invsync.versions.json",
+        Some("foo"),
+        ".s3invsync.esc.%2Es3invsync%2Eversions%2Ejson"
+    )]
+    #[case("foo/bar\0.nwb", Some("foo"), ".s3invsync.esc.bar%00%2Enwb")]
+    fn test_local_split(#[case] p: KeyPath, #[case] dirname: Option<&str>, #[case] filename: &str) {
+        let (d, f) = p.local_split(false);
+        assert_eq!(d.as_deref(), dirname);
         assert_eq!(f, filename);
     }
 
     #[rstest]
     #[case("foo.nwb")]
     #[case("foo/bar.nwb")]
+    #[case("/")]
+    #[case("foo//bar.nwb")]
+    #[case("foo/../bar.nwb")]
+    #[case("foo/bar.nwb/.")]
     fn test_good_paths(#[case] s: &str) {
         let r = s.parse::<KeyPath>();
         assert_matches!(r, Ok(_));
     }
 
+    #[test]
+    fn test_empty_path() {
+        let r = "".parse::<KeyPath>();
+        assert_matches!(r, Err(ParseKeyPathError::Empty));
+    }
+
+    #[rstest]
+    #[case("foo.txt", "foo.txt")]
+    #[case("foo bar.txt", "foo bar.txt")]
+    #[case("a:b", ".s3invsync.esc.a%3Ab")]
+    #[case("what?", ".s3invsync.esc.what%3F")]
+    #[case("a\\b", ".s3invsync.esc.a%5Cb")]
+    #[case("<|>", ".s3invsync.esc.%3C%7C%3E")]
+    #[case("\"*\"", ".s3invsync.esc.%22%2A%22")]
+    #[case("tab\there", ".s3invsync.esc.tab%09here")]
+    #[case("foo.", ".s3invsync.esc.foo%2E")]
+    #[case("foo ", ".s3invsync.esc.foo%20")]
+    #[case(".hidden", ".hidden")]
+    #[case("CON", ".s3invsync.esc.CON")]
+    #[case("prn", ".s3invsync.esc.prn")]
+    #[case("Aux.txt", ".s3invsync.esc.Aux%2Etxt")]
+    #[case("nul.tar.gz", ".s3invsync.esc.nul%2Etar%2Egz")]
+    #[case("NUL .txt", ".s3invsync.esc.NUL%20%2Etxt")]
+    #[case("com1", ".s3invsync.esc.com1")]
+    #[case("COM9.log", ".s3invsync.esc.COM9%2Elog")]
+    #[case("LPT1", ".s3invsync.esc.LPT1")]
+    #[case("lpt9.dat", ".s3invsync.esc.lpt9%2Edat")]
+    #[case("CONSOLE", "CONSOLE")]
+    #[case("con-fig.txt", "con-fig.txt")]
+    #[case("COM0", "COM0")]
+    #[case("COM10", "COM10")]
+    #[case("LPT", "LPT")]
+    #[case("my.con", "my.con")]
+    fn test_portable_local_name(#[case] component: &str, #[case] name: &str) {
+        assert_eq!(local_name(component, true), name);
+    }
+
+    #[test]
+    fn test_nonportable_local_name() {
+        assert_eq!(local_name("a:b", false), "a:b");
+        assert_eq!(local_name("foo.", false), "foo.");
+        assert_eq!(local_name("CON", false), "CON");
+    }
+
     #[rstest]
+    #[case("foo.txt")]
+    #[case("%2E")]
+    #[case(".")]
     #[case("")]
-    #[case("/")]
-    #[case("/foo")]
+    #[case("foo.old.bar.baz")]
+    #[case(".s3invsync.esc.")]
     #[case("foo/")]
-    #[case("/foo/")]
-    #[case("foo//bar.nwb")]
-    #[case("foo///bar.nwb")]
-    #[case("foo/bar\0.nwb")]
-    #[case("foo/./bar.nwb")]
-    #[case("foo/../bar.nwb")]
-    #[case("./foo/bar.nwb")]
-    #[case("../foo/bar.nwb")]
-    #[case("foo/bar.nwb/.")]
-    #[case("foo/bar.nwb/..")]
-    fn test_bad_paths(#[case] s: &str) {
-        let r = s.parse::<KeyPath>();
-        assert_matches!(r, Err(_));
+    #[case("a:b*c? ")]
+    fn test_escape_roundtrip(#[case] s: &str) {
+        let name = escape_name(s);
+        let encoded = name.strip_prefix(ESCAPE_PREFIX).unwrap();
+        assert!(!encoded.contains('.'));
+        let decoded = percent_encoding::percent_decode_str(encoded)
+            .decode_utf8()
+            .unwrap();
+        assert_eq!(decoded, s);
+    }
+
+    #[rstest]
+    #[case("foo", ".s3invsync.esc.foo%2F")]
+    #[case("", ".s3invsync.esc.%2F")]
+    #[case("foo.old.1.2", ".s3invsync.esc.foo%2Eold%2E1%2E2%2F")]
+    fn test_conflict_file_name(#[case] component: &str, #[case] name: &str) {
+        assert_eq!(conflict_file_name(component), name);
+        assert_ne!(conflict_file_name(component), local_name(component, false));
+    }
+
+    #[test]
+    fn test_shorten_name() {
+        let short = "a".repeat(MAX_NAME_LEN);
+        assert_eq!(local_name(&short, false), short);
+        let long = "a".repeat(MAX_NAME_LEN + 1);
+        let name = local_name(&long, false);
+        assert_eq!(name.len(), MAX_NAME_LEN);
+        assert!(name.starts_with(&"a".repeat(MAX_NAME_LEN - SHORTEN_SUFFIX_LEN)));
+        assert_eq!(
+            name[(MAX_NAME_LEN - SHORTEN_SUFFIX_LEN)..],
+            format!("~{}", hex::encode(Md5::digest(long.as_bytes())))
+        );
+        assert_ne!(local_name(&"a".repeat(MAX_NAME_LEN + 2), false), name);
+    }
+
+    #[test]
+    fn test_shorten_name_char_boundary() {
+        let long = "\u{e9}".repeat(MAX_NAME_LEN);
+        let name = shorten_name(Cow::Borrowed(&long), MAX_NAME_LEN);
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(name.starts_with(&"\u{e9}".repeat((MAX_NAME_LEN - SHORTEN_SUFFIX_LEN) / 2)));
+    }
+
+    #[rstest]
+    #[case("Data.nwb", "data.nwb")]
+    #[case("DATA.NWB", "data.nwb")]
+    #[case("caf\u{e9}", "cafe\u{301}")]
+    #[case("CAF\u{c9}", "cafe\u{301}")]
+    fn test_fold_name_eq(#[case] a: &str, #[case] b: &str) {
+        assert_eq!(fold_name(a), fold_name(b));
+    }
+
+    #[rstest]
+    #[case("data.nwb", "data.nw")]
+    #[case("cafe", "caf\u{e9}")]
+    fn test_fold_name_ne(#[case] a: &str, #[case] b: &str) {
+        assert_ne!(fold_name(a), fold_name(b));
+    }
+
+    #[test]
+    fn test_disambiguate_name() {
+        let a = disambiguate_name("Data.nwb", "Data.nwb");
+        let b = disambiguate_name("data.nwb", "data.nwb");
+        assert!(a.starts_with("Data.nwb~"));
+        assert!(b.starts_with("data.nwb~"));
+        assert_ne!(fold_name(&a), fold_name(&b));
     }
 
     #[rstest]
@@ -232,4 +526,20 @@ mod tests {
     fn test_is_special_component(#[case] s: &str, #[case] r: bool) {
         assert_eq!(is_special_component(s), r);
     }
+
+    #[rstest]
+    #[case(".s3invsync.download.a1B2c3", true)]
+    #[case(".s3invsync.versions.Xy9Zq0", true)]
+    #[case(".s3invsync.first-keys.abcdef", true)]
+    #[case(".s3invsync.versions.json", false)]
+    #[case(".s3invsync.state.json", false)]
+    #[case(".s3invsync.download.1234abcd", false)]
+    #[case(".s3invsync.download.a1.b2c", false)]
+    #[case(".s3invsync.esc.abcdef", false)]
+    #[case(".s3invsync.lock", false)]
+    #[case(".s3invsync.tmp", false)]
+    #[case("download.a1B2c3", false)]
+    fn test_is_temporary_name(#[case] s: &str, #[case] r: bool) {
+        assert_eq!(is_temporary_name(s), r);
+    }
 }
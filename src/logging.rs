@@ -0,0 +1,421 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+use time::{Date, OffsetDateTime, UtcOffset};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::format::{FormatEvent, FormatFields, JsonFields, Writer};
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::{FmtContext, FormattedFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+/// The format in which to emit log messages
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+
+    /// One JSON object per line
+    Json,
+}
+
+/// A boxed logging layer, as used to combine layers of different types
+pub(crate) type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Construct a layer that emits log messages at or above `level` to `writer`
+/// in the given format
+pub(crate) fn log_layer<T, W>(
+    format: LogFormat,
+    timer: T,
+    writer: W,
+    ansi: bool,
+    level: Level,
+) -> BoxedLayer
+where
+    T: FormatTime + Send + Sync + 'static,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let targets = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_target("aws_config", Level::DEBUG.min(level))
+        .with_default(Level::INFO.min(level));
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_timer(timer)
+            .with_ansi(ansi)
+            .with_writer(writer)
+            .with_filter(targets)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields::new())
+            .event_format(FlatJson { timer })
+            .with_writer(writer)
+            .with_filter(targets)
+            .boxed(),
+    }
+}
+
+/// An event formatter that emits each event as a single-line JSON object
+/// containing the timestamp, level, and target along with the fields of the
+/// event and of all spans it occurs in as top-level keys.  Where a field name
+/// is used more than once, inner spans take precedence over outer spans, and
+/// the event takes precedence over all spans.
+///
+/// Span fields are only included if the layer's field formatter is
+/// [`JsonFields`].
+#[derive(Clone, Copy, Debug)]
+struct FlatJson<T> {
+    timer: T,
+}
+
+impl<S, N, T> FormatEvent<S, N> for FlatJson<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    T: FormatTime,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        self.timer.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        let mut obj = Map::new();
+        obj.insert("timestamp".into(), timestamp.into());
+        obj.insert("level".into(), metadata.level().as_str().into());
+        obj.insert("target".into(), metadata.target().into());
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<N>>() {
+                    if let Ok(map) = serde_json::from_str::<Map<String, Value>>(fields) {
+                        obj.extend(map);
+                    }
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut obj));
+        let line = serde_json::to_string(&obj).map_err(|_| fmt::Error)?;
+        writeln!(writer, "{line}")
+    }
+}
+
+/// A visitor for recording the fields of an event in a JSON object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// When to rotate the file given by `--log-file`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Rotation {
+    /// Rotate the file when the local date changes
+    Daily,
+
+    /// Rotate the file before it would exceed the given size in bytes
+    Size(u64),
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = ParseRotationError;
+
+    fn from_str(s: &str) -> Result<Rotation, ParseRotationError> {
+        if s.eq_ignore_ascii_case("daily") {
+            return Ok(Rotation::Daily);
+        }
+        match s.parse::<bytesize::ByteSize>() {
+            Ok(size) if size.as_u64() > 0 => Ok(Rotation::Size(size.as_u64())),
+            _ => Err(ParseRotationError),
+        }
+    }
+}
+
+/// Error returned when parsing an invalid `--log-rotate` value
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+#[error(r#"expected "daily" or a nonzero size like "100MiB""#)]
+pub(crate) struct ParseRotationError;
+
+/// A log file that is optionally rotated by date or size.  When the file is
+/// rotated, it is renamed with a `.1` suffix, any existing rotated files are
+/// renamed to the next higher suffix, and files beyond the given number to
+/// keep are discarded.
+#[derive(Debug)]
+pub(crate) struct LogFile {
+    /// The path to the current log file
+    path: PathBuf,
+
+    /// When to rotate the file
+    rotation: Option<Rotation>,
+
+    /// The number of rotated files to keep
+    keep: usize,
+
+    /// The local timezone offset, used to determine the date for daily
+    /// rotation
+    offset: UtcOffset,
+
+    /// The open file & the information needed to decide when to rotate it
+    state: Mutex<LogFileState>,
+}
+
+#[derive(Debug)]
+struct LogFileState {
+    file: File,
+
+    /// The current size of the file in bytes
+    size: u64,
+
+    /// The local date on which the file was last written to
+    date: Date,
+}
+
+impl LogFile {
+    /// Open the log file at `path` for appending, creating it if it does not
+    /// exist
+    pub(crate) fn open(
+        path: PathBuf,
+        rotation: Option<Rotation>,
+        keep: usize,
+        offset: UtcOffset,
+    ) -> io::Result<LogFile> {
+        let file = open_append(&path)?;
+        let metadata = file.metadata()?;
+        let date = metadata
+            .modified()
+            .map_or_else(|_| OffsetDateTime::now_utc(), OffsetDateTime::from)
+            .to_offset(offset)
+            .date();
+        Ok(LogFile {
+            path,
+            rotation,
+            keep,
+            offset,
+            state: Mutex::new(LogFileState {
+                file,
+                size: metadata.len(),
+                date,
+            }),
+        })
+    }
+
+    /// Write `buf` to the file, first rotating the file if needed
+    fn write_record(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("log file mutex should not be poisoned");
+        let today = OffsetDateTime::now_utc().to_offset(self.offset).date();
+        let rotate = match self.rotation {
+            None => false,
+            Some(Rotation::Daily) => state.date != today,
+            Some(Rotation::Size(max)) => {
+                state.size > 0 && state.size.saturating_add(buf.len() as u64) > max
+            }
+        };
+        if rotate {
+            state.file.flush()?;
+            self.rotate()?;
+            state.file = open_append(&self.path)?;
+            state.size = 0;
+        }
+        state.file.write_all(buf)?;
+        state.size = state.size.saturating_add(buf.len() as u64);
+        state.date = today;
+        Ok(buf.len())
+    }
+
+    /// Shift the current log file & the rotated files up by one suffix
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return ignore_not_found(fs_err::remove_file(&self.path));
+        }
+        for i in (1..self.keep).rev() {
+            ignore_not_found(fs_err::rename(
+                self.rotated_path(i),
+                self.rotated_path(i + 1),
+            ))?;
+        }
+        ignore_not_found(fs_err::rename(&self.path, self.rotated_path(1)))
+    }
+
+    /// Returns the path of the log file rotated `i` times
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut s = self.path.clone().into_os_string();
+        s.push(format!(".{i}"));
+        PathBuf::from(s)
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFileWriter<'a>;
+
+    fn make_writer(&'a self) -> LogFileWriter<'a> {
+        LogFileWriter(self)
+    }
+}
+
+/// A handle for writing to a [`LogFile`]
+#[derive(Debug)]
+pub(crate) struct LogFileWriter<'a>(&'a LogFile);
+
+impl Write for LogFileWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_record(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0
+            .state
+            .lock()
+            .expect("log file mutex should not be poisoned")
+            .file
+            .flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    File::options().create(true).append(true).open(path)
+}
+
+fn ignore_not_found(r: io::Result<()>) -> io::Result<()> {
+    match r {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use std::sync::Arc;
+    use tracing_subscriber::prelude::*;
+
+    #[rstest]
+    #[case("daily", Rotation::Daily)]
+    #[case("DAILY", Rotation::Daily)]
+    #[case("1000", Rotation::Size(1000))]
+    #[case("100MiB", Rotation::Size(100 << 20))]
+    fn parse_rotation(#[case] s: &str, #[case] rotation: Rotation) {
+        assert_eq!(s.parse::<Rotation>(), Ok(rotation));
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("0")]
+    #[case("weekly")]
+    fn parse_rotation_err(#[case] s: &str) {
+        assert_eq!(s.parse::<Rotation>(), Err(ParseRotationError));
+    }
+
+    #[derive(Clone, Debug, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&self) -> Buffer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn json_span_fields() {
+        let buf = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(log_layer(
+            LogFormat::Json,
+            (),
+            buf.clone(),
+            false,
+            Level::DEBUG,
+        ));
+        tracing::subscriber::with_default(subscriber, || {
+            let _outer = tracing::info_span!("download", url = "s3://bucket/foo").entered();
+            let _inner = tracing::info_span!("save", path = "foo", attempt = 2).entered();
+            tracing::debug!(size = 42, "Downloaded object");
+        });
+        let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let obj = serde_json::from_str::<Value>(lines[0]).unwrap();
+        assert_eq!(
+            obj,
+            serde_json::json!({
+                "timestamp": "",
+                "level": "DEBUG",
+                "target": env!("CARGO_CRATE_NAME").to_owned() + "::logging::tests",
+                "url": "s3://bucket/foo",
+                "path": "foo",
+                "attempt": 2,
+                "size": 42,
+                "message": "Downloaded object",
+            })
+        );
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("s3invsync.log");
+        let log = LogFile::open(path.clone(), Some(Rotation::Size(10)), 2, UtcOffset::UTC).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.make_writer().write_all(line.as_bytes()).unwrap();
+        }
+        drop(log);
+        assert_eq!(fs_err::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs_err::read_to_string(tmpdir.path().join("s3invsync.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs_err::read_to_string(tmpdir.path().join("s3invsync.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!tmpdir.path().join("s3invsync.log.3").exists());
+    }
+}
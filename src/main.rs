@@ -1,41 +1,90 @@
+mod bucketmap;
 mod consts;
 mod errorset;
 mod inventory;
 mod keypath;
+mod logging;
 mod manifest;
+mod metrics;
 mod nursery;
+mod report;
+mod runlock;
 mod s3;
 mod statefile;
 mod syncer;
 mod timestamps;
 mod util;
+use crate::bucketmap::BucketMapping;
+use crate::consts::RESERVED_PREFIX;
 use crate::errorset::ErrorSet;
-use crate::s3::{get_bucket_region, S3Client, S3Location};
-use crate::statefile::StateFileManager;
-use crate::syncer::Syncer;
-use crate::timestamps::DateMaybeHM;
-use crate::util::is_empty_dir;
+use crate::logging::{log_layer, LogFile, LogFormat, Rotation};
+use crate::metrics::Metrics;
+use crate::report::RunReport;
+use crate::runlock::RunLock;
+use crate::s3::{
+    get_bucket_region, HttpBaseUrl, HttpDownloader, InventoryCache, RestoreTier, S3Client,
+    S3Location,
+};
+use crate::statefile::{RunRecord, RunStatus, StateFileManager};
+use crate::syncer::{ArchivePolicy, NameOptions, ProgressMode, RestoreParams, SyncOptions, Syncer};
+use crate::timestamps::{DateHM, DateMaybeHM};
+use crate::util::{is_empty_dir, sweep_temporaries, sweep_tmpdir};
 use anyhow::Context;
-use clap::Parser;
+use bytesize::ByteSize;
+use clap::{Parser, Subcommand};
 use fs_err::PathExt;
 use futures_util::TryStreamExt;
 use std::io::{stderr, IsTerminal};
+use std::net::SocketAddr;
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime, UtcOffset};
 use tracing::Level;
-use tracing_subscriber::{filter::Targets, fmt::time::OffsetTime, prelude::*};
+use tracing_subscriber::{fmt::time::OffsetTime, prelude::*};
 
 /// Back up an AWS S3 bucket using S3 Inventory files
 ///
 /// See <https://github.com/dandi/s3invsync> for more information.
 #[derive(Clone, Debug, Parser)]
-#[command(version = env!("VERSION_WITH_GIT"))]
+#[command(
+    version = env!("VERSION_WITH_GIT"),
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Arguments {
     /// If OUTDIR is nonempty and does not contain an `.s3invsync.state.json`
     /// file, run the backup anyway instead of erroring out.
     #[arg(long)]
     allow_new_nonempty: bool,
 
+    /// Sync the inventory even if its manifest date is older than that of
+    /// the last successful backup to OUTDIR.  By default, doing so is an
+    /// error, as it would roll the backup back to an earlier state.
+    #[arg(long)]
+    allow_rewind: bool,
+
+    /// Specify what to do with objects in need of downloading that are stored
+    /// in the `GLACIER` or `DEEP_ARCHIVE` storage class or in an archive access
+    /// tier of the `INTELLIGENT_TIERING` storage class.
+    ///
+    /// Such objects cannot be downloaded without first being restored.  The
+    /// number & total size of archived objects in need of downloading are
+    /// logged at the end of each run.
+    ///
+    /// With the `restore` policy, a restore is requested for each archived
+    /// object, and the pending restores are recorded in
+    /// `.s3invsync.restores.json`; later runs download the objects once their
+    /// restores complete.
+    #[arg(long, value_enum, default_value_t, value_name = "POLICY")]
+    archived_objects: ArchivePolicy,
+
+    /// If another process holds the lock on OUTDIR, break the lock and run
+    /// anyway.  Only use this if the process holding the lock is no longer
+    /// running.
+    #[arg(long)]
+    break_lock: bool,
+
     /// Instead of emitting a log message for each object skipped by
     /// `--path-filter`, emit one message for every `N` objects skipped.
     #[arg(long, value_name = "N")]
@@ -51,6 +100,15 @@ struct Arguments {
     #[arg(short, long)]
     date: Option<DateMaybeHM>,
 
+    /// Download objects via plain HTTP(S) GET requests to
+    /// `{URL}/{key}?versionId={versionId}` instead of via the S3 API.
+    ///
+    /// This is useful for downloading public buckets through a CDN or caching
+    /// HTTP proxy.  Any occurrences of `{bucket}` in `URL` are replaced with
+    /// the name of the object's bucket.
+    #[arg(long, value_name = "URL")]
+    http_base_url: Option<HttpBaseUrl>,
+
     /// Treat the given error types as non-fatal.
     ///
     /// If one of the specified types of errors occurs, a warning is emitted,
@@ -76,15 +134,65 @@ struct Arguments {
     #[arg(long, value_name = "LIST")]
     ignore_errors: Option<ErrorSet>,
 
+    /// Detect keys whose names collide on case-insensitive or
+    /// normalization-insensitive filesystems and back them up under
+    /// disambiguated names.
+    ///
+    /// Names in the same directory that are equal after lowercasing & NFC
+    /// normalization are considered to collide.  The first such name in sorted
+    /// order is backed up as normal, and the others have a `~` and the hex MD5
+    /// digest of the name appended.  A warning is emitted for each such key.
+    #[arg(long)]
+    insensitive_names: bool,
+
+    /// Cache downloaded inventory list files in the given directory across
+    /// runs, keyed by their MD5 checksums.
+    ///
+    /// When the total size of the cached files exceeds
+    /// `--inventory-cache-size`, the least recently used files not needed by
+    /// the current run are deleted.  Temporary files left behind by
+    /// interrupted downloads are deleted at startup, and so the directory must
+    /// not be used by multiple backups at once.
+    #[arg(long, value_name = "DIR")]
+    inventory_cache: Option<PathBuf>,
+
+    /// Set the maximum total size of the files in `--inventory-cache`, e.g.,
+    /// `500 MiB` or `20GB`
+    #[arg(long, default_value = "10 GiB", value_name = "SIZE")]
+    inventory_cache_size: ByteSize,
+
     /// Set the maximum number of concurrent download jobs.  Defaults to the
     /// number of available CPU cores, or 20, whichever is lower.
     #[arg(short = 'J', long)]
     jobs: Option<NonZeroUsize>,
 
+    /// Do not shut down when an object cannot be downloaded or otherwise
+    /// processed.  Instead, record the error, skip cleaning up the object's
+    /// directory, continue with the rest of the backup, and exit with an error
+    /// listing all such failures at the end.
+    #[arg(long)]
+    keep_going: bool,
+
     /// List available inventory manifest dates instead of backing anything up
     #[arg(long)]
     list_dates: bool,
 
+    /// Also write log messages to the given file.  When this is set, messages
+    /// below INFO level are only written to the file and not to stderr.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Emit log messages in the given format.  With `json`, each message is
+    /// written as a single-line JSON object in which the message's fields and
+    /// the fields of the spans in which it was emitted (e.g., `url` and
+    /// `path`) are top-level keys.
+    #[arg(long, value_enum, default_value_t, value_name = "FORMAT")]
+    log_format: LogFormat,
+
+    /// Keep up to the given number of rotated log files
+    #[arg(long, default_value_t = 5, value_name = "INT", requires = "log_rotate")]
+    log_keep: usize,
+
     /// Set logging level
     #[arg(
         short,
@@ -94,6 +202,23 @@ struct Arguments {
     )]
     log_level: Level,
 
+    /// Rotate the file given by `--log-file` either daily (`daily`) or
+    /// whenever it would exceed the given size (e.g., `100MiB`)
+    #[arg(long, value_name = "daily|SIZE", requires = "log_file")]
+    log_rotate: Option<Rotation>,
+
+    /// Serve Prometheus metrics for the run over HTTP at `/metrics` on the
+    /// given address (e.g., `127.0.0.1:9180`)
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// At the end of the run, write Prometheus metrics for the run to the
+    /// given file for collection by `node_exporter`'s textfile collector.  The
+    /// file is written even if the run fails at any point after the
+    /// command-line arguments are parsed.
+    #[arg(long, value_name = "FILE")]
+    metrics_textfile: Option<PathBuf>,
+
     /// Deprecated since v0.2.0.  Use `--ignore-errors` instead.
     #[arg(
         long,
@@ -107,11 +232,110 @@ struct Arguments {
     #[arg(long, value_name = "REGEX")]
     path_filter: Option<regex::Regex>,
 
+    /// Escape key components that are not valid Windows filenames, i.e., that
+    /// contain control characters or any of `:\*?"<>|`, that end in a period
+    /// or space, or that are reserved device names (`CON`, `PRN`, `AUX`,
+    /// `NUL`, `COM1` through `COM9`, and `LPT1` through `LPT9`, in any case and
+    /// with or without an extension).
+    ///
+    /// Such components are stored under `.s3invsync.esc.`-prefixed
+    /// percent-encoded names, and the original keys are recorded in the
+    /// `.s3invsync.versions.json` files.  Changing this setting between runs
+    /// causes affected objects to be downloaded again under their new names.
+    #[arg(long)]
+    portable_filenames: bool,
+
+    /// Download up to the given number of inventory list files at once.
+    ///
+    /// Objects are only processed up to the first key of the earliest list
+    /// file still being downloaded, as whether a list file is sorted by key is
+    /// only known once it has been downloaded.  If an unsorted list file
+    /// contains keys before ones already processed, the run fails, and the
+    /// next run downloads the file earlier.
+    #[arg(long, default_value = "2", value_name = "INT")]
+    prefetch_lists: NonZeroUsize,
+
+    /// Display the progress of the backup: the inventory list files read,
+    /// the objects & bytes processed versus those discovered so far, the
+    /// current throughput, and an estimated time remaining.
+    ///
+    /// With `tty`, a status line is continually redrawn on stderr; if stderr
+    /// is not a terminal, `log` is used instead.  With `log`, the status is
+    /// logged at INFO level once a minute.
+    #[arg(long, value_enum, value_name = "MODE")]
+    progress: Option<ProgressMode>,
+
+    /// After syncing the inventory, also sync objects that were created,
+    /// modified, or deleted after the inventory was generated.
+    ///
+    /// This is done by listing all object versions in the inventoried bucket
+    /// and syncing those last modified after the inventory's creation time.
+    /// Objects synced this way are recorded so that subsequent runs using
+    /// older inventories do not delete them.
+    #[arg(long)]
+    recent_changes: bool,
+
+    /// Restrict `--recent-changes` to objects whose keys start with the given
+    /// prefix.  This option can be given multiple times.
+    #[arg(long, value_name = "PREFIX", requires = "recent_changes")]
+    recent_prefix: Vec<String>,
+
+    /// At the end of the run, write a JSON report of the actions taken,
+    /// broken down by category, to the given file.  The report is written
+    /// even if the run fails.
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
     /// Error out immediately if the most recent backup did not complete
-    /// successfully
+    /// successfully.  `--retry-failures` runs are not considered backups for
+    /// this purpose.
     #[arg(long)]
     require_last_success: bool,
 
+    /// Keep restored copies of archived objects available for the given number
+    /// of days when using `--archived-objects restore`.  This is ignored for
+    /// objects in Intelligent-Tiering archive access tiers.
+    #[arg(
+        long,
+        default_value_t = 7,
+        value_name = "DAYS",
+        value_parser = clap::value_parser!(i32).range(1..)
+    )]
+    restore_days: i32,
+
+    /// Set the retrieval tier to use for restoring archived objects when using
+    /// `--archived-objects restore`
+    #[arg(long, value_enum, default_value_t, value_name = "TIER")]
+    restore_tier: RestoreTier,
+
+    /// Instead of syncing the whole inventory, only process the objects
+    /// recorded in `.s3invsync.failures.jsonl` as having failed during the
+    /// previous run.  The objects are looked up in the current inventory, only
+    /// reading the inventory list files whose key ranges could contain them or
+    /// keys that affect where they are stored (all files are read when
+    /// `--insensitive-names` is given), and no files or directories are
+    /// deleted.
+    #[arg(long, conflicts_with = "recent_changes")]
+    retry_failures: bool,
+
+    /// Download objects listed as belonging to bucket `SOURCE` from bucket
+    /// `TARGET` instead (e.g., a same-region replica of `SOURCE`).
+    ///
+    /// Version IDs are kept as-is.  If an object is not found in `TARGET`, it
+    /// is downloaded from `SOURCE`.  This option can be given multiple times.
+    #[arg(long, value_name = "SOURCE=TARGET")]
+    source_bucket_map: Vec<BucketMapping>,
+
+    /// Store temporary files, such as downloaded inventory list files, in the
+    /// given directory.  Defaults to `.s3invsync.tmp` inside OUTDIR, or to the
+    /// system temporary directory when `--list-dates` is given, as OUTDIR is
+    /// then optional and never written to.
+    ///
+    /// Each run creates its own subdirectory, and subdirectories left behind
+    /// by previous runs that did not exit cleanly are deleted at startup.
+    #[arg(long, value_name = "DIR")]
+    tmpdir: Option<PathBuf>,
+
     /// Emit download progress information at TRACE level
     #[arg(long)]
     trace_progress: bool,
@@ -124,11 +348,29 @@ struct Arguments {
     /// manifest files are located in the bucket (i.e., appending a string of
     /// the form `YYYY-MM-DDTHH-MMZ/manifest.json` to `{prefix}/` should yield
     /// a key for a manifest file).
-    inventory_base: S3Location,
+    #[arg(required = true)]
+    inventory_base: Option<S3Location>,
 
     /// Directory in which to download the S3 objects.  Defaults to the current
     /// working directory.
     outdir: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Subcommand)]
+enum Command {
+    /// Show the history of runs recorded in a backup directory, from oldest
+    /// to newest
+    History {
+        /// Output the history as a JSON array
+        #[arg(long)]
+        json: bool,
+
+        /// The backup directory
+        outdir: PathBuf,
+    },
 }
 
 impl Arguments {
@@ -142,14 +384,98 @@ impl Arguments {
         }
     }
 
-    async fn get_client(&self) -> anyhow::Result<S3Client> {
-        let bucket = self.inventory_base.bucket();
+    /// If `--recent-changes` was given, return the key prefixes under which to
+    /// list recent changes, with any prefixes that are covered by other
+    /// prefixes removed
+    fn recent_prefixes(&self) -> Option<Vec<String>> {
+        if !self.recent_changes {
+            return None;
+        }
+        if self.recent_prefix.is_empty() {
+            return Some(vec![String::new()]);
+        }
+        let mut prefixes = self.recent_prefix.clone();
+        prefixes.sort();
+        let mut deduped: Vec<String> = Vec::with_capacity(prefixes.len());
+        for p in prefixes {
+            if !deduped.last().is_some_and(|q| p.starts_with(q.as_str())) {
+                deduped.push(p);
+            }
+        }
+        Some(deduped)
+    }
+
+    /// Construct the options for a [`Syncer`] from the arguments
+    fn sync_options(&self) -> anyhow::Result<SyncOptions> {
+        let ignore_errors = if let Some(ie) = self.ignore_errors {
+            ie
+        } else if let Some(ie) = self.ok_errors {
+            tracing::warn!("--ok-errors is deprecated; use --ignore-errors instead");
+            ie
+        } else {
+            ErrorSet::default()
+        };
+        let http = self
+            .http_base_url
+            .clone()
+            .map(|url| HttpDownloader::new(url, self.trace_progress))
+            .transpose()?;
+        Ok(SyncOptions {
+            jobs: self.jobs()?,
+            prefetch_lists: self.prefetch_lists,
+            path_filter: self.path_filter.clone(),
+            compress_filter_msgs: self.compress_filter_msgs,
+            ignore_errors,
+            bucket_map: self.source_bucket_map.iter().cloned().collect(),
+            http,
+            recent_prefixes: self.recent_prefixes(),
+            archive_policy: self.archived_objects,
+            restore_params: RestoreParams {
+                tier: self.restore_tier,
+                days: self.restore_days,
+            },
+            name_options: NameOptions {
+                fold: self.insensitive_names,
+                portable: self.portable_filenames,
+            },
+            retry_failures: self.retry_failures,
+            keep_going: self.keep_going,
+            progress_mode: self.progress,
+        })
+    }
+
+    /// Construct an S3 client that stores temporary files in a subdirectory
+    /// of `tmpdir`
+    async fn get_client(&self, tmpdir: &Path, metrics: Arc<Metrics>) -> anyhow::Result<S3Client> {
+        let inventory_base = self
+            .inventory_base
+            .clone()
+            .context("missing required INVENTORY_BASE argument")?;
+        let bucket = inventory_base.bucket();
         tracing::info!(%bucket, "Determining region for S3 bucket ...");
-        let region = get_bucket_region(self.inventory_base.bucket()).await?;
+        let region = get_bucket_region(bucket).await?;
         tracing::info!(%bucket, %region, "Found S3 bucket region");
-        S3Client::new(region, self.inventory_base.clone(), self.trace_progress)
-            .await
-            .map_err(Into::into)
+        let inventory_cache = self
+            .inventory_cache
+            .clone()
+            .map(|dir| InventoryCache::new(dir, self.inventory_cache_size.as_u64()))
+            .transpose()
+            .context("failed to create inventory cache directory")?;
+        if !self.list_dates {
+            if let Some(ref cache) = inventory_cache {
+                cache.sweep();
+            }
+        }
+        S3Client::new(
+            region,
+            inventory_base,
+            self.trace_progress,
+            inventory_cache,
+            tmpdir,
+            metrics,
+        )
+        .await
+        .map_err(Into::into)
     }
 }
 
@@ -158,71 +484,228 @@ impl Arguments {
 // for an explanation of the main + #[tokio::main]run thing
 fn main() -> anyhow::Result<()> {
     let args = Arguments::parse();
-    let timer =
-        OffsetTime::local_rfc_3339().context("failed to determine local timezone offset")?;
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_timer(timer)
-                .with_ansi(stderr().is_terminal())
-                .with_writer(stderr),
-        )
-        .with(
-            Targets::new()
-                .with_target(env!("CARGO_CRATE_NAME"), args.log_level)
-                .with_target("aws_config", Level::DEBUG.min(args.log_level))
-                .with_default(Level::INFO.min(args.log_level)),
-        )
-        .init();
+    let offset =
+        UtcOffset::current_local_offset().context("failed to determine local timezone offset")?;
+    let timer = OffsetTime::new(offset, Rfc3339);
+    let mut layers = Vec::new();
+    let stderr_level = if let Some(ref path) = args.log_file {
+        let logfile = LogFile::open(path.clone(), args.log_rotate, args.log_keep, offset)
+            .with_context(|| format!("failed to open log file {}", path.display()))?;
+        layers.push(log_layer(
+            args.log_format,
+            timer.clone(),
+            logfile,
+            false,
+            args.log_level,
+        ));
+        Level::INFO.min(args.log_level)
+    } else {
+        args.log_level
+    };
+    layers.push(log_layer(
+        args.log_format,
+        timer,
+        stderr,
+        stderr().is_terminal(),
+        stderr_level,
+    ));
+    tracing_subscriber::registry().with(layers).init();
     run(args)
 }
 
 #[tokio::main]
 async fn run(args: Arguments) -> anyhow::Result<()> {
-    if args.list_dates {
-        let client = args.get_client().await?;
+    if let Some(Command::History { json, ref outdir }) = args.command {
+        let history = StateFileManager::new(outdir).history()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&history)?);
+        } else {
+            for record in history {
+                println!("{record}");
+            }
+        }
+    } else if args.list_dates {
+        // Don't write to OUTDIR, which may be in use by a backup that would
+        // sweep our temporary directory:
+        let tmpdir = args.tmpdir.clone().unwrap_or_else(std::env::temp_dir);
+        let client = args.get_client(&tmpdir, Arc::default()).await?;
+        let last_date = match args.outdir {
+            Some(ref outdir) => StateFileManager::new(outdir).last_manifest_date()?,
+            None => None,
+        };
         let mut stream = client.list_all_manifest_timestamps();
         while let Some(date) = stream.try_next().await? {
-            println!("{date}");
+            if Some(date) == last_date {
+                println!("{date} (last synced)");
+            } else {
+                println!("{date}");
+            }
         }
     } else {
-        let Some(outdir) = args.outdir.clone() else {
-            anyhow::bail!("missing required OUTDIR argument");
-        };
-        let ignore_errors = if let Some(ie) = args.ignore_errors {
-            ie
-        } else if let Some(ie) = args.ok_errors {
-            tracing::warn!("--ok-errors is deprecated; use --ignore-errors instead");
-            ie
+        let report_path = args.report.clone();
+        let textfile_path = args.metrics_textfile.clone();
+        let retry_failures = args.retry_failures;
+        let metrics = Arc::new(Metrics::default());
+        let mut progress = RunProgress::default();
+        let r = prepare_and_backup(args, metrics.clone(), &mut progress).await;
+        let summary = metrics.run.summary();
+        let terminated = progress
+            .syncer
+            .as_ref()
+            .is_some_and(|syncer| syncer.terminated());
+        metrics.finish(r.is_ok());
+        let status = if r.is_ok() {
+            if retry_failures {
+                RunStatus::Retried
+            } else {
+                RunStatus::Success
+            }
+        } else if terminated {
+            RunStatus::Interrupted
         } else {
-            ErrorSet::default()
+            RunStatus::Failed
         };
-        let jobs = args.jobs()?;
-        let start_time = std::time::Instant::now();
-        tracing::trace!(path = %outdir.display(), "Creating root output directory");
-        fs_err::create_dir_all(&outdir)?;
-        let sfm = StateFileManager::new(&outdir);
-        if !args.allow_new_nonempty && !is_empty_dir(&outdir)? && !sfm.path().fs_err_try_exists()? {
-            anyhow::bail!("Backup directory is nonempty and does not contain a .s3invsync.state.json file; pass --allow-new-nonempty to run anyway");
+        let finished = OffsetDateTime::now_utc();
+        let record = match (progress.recorded, progress.manifest_date) {
+            (Some((sfm, started)), Some(manifest_date)) => Some((
+                sfm,
+                RunRecord {
+                    manifest_date,
+                    started,
+                    finished,
+                    status,
+                    counts: summary.counts(),
+                    version: env!("VERSION_WITH_GIT").to_owned(),
+                },
+            )),
+            _ => None,
+        };
+        let report = report_path.map(|path| {
+            let started = record
+                .as_ref()
+                .map_or(progress.started, |(_, rec)| rec.started);
+            let report = RunReport::new(
+                progress.manifest_date,
+                started,
+                finished,
+                status,
+                summary,
+                r.as_ref().err(),
+            );
+            (report, path)
+        });
+        let ended = record.map(|(sfm, record)| sfm.end(record));
+        drop(progress.lock);
+        match r {
+            Ok(()) => {
+                ended.transpose()?;
+                if let Some((report, path)) = report {
+                    report.write(&path)?;
+                }
+                if let Some(path) = textfile_path {
+                    metrics.write_textfile(&path)?;
+                }
+                tracing::info!("Backup complete");
+            }
+            Err(e) => {
+                if let Some(Err(e2)) = ended {
+                    tracing::warn!(error = ?e2, "Failed to record run in state file");
+                }
+                if let Some((report, path)) = report {
+                    if let Err(e2) = report.write(&path) {
+                        tracing::warn!(error = ?e2, "Failed to write report");
+                    }
+                }
+                if let Some(path) = textfile_path {
+                    if let Err(e2) = metrics.write_textfile(&path) {
+                        tracing::warn!(error = ?e2, "Failed to write metrics textfile");
+                    }
+                }
+                return Err(e);
+            }
         }
-        sfm.start(args.require_last_success)?;
-        let client = args.get_client().await?;
-        tracing::info!("Fetching manifest ...");
-        let (manifest, manifest_date) = client.get_manifest_for_date(args.date).await?;
-        let syncer = Syncer::new(
-            client,
-            outdir,
-            manifest_date,
-            start_time,
-            jobs,
-            args.path_filter,
-            args.compress_filter_msgs,
-            ignore_errors,
-        );
-        tracing::info!("Starting backup ...");
-        syncer.run(manifest).await?;
-        sfm.end()?;
-        tracing::info!("Backup complete");
     }
     Ok(())
 }
+
+/// Information about a backup run gathered as it progresses, used for
+/// recording the run once it ends, whether successfully or not
+struct RunProgress {
+    /// The time at which the run started
+    started: OffsetDateTime,
+
+    /// The date of the inventory manifest being synced, once it has been
+    /// fetched
+    manifest_date: Option<DateHM>,
+
+    /// The manager for the backup's state file and the start time recorded in
+    /// it, once the start of the run has been recorded
+    recorded: Option<(StateFileManager, OffsetDateTime)>,
+
+    /// The syncer, once it has been constructed
+    syncer: Option<Arc<Syncer>>,
+
+    /// The lock on the backup directory, which is held until the run has been
+    /// recorded
+    lock: Option<RunLock>,
+}
+
+impl Default for RunProgress {
+    fn default() -> RunProgress {
+        RunProgress {
+            started: OffsetDateTime::now_utc(),
+            manifest_date: None,
+            recorded: None,
+            syncer: None,
+            lock: None,
+        }
+    }
+}
+
+/// Prepare the backup directory, fetch the inventory manifest, record the
+/// start of the run, and perform the backup, storing information about the
+/// run in `progress` as it becomes available.  Once the syncer is
+/// constructed, it is stored in `progress` so that its statistics can be
+/// recorded in the run history even if the backup fails.
+async fn prepare_and_backup(
+    args: Arguments,
+    metrics: Arc<Metrics>,
+    progress: &mut RunProgress,
+) -> anyhow::Result<()> {
+    let Some(outdir) = args.outdir.clone() else {
+        anyhow::bail!("missing required OUTDIR argument");
+    };
+    let options = args.sync_options()?;
+    let start_time = std::time::Instant::now();
+    tracing::trace!(path = %outdir.display(), "Creating root output directory");
+    fs_err::create_dir_all(&outdir)?;
+    let sfm = StateFileManager::new(&outdir);
+    if !args.allow_new_nonempty && !is_empty_dir(&outdir)? && !sfm.path().fs_err_try_exists()? {
+        anyhow::bail!("Backup directory is nonempty and does not contain a .s3invsync.state.json file; pass --allow-new-nonempty to run anyway");
+    }
+    progress.lock = Some(RunLock::acquire(&outdir, args.break_lock)?);
+    sweep_temporaries(&outdir)?;
+    if let Some(addr) = args.metrics_listen {
+        metrics.serve(addr).await?;
+    }
+    let tmpdir = args
+        .tmpdir
+        .clone()
+        .unwrap_or_else(|| outdir.join(format!("{RESERVED_PREFIX}.tmp")));
+    sweep_tmpdir(&tmpdir)?;
+    let client = args.get_client(&tmpdir, metrics.clone()).await?;
+    tracing::info!("Fetching manifest ...");
+    let (manifest, date) = client.get_manifest_for_date(args.date).await?;
+    progress.manifest_date = Some(date);
+    // Check the date before recording the start of the run so that a refused
+    // rewind leaves the state file untouched:
+    sfm.check_manifest_date(date, args.allow_rewind)?;
+    let started = sfm.start(args.require_last_success, args.retry_failures)?;
+    progress.recorded = Some((sfm, started));
+    let syncer = progress.syncer.insert(Syncer::new(
+        client, outdir, date, start_time, metrics, options,
+    ));
+    tracing::info!("Starting backup ...");
+    syncer.clone().run(manifest).await?;
+    Ok(())
+}
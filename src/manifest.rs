@@ -1,11 +1,18 @@
 use crate::inventory::FileSchema;
 use serde::Deserialize;
 use thiserror::Error;
+use time::OffsetDateTime;
 
 /// A listing of CSV inventory files from a manifest
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 #[serde(try_from = "RawManifest")]
 pub(crate) struct CsvManifest {
+    /// The name of the bucket that the inventory is for
+    pub(crate) source_bucket: String,
+
+    /// The time at which the inventory was started
+    pub(crate) creation_timestamp: OffsetDateTime,
+
     pub(crate) files: Vec<FileSpec>,
 }
 
@@ -16,6 +23,12 @@ impl TryFrom<RawManifest> for CsvManifest {
         if value.file_format != FileFormat::Csv {
             Err(ManifestError::Format(value.file_format))
         } else {
+            let creation_timestamp = value
+                .creation_timestamp
+                .parse::<i128>()
+                .ok()
+                .and_then(|ms| OffsetDateTime::from_unix_timestamp_nanos(ms * 1_000_000).ok())
+                .ok_or(ManifestError::Timestamp(value.creation_timestamp))?;
             let files = value
                 .files
                 .into_iter()
@@ -26,7 +39,11 @@ impl TryFrom<RawManifest> for CsvManifest {
                     file_schema: value.file_schema.clone(),
                 })
                 .collect();
-            Ok(CsvManifest { files })
+            Ok(CsvManifest {
+                source_bucket: value.source_bucket,
+                creation_timestamp,
+                files,
+            })
         }
     }
 }
@@ -38,16 +55,21 @@ pub(crate) enum ManifestError {
     /// CSV
     #[error("inventory files are in {0:?} format; only CSV is supported")]
     Format(FileFormat),
+
+    /// Returned when a manifest's creation timestamp is not a valid number of
+    /// milliseconds since the Unix epoch
+    #[error("invalid manifest creation timestamp: {0:?}")]
+    Timestamp(String),
 }
 
 /// Parsed `manifest.json` file
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 struct RawManifest {
-    //source_bucket: String,
+    source_bucket: String,
     //destination_bucket: String,
     //version: String,
-    //creation_timestamp: String,
+    creation_timestamp: String,
     file_format: FileFormat,
     file_schema: FileSchema,
     files: Vec<RawFileSpec>,
@@ -94,3 +116,38 @@ pub(crate) struct RawFileSpec {
     #[serde(rename = "MD5checksum")]
     pub(crate) md5_checksum: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = serde_json::from_str::<CsvManifest>(
+            r#"{
+                "sourceBucket": "dandiarchive",
+                "destinationBucket": "arn:aws:s3:::dandiarchive-inventory",
+                "version": "2016-11-30",
+                "creationTimestamp": "1731560400000",
+                "fileFormat": "CSV",
+                "fileSchema": "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, LastModifiedDate, ETag, IsMultipartUploaded",
+                "files": [
+                    {
+                        "key": "dandiarchive/dandiarchive/data/ee9f3a2b-9b1c-4e6b-a2a4-5d4d4f6ce1d3.csv.gz",
+                        "size": 7031,
+                        "MD5checksum": "5bbb5fe7fc4c1c1e3a0a26bbc57c3d2f"
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.source_bucket, "dandiarchive");
+        assert_eq!(
+            manifest.creation_timestamp,
+            datetime!(2024-11-14 05:00:00 UTC)
+        );
+        assert_eq!(manifest.files.len(), 1);
+        assert_eq!(manifest.files[0].size, 7031);
+    }
+}
@@ -0,0 +1,498 @@
+//! Prometheus metrics for monitoring backups
+use crate::syncer::{RunStats, Totals};
+use anyhow::Context;
+use aws_sdk_s3::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::interceptors::context::BeforeTransmitInterceptorContextRef;
+use aws_smithy_runtime_api::client::retries::RequestAttempts;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds in seconds of the buckets of [`Metrics::get_object_duration`]
+const DURATION_BUCKETS: [f64; 14] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+/// The maximum size in bytes of an HTTP request head accepted by the metrics
+/// endpoint
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// The metrics collected during a run
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    /// Tally of the actions taken on objects & local files, by outcome
+    pub(crate) run: RunStats,
+
+    /// Durations of "Get Object" requests until the response headers are
+    /// received
+    pub(crate) get_object_duration: Histogram,
+
+    /// Number of S3 requests retried by the AWS SDK
+    pub(crate) s3_retries: Counter,
+
+    /// Number of objects currently being processed
+    pub(crate) jobs_in_flight: Gauge,
+
+    /// Number of inventory list files in the inventory being synced
+    pub(crate) inventory_lists: Gauge,
+
+    /// Number of inventory list files downloaded so far
+    pub(crate) inventory_lists_downloaded: Counter,
+
+    /// Number of entries read from inventory list files so far
+    pub(crate) inventory_entries: Counter,
+
+    /// Whether the run completed successfully; set at the end of the run
+    run_success: Gauge,
+
+    /// The UNIX time at which the run finished; set at the end of the run
+    run_finished: Gauge,
+}
+
+impl Metrics {
+    /// Record the end of the run
+    pub(crate) fn finish(&self, success: bool) {
+        self.run_success.set(i64::from(success));
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.run_finished
+            .set(i64::try_from(now).unwrap_or(i64::MAX));
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_into(&mut out)
+            .expect("writing to a String should not fail");
+        out
+    }
+
+    fn render_into(&self, out: &mut String) -> fmt::Result {
+        let summary = self.run.summary();
+        let outcomes: [(&str, Totals); 10] = [
+            ("downloaded_latest", summary.downloaded_latest),
+            ("downloaded_old", summary.downloaded_old),
+            ("renamed_latest_to_old", summary.renamed_latest_to_old),
+            ("renamed_old_to_latest", summary.renamed_old_to_latest),
+            ("unchanged", summary.unchanged),
+            ("filtered", summary.filtered),
+            ("archived", summary.archived),
+            ("delete_marker", summary.delete_markers),
+            ("deleted_file", summary.deleted_files),
+            ("deleted_directory", summary.deleted_directories),
+        ];
+        header(
+            out,
+            "objects_total",
+            "counter",
+            "Objects & local entries processed, by outcome",
+        )?;
+        for (outcome, totals) in outcomes {
+            writeln!(
+                out,
+                "s3invsync_objects_total{{outcome=\"{outcome}\"}} {}",
+                totals.count
+            )?;
+        }
+        header(
+            out,
+            "object_bytes_total",
+            "counter",
+            "Total size of objects & local entries processed, by outcome",
+        )?;
+        for (outcome, totals) in outcomes {
+            writeln!(
+                out,
+                "s3invsync_object_bytes_total{{outcome=\"{outcome}\"}} {}",
+                totals.bytes
+            )?;
+        }
+        header(
+            out,
+            "downloaded_bytes_total",
+            "counter",
+            "Total size of downloaded objects",
+        )?;
+        writeln!(
+            out,
+            "s3invsync_downloaded_bytes_total {}",
+            summary.downloaded_latest.bytes + summary.downloaded_old.bytes
+        )?;
+        let mut ignored = BTreeMap::new();
+        for e in &summary.ignored_errors {
+            *ignored.entry(<&str>::from(e.kind)).or_insert(0u64) += 1;
+        }
+        header(
+            out,
+            "ignored_errors_total",
+            "counter",
+            "Download errors ignored due to --ignore-errors, by type",
+        )?;
+        for (kind, count) in ignored {
+            writeln!(
+                out,
+                "s3invsync_ignored_errors_total{{type=\"{kind}\"}} {count}"
+            )?;
+        }
+        self.get_object_duration.render(
+            out,
+            "get_object_duration_seconds",
+            "Durations of S3 GetObject requests until the response headers are received",
+        )?;
+        header(
+            out,
+            "s3_retries_total",
+            "counter",
+            "S3 request attempts that were retries",
+        )?;
+        writeln!(out, "s3invsync_s3_retries_total {}", self.s3_retries.get())?;
+        header(
+            out,
+            "jobs_in_flight",
+            "gauge",
+            "Objects currently being processed",
+        )?;
+        writeln!(
+            out,
+            "s3invsync_jobs_in_flight {}",
+            self.jobs_in_flight.get()
+        )?;
+        header(
+            out,
+            "inventory_lists",
+            "gauge",
+            "Inventory list files in the inventory being synced",
+        )?;
+        writeln!(
+            out,
+            "s3invsync_inventory_lists {}",
+            self.inventory_lists.get()
+        )?;
+        header(
+            out,
+            "inventory_lists_downloaded_total",
+            "counter",
+            "Inventory list files downloaded",
+        )?;
+        writeln!(
+            out,
+            "s3invsync_inventory_lists_downloaded_total {}",
+            self.inventory_lists_downloaded.get()
+        )?;
+        header(
+            out,
+            "inventory_entries_total",
+            "counter",
+            "Entries read from inventory list files",
+        )?;
+        writeln!(
+            out,
+            "s3invsync_inventory_entries_total {}",
+            self.inventory_entries.get()
+        )?;
+        if let Some(st) = memory_stats::memory_stats() {
+            header(
+                out,
+                "physical_memory_bytes",
+                "gauge",
+                "Physical memory used by the process",
+            )?;
+            writeln!(out, "s3invsync_physical_memory_bytes {}", st.physical_mem)?;
+            header(
+                out,
+                "virtual_memory_bytes",
+                "gauge",
+                "Virtual memory used by the process",
+            )?;
+            writeln!(out, "s3invsync_virtual_memory_bytes {}", st.virtual_mem)?;
+        }
+        if self.run_finished.get() != 0 {
+            header(
+                out,
+                "run_success",
+                "gauge",
+                "Whether the run completed successfully",
+            )?;
+            writeln!(out, "s3invsync_run_success {}", self.run_success.get())?;
+            header(
+                out,
+                "run_finished_timestamp_seconds",
+                "gauge",
+                "UNIX time at which the run finished",
+            )?;
+            writeln!(
+                out,
+                "s3invsync_run_finished_timestamp_seconds {}",
+                self.run_finished.get()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Atomically write the metrics to `path` in the format expected by
+    /// `node_exporter`'s textfile collector
+    pub(crate) fn write_textfile(&self, path: &Path) -> anyhow::Result<()> {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let mut fp = tempfile::Builder::new()
+            .prefix(".s3invsync-metrics.")
+            .tempfile_in(parent)
+            .with_context(|| {
+                format!(
+                    "failed to create temporary file for writing metrics to {}",
+                    path.display()
+                )
+            })?;
+        fp.write_all(self.render().as_bytes())
+            .with_context(|| format!("failed to write metrics to {}", path.display()))?;
+        fp.persist(path)
+            .with_context(|| format!("failed to persist metrics to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Start serving the metrics over HTTP on `addr` in a background task
+    pub(crate) async fn serve(self: &Arc<Self>, addr: SocketAddr) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to listen for metrics requests on {addr}"))?;
+        tracing::info!(%addr, "Serving metrics");
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((conn, _)) => {
+                        let this = this.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = this.respond(conn).await {
+                                tracing::debug!(error = ?e, "Failed to respond to metrics request");
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        tracing::warn!(error = ?e, "Failed to accept metrics connection");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Read an HTTP request from `conn` and respond with the metrics if the
+    /// request is for `/metrics`
+    async fn respond(&self, mut conn: TcpStream) -> std::io::Result<()> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_REQUEST_SIZE {
+            let n = conn.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let request_line = buf.split(|&b| b == b'\n').next().unwrap_or_default();
+        let mut words = request_line.split(|&b| b == b' ');
+        let response = match (words.next(), words.next()) {
+            (Some(b"GET"), Some(b"/metrics")) => {
+                let body = self.render();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+            }
+            _ => String::from(
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            ),
+        };
+        conn.write_all(response.as_bytes()).await?;
+        conn.shutdown().await
+    }
+}
+
+/// Write the `HELP` and `TYPE` lines for the metric `s3invsync_{name}`
+fn header(out: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP s3invsync_{name} {help}")?;
+    writeln!(out, "# TYPE s3invsync_{name} {kind}")
+}
+
+/// A monotonically increasing count
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A distribution of durations, bucketed by [`DURATION_BUCKETS`]
+#[derive(Debug, Default)]
+pub(crate) struct Histogram {
+    /// The number of observations in each bucket (non-cumulative), with an
+    /// extra bucket at the end for observations above the largest bound
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+
+    /// The sum of all observations in nanoseconds
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let i = DURATION_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(
+            u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+        header(out, name, "histogram", help)?;
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match DURATION_BUCKETS.get(i) {
+                Some(le) => writeln!(out, "s3invsync_{name}_bucket{{le=\"{le}\"}} {cumulative}")?,
+                None => writeln!(out, "s3invsync_{name}_bucket{{le=\"+Inf\"}} {cumulative}")?,
+            }
+        }
+        let sum = Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed));
+        writeln!(out, "s3invsync_{name}_sum {}", sum.as_secs_f64())?;
+        writeln!(out, "s3invsync_{name}_count {cumulative}")
+    }
+}
+
+/// An AWS SDK interceptor that counts retried request attempts in
+/// [`Metrics::s3_retries`]
+#[derive(Debug)]
+pub(crate) struct RetryCounter(pub(crate) Arc<Metrics>);
+
+impl Intercept for RetryCounter {
+    fn name(&self) -> &'static str {
+        "RetryCounter"
+    }
+
+    fn read_before_attempt(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if cfg
+            .load::<RequestAttempts>()
+            .is_some_and(|a| a.attempts() > 1)
+        {
+            self.0.s3_retries.inc();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_histogram() {
+        let hist = Histogram::default();
+        hist.observe(Duration::from_millis(20));
+        hist.observe(Duration::from_millis(700));
+        hist.observe(Duration::from_secs(600));
+        let mut out = String::new();
+        hist.render(&mut out, "test_seconds", "Test").unwrap();
+        assert!(out.contains("s3invsync_test_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(out.contains("s3invsync_test_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(out.contains("s3invsync_test_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(out.contains("s3invsync_test_seconds_bucket{le=\"120\"} 2\n"));
+        assert!(out.contains("s3invsync_test_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(out.contains("s3invsync_test_seconds_sum 600.72\n"));
+        assert!(out.contains("s3invsync_test_seconds_count 3\n"));
+    }
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::default();
+        metrics.jobs_in_flight.inc();
+        metrics.jobs_in_flight.inc();
+        metrics.jobs_in_flight.dec();
+        metrics.inventory_entries.inc();
+        let out = metrics.render();
+        assert!(out.contains("# TYPE s3invsync_objects_total counter\n"));
+        assert!(out.contains("s3invsync_objects_total{outcome=\"downloaded_latest\"} 0\n"));
+        assert!(out.contains("s3invsync_jobs_in_flight 1\n"));
+        assert!(out.contains("s3invsync_inventory_entries_total 1\n"));
+        assert!(!out.contains("s3invsync_run_success"));
+        metrics.finish(true);
+        assert!(metrics.render().contains("s3invsync_run_success 1\n"));
+    }
+
+    #[test]
+    fn write_textfile_for_failed_run() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("s3invsync.prom");
+        let metrics = Metrics::default();
+        metrics.finish(false);
+        metrics.write_textfile(&path).unwrap();
+        let out = fs_err::read_to_string(&path).unwrap();
+        assert!(out.contains("s3invsync_run_success 0\n"));
+        assert!(out.contains("s3invsync_run_finished_timestamp_seconds "));
+        assert!(out.contains("s3invsync_objects_total{outcome=\"downloaded_latest\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn serve_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        metrics.serve(addr).await.unwrap();
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("s3invsync_jobs_in_flight 0\n"));
+    }
+}
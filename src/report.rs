@@ -0,0 +1,158 @@
+use crate::statefile::RunStatus;
+use crate::syncer::RunSummary;
+use crate::timestamps::DateHM;
+use crate::util::MultiError;
+use anyhow::Context;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+use time::OffsetDateTime;
+
+/// A machine-readable summary of a run, written to the file given by
+/// `--report`
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub(crate) struct RunReport {
+    /// The date of the inventory manifest being synced, or `None` if the run
+    /// failed before the manifest was fetched
+    manifest_date: Option<DateHM>,
+
+    /// The time at which the run started
+    #[serde(with = "time::serde::rfc3339")]
+    started: OffsetDateTime,
+
+    /// The time at which the run finished
+    #[serde(with = "time::serde::rfc3339")]
+    finished: OffsetDateTime,
+
+    /// How the run ended
+    status: RunStatus,
+
+    /// The version of s3invsync that performed the run
+    version: String,
+
+    /// Counts & byte totals of the actions taken during the run, by category
+    #[serde(flatten)]
+    summary: RunSummary,
+
+    /// The errors that caused the run to fail
+    fatal_errors: Vec<String>,
+}
+
+impl RunReport {
+    /// Construct a report for a run with the given details & summary, which
+    /// failed with `error` (if any)
+    pub(crate) fn new(
+        manifest_date: Option<DateHM>,
+        started: OffsetDateTime,
+        finished: OffsetDateTime,
+        status: RunStatus,
+        summary: RunSummary,
+        error: Option<&anyhow::Error>,
+    ) -> RunReport {
+        let fatal_errors = match error {
+            Some(e) => match e.downcast_ref::<MultiError>() {
+                Some(MultiError(errors)) => errors.iter().map(|e| format!("{e:#}")).collect(),
+                None => vec![format!("{e:#}")],
+            },
+            None => Vec::new(),
+        };
+        RunReport {
+            manifest_date,
+            started,
+            finished,
+            status,
+            version: env!("VERSION_WITH_GIT").to_owned(),
+            summary,
+            fatal_errors,
+        }
+    }
+
+    /// Atomically write the report as JSON to `path`
+    pub(crate) fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let fp = tempfile::Builder::new()
+            .prefix(".s3invsync-report.")
+            .tempfile_in(parent)
+            .with_context(|| {
+                format!(
+                    "failed to create temporary file for writing report to {}",
+                    path.display()
+                )
+            })?;
+        serde_json::to_writer_pretty(fp.as_file(), self)
+            .with_context(|| format!("failed to serialize report to {}", path.display()))?;
+        fp.as_file().write_all(b"\n").with_context(|| {
+            format!("failed to write terminating newline to {}", path.display())
+        })?;
+        fp.persist(path)
+            .with_context(|| format!("failed to persist report to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_failed_report() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("report.json");
+        let error = anyhow::Error::from(MultiError(vec![
+            anyhow::anyhow!("first"),
+            anyhow::anyhow!("second").context("wrapped"),
+        ]));
+        RunReport::new(
+            Some("2025-03-01T01-00Z".parse().unwrap()),
+            time::macros::datetime!(2025-03-01 12:00:00 UTC),
+            time::macros::datetime!(2025-03-01 13:00:00 UTC),
+            RunStatus::Failed,
+            RunSummary::default(),
+            Some(&error),
+        )
+        .write(&path)
+        .unwrap();
+        let report =
+            serde_json::from_str::<serde_json::Value>(&fs_err::read_to_string(&path).unwrap())
+                .unwrap();
+        assert_eq!(report["status"], "failed");
+        assert_eq!(report["manifest_date"], "2025-03-01T01-00Z");
+        assert_eq!(
+            report["downloaded_latest"],
+            serde_json::json!({"count": 0, "bytes": 0})
+        );
+        assert_eq!(
+            report["fatal_errors"],
+            serde_json::json!(["first", "wrapped: second"])
+        );
+    }
+
+    #[test]
+    fn write_report_without_manifest() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("report.json");
+        let error = anyhow::anyhow!("could not fetch manifest");
+        RunReport::new(
+            None,
+            time::macros::datetime!(2025-03-01 12:00:00 UTC),
+            time::macros::datetime!(2025-03-01 12:00:05 UTC),
+            RunStatus::Failed,
+            RunSummary::default(),
+            Some(&error),
+        )
+        .write(&path)
+        .unwrap();
+        let report =
+            serde_json::from_str::<serde_json::Value>(&fs_err::read_to_string(&path).unwrap())
+                .unwrap();
+        assert_eq!(report["status"], "failed");
+        assert_eq!(report["manifest_date"], serde_json::Value::Null);
+        assert_eq!(
+            report["fatal_errors"],
+            serde_json::json!(["could not fetch manifest"])
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::consts::RESERVED_PREFIX;
+use anyhow::Context;
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::path::Path;
+use time::OffsetDateTime;
+
+/// An exclusive advisory lock on a backup directory, held for the duration of
+/// a run in order to prevent concurrent runs from syncing into the same
+/// directory.  The lock is released on drop.
+///
+/// While the lock is held, the lock file contains a [`LockInfo`] describing
+/// the process holding it.
+#[derive(Debug)]
+pub(crate) struct RunLock {
+    /// The open handle to the lock file on which the lock is held
+    _file: File,
+}
+
+impl RunLock {
+    /// Acquire the lock on `outdir`.  If another process holds the lock, then
+    /// if `break_lock` is true, the lock file is deleted and recreated, and a
+    /// lock on the new file is acquired; otherwise, an error describing the
+    /// holder of the lock is returned immediately.
+    pub(crate) fn acquire(outdir: &Path, break_lock: bool) -> anyhow::Result<RunLock> {
+        let path = outdir.join(format!("{RESERVED_PREFIX}.lock"));
+        let mut file = open_lockfile(&path)?;
+        if !try_lock(&file, &path)? {
+            let holder = LockInfo::read(&path);
+            if !break_lock {
+                match holder {
+                    Some(info) => anyhow::bail!(
+                        "Backup directory {} is locked by s3invsync {info}; if that process is no longer running, rerun with --break-lock",
+                        outdir.display()
+                    ),
+                    None => anyhow::bail!(
+                        "Backup directory {} is locked by another s3invsync process; if that process is no longer running, rerun with --break-lock",
+                        outdir.display()
+                    ),
+                }
+            }
+            if let Some(info) = holder {
+                tracing::warn!(holder = %info, "Breaking lock held by another process");
+            } else {
+                tracing::warn!("Breaking lock held by another process");
+            }
+            drop(file);
+            fs_err::remove_file(&path)?;
+            file = open_lockfile(&path)?;
+            if !try_lock(&file, &path)? {
+                anyhow::bail!(
+                    "Failed to lock {} after breaking the previous lock",
+                    path.display()
+                );
+            }
+        }
+        tracing::debug!(path = %path.display(), "Acquired run lock");
+        LockInfo::current()
+            .write(&mut file)
+            .with_context(|| format!("failed to write lock information to {}", path.display()))?;
+        Ok(RunLock { _file: file })
+    }
+}
+
+fn open_lockfile(path: &Path) -> anyhow::Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("failed to open lock file {}", path.display()))
+}
+
+/// Try to acquire an exclusive lock on `file`, returning `false` if another
+/// process holds a lock on it
+fn try_lock(file: &File, path: &Path) -> anyhow::Result<bool> {
+    file.try_lock_exclusive()
+        .with_context(|| format!("failed to lock {}", path.display()))
+}
+
+/// Information about the process holding a [`RunLock`]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct LockInfo {
+    /// The process ID
+    pid: u32,
+
+    /// The hostname of the machine on which the process is running
+    host: String,
+
+    /// The time at which the lock was acquired
+    #[serde(with = "time::serde::rfc3339")]
+    started: OffsetDateTime,
+}
+
+impl LockInfo {
+    /// Return information about the current process
+    fn current() -> LockInfo {
+        LockInfo {
+            pid: std::process::id(),
+            host: gethostname::gethostname().to_string_lossy().into_owned(),
+            started: OffsetDateTime::now_utc(),
+        }
+    }
+
+    /// Read the information from the lock file at `path`.  Returns `None` if
+    /// the file cannot be read or parsed.
+    fn read(path: &Path) -> Option<LockInfo> {
+        let content = fs_err::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Replace the contents of `file` with the serialized information
+    fn write(&self, file: &mut File) -> std::io::Result<()> {
+        file.set_len(0)?;
+        file.rewind()?;
+        serde_json::to_writer_pretty(&mut *file, self)?;
+        file.write_all(b"\n")?;
+        file.flush()
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let started = self
+            .started
+            .format(&time::format_description::well_known::Rfc3339)
+            .map_err(|_| fmt::Error)?;
+        write!(
+            f,
+            "process {} on host {} (started {started})",
+            self.pid, self.host
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_info_roundtrip() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("lock");
+        let info = LockInfo {
+            pid: 1234,
+            host: "backups.example.com".into(),
+            started: time::macros::datetime!(2025-03-01 12:34:56 UTC),
+        };
+        let mut file = open_lockfile(&path).unwrap();
+        info.write(&mut file).unwrap();
+        assert_eq!(LockInfo::read(&path), Some(info.clone()));
+        assert_eq!(
+            info.to_string(),
+            "process 1234 on host backups.example.com (started 2025-03-01T12:34:56Z)"
+        );
+    }
+
+    #[test]
+    fn lock_is_exclusive() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let lock = RunLock::acquire(tmpdir.path(), false).unwrap();
+        let e = RunLock::acquire(tmpdir.path(), false).unwrap_err();
+        assert!(e.to_string().contains("rerun with --break-lock"));
+        assert!(e
+            .to_string()
+            .contains(&format!("process {}", std::process::id())));
+        let _broken = RunLock::acquire(tmpdir.path(), true).unwrap();
+        drop(lock);
+    }
+}
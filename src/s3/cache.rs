@@ -0,0 +1,291 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+
+/// A persistent local directory in which downloaded inventory list files are
+/// cached across runs, keyed by their MD5 checksums.  Whenever a file is added
+/// and the total size of the cached files exceeds a limit, the least recently
+/// used files are deleted until the total is back under the limit.  Files
+/// used during the current run are never deleted, as they may still need to be
+/// opened.
+#[derive(Clone, Debug)]
+pub(crate) struct InventoryCache {
+    /// The cache directory
+    dir: PathBuf,
+
+    /// The maximum total size in bytes of the cached files
+    max_size: u64,
+
+    /// The paths of the cached files that have been used during the current
+    /// run
+    pinned: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl InventoryCache {
+    /// Create a cache in the directory `dir`, creating the directory if it
+    /// does not exist
+    pub(crate) fn new(dir: PathBuf, max_size: u64) -> std::io::Result<InventoryCache> {
+        fs_err::create_dir_all(&dir)?;
+        Ok(InventoryCache {
+            dir,
+            max_size,
+            pinned: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    /// Delete any temporary download files left behind in the cache directory
+    /// by previous runs that did not exit cleanly.  This must only be called
+    /// at startup, as it would also delete files being downloaded by other
+    /// runs.
+    pub(crate) fn sweep(&self) {
+        let entries = match fs_err::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!(error = ?e, "Failed to list inventory cache directory");
+                return;
+            }
+        };
+        for entry in entries.filter_map(Result::ok) {
+            if !is_download_tempfile(&entry.path()) {
+                continue;
+            }
+            tracing::debug!(path = %entry.path().display(), "Deleting leftover temporary file from inventory cache");
+            if let Err(e) = fs_err::remove_file(entry.path()) {
+                tracing::warn!(error = ?e, "Failed to delete leftover temporary file from inventory cache");
+            }
+        }
+    }
+
+    /// Protect the cached file at `path` from eviction for the rest of the run
+    fn pin(&self, path: &Path) {
+        self.pinned
+            .lock()
+            .expect("pinned mutex should not be poisoned")
+            .insert(path.to_owned());
+    }
+
+    /// Returns the path at which the list file with the given MD5 checksum is
+    /// cached.  Files whose entries are not sorted by key are stored under a
+    /// different name so that they can be recognized as such.
+    fn path(&self, md5_digest: &str, sorted: bool) -> PathBuf {
+        if sorted {
+            self.dir.join(format!("{md5_digest}.csv.gz"))
+        } else {
+            self.dir.join(format!("{md5_digest}.unsorted.csv.gz"))
+        }
+    }
+
+    /// If a list file with the given MD5 checksum is cached, mark it as
+    /// recently used and return an open handle to it, its path, and whether
+    /// its entries are sorted by key
+    pub(crate) fn get(&self, md5_digest: &str) -> Option<(File, PathBuf, bool)> {
+        let (fp, path, sorted) = [true, false].into_iter().find_map(|sorted| {
+            let path = self.path(md5_digest, sorted);
+            File::open(&path).ok().map(|fp| (fp, path, sorted))
+        })?;
+        if let Err(e) = fp.set_modified(SystemTime::now()) {
+            tracing::debug!(path = %path.display(), error = ?e, "Failed to update modification time of cached inventory list");
+        }
+        tracing::debug!(path = %path.display(), "Using cached inventory list");
+        self.pin(&path);
+        Some((fp, path, sorted))
+    }
+
+    /// Create a temporary file in the cache directory in which to download a
+    /// list file for adding to the cache with [`InventoryCache::insert()`]
+    pub(crate) fn tempfile(&self) -> std::io::Result<NamedTempFile> {
+        tempfile::Builder::new()
+            .prefix(DOWNLOAD_PREFIX)
+            .tempfile_in(&self.dir)
+    }
+
+    /// Move a temporary file containing the fully-downloaded list file with
+    /// the given MD5 checksum into the cache, evict least recently used files
+    /// as needed, and return the file's path, which is pinned for the rest of
+    /// the run.  `sorted` indicates whether the file's entries are sorted by
+    /// key.
+    pub(crate) fn insert(
+        &self,
+        tmpfile: NamedTempFile,
+        md5_digest: &str,
+        sorted: bool,
+    ) -> std::io::Result<PathBuf> {
+        let path = self.path(md5_digest, sorted);
+        tmpfile.persist(&path).map_err(|e| e.error)?;
+        self.pin(&path);
+        self.evict();
+        Ok(path)
+    }
+
+    /// Delete the least recently used unpinned cached files until the total
+    /// size of the files in the cache directory, including in-progress
+    /// downloads, does not exceed `max_size`
+    fn evict(&self) {
+        let entries = match fs_err::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!(error = ?e, "Failed to list inventory cache directory");
+                return;
+            }
+        };
+        let pinned = self
+            .pinned
+            .lock()
+            .expect("pinned mutex should not be poisoned")
+            .clone();
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let is_tempfile = is_download_tempfile(&path);
+            if !is_tempfile && !path.to_str().is_some_and(|s| s.ends_with(".csv.gz")) {
+                continue;
+            }
+            let Ok(md) = entry.metadata() else {
+                continue;
+            };
+            total += md.len();
+            if !is_tempfile && !pinned.contains(&path) {
+                files.push((
+                    md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    md.len(),
+                    path,
+                ));
+            }
+        }
+        files.sort();
+        for (_, size, path) in files {
+            if total <= self.max_size {
+                break;
+            }
+            tracing::debug!(path = %path.display(), "Evicting inventory list from cache");
+            match fs_err::remove_file(&path) {
+                Ok(()) => total -= size,
+                Err(e) => tracing::warn!(error = ?e, "Failed to evict inventory list from cache"),
+            }
+        }
+    }
+}
+
+/// The filename prefix of temporary files in which list files are downloaded
+const DOWNLOAD_PREFIX: &str = ".download.";
+
+/// Returns true if `path` is a temporary file created by
+/// [`InventoryCache::tempfile()`]
+fn is_download_tempfile(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(DOWNLOAD_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn add(cache: &InventoryCache, md5_digest: &str, size: usize) {
+        let mut tmp = cache.tempfile().unwrap();
+        tmp.write_all(&vec![b'x'; size]).unwrap();
+        cache.insert(tmp, md5_digest, true).unwrap();
+    }
+
+    /// Make the cached file for `md5_digest` look as though it was last used
+    /// `secs` seconds ago
+    fn age(cache: &InventoryCache, md5_digest: &str, secs: u64) {
+        File::open(cache.path(md5_digest, true))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn is_cached(cache: &InventoryCache, md5_digest: &str) -> bool {
+        cache.path(md5_digest, true).exists()
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().join("cache");
+        let cache = InventoryCache::new(dir.clone(), 250).unwrap();
+        add(&cache, "aaaa", 100);
+        age(&cache, "aaaa", 60);
+        add(&cache, "bbbb", 100);
+        age(&cache, "bbbb", 120);
+        // In a new run, "aaaa" is used and thus becomes more recently used
+        // than "bbbb":
+        let cache = InventoryCache::new(dir, 250).unwrap();
+        let _ = cache.get("aaaa").unwrap();
+        add(&cache, "cccc", 100);
+        assert!(is_cached(&cache, "aaaa"));
+        assert!(!is_cached(&cache, "bbbb"));
+        assert!(is_cached(&cache, "cccc"));
+    }
+
+    #[test]
+    fn keep_new_oversized_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 50).unwrap();
+        add(&cache, "aaaa", 10);
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 50).unwrap();
+        add(&cache, "bbbb", 100);
+        assert!(!is_cached(&cache, "aaaa"));
+        assert!(is_cached(&cache, "bbbb"));
+    }
+
+    #[test]
+    fn keep_files_used_by_run() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 150).unwrap();
+        add(&cache, "aaaa", 100);
+        age(&cache, "aaaa", 60);
+        add(&cache, "bbbb", 100);
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 150).unwrap();
+        let _ = cache.get("bbbb").unwrap();
+        add(&cache, "cccc", 100);
+        assert!(!is_cached(&cache, "aaaa"));
+        assert!(is_cached(&cache, "bbbb"));
+        assert!(is_cached(&cache, "cccc"));
+    }
+
+    #[test]
+    fn count_downloads_toward_limit() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 250).unwrap();
+        add(&cache, "aaaa", 100);
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 250).unwrap();
+        let mut download = cache.tempfile().unwrap();
+        download.write_all(&[b'x'; 100]).unwrap();
+        download.flush().unwrap();
+        add(&cache, "bbbb", 100);
+        assert!(!is_cached(&cache, "aaaa"));
+        assert!(is_cached(&cache, "bbbb"));
+        assert!(download.path().exists());
+    }
+
+    #[test]
+    fn sweep_leftover_downloads() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 1000).unwrap();
+        add(&cache, "aaaa", 100);
+        let (_, leftover) = cache.tempfile().unwrap().keep().unwrap();
+        cache.sweep();
+        assert!(!leftover.exists());
+        assert!(is_cached(&cache, "aaaa"));
+    }
+
+    #[test]
+    fn remember_unsorted() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let cache = InventoryCache::new(tmpdir.path().to_path_buf(), 1000).unwrap();
+        let tmp = cache.tempfile().unwrap();
+        let path = cache.insert(tmp, "aaaa", false).unwrap();
+        assert_eq!(path, tmpdir.path().join("aaaa.unsorted.csv.gz"));
+        let (_, cached_path, sorted) = cache.get("aaaa").unwrap();
+        assert_eq!(cached_path, path);
+        assert!(!sorted);
+    }
+}
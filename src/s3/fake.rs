@@ -0,0 +1,471 @@
+//! A minimal in-process imitation of the S3 REST API for use in tests
+use md5::{Digest, Md5};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use time::format_description::{well_known::Rfc3339, BorrowedFormatItem};
+use time::macros::{datetime, format_description};
+use time::OffsetDateTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A local HTTP server that serves objects stored in memory in response to
+/// path-style S3 requests
+#[derive(Debug)]
+pub(crate) struct FakeS3 {
+    /// The base URL of the server
+    endpoint: String,
+
+    /// The objects being served
+    state: Arc<Mutex<State>>,
+
+    /// The task running the server
+    handle: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The versions of each object, keyed by bucket & key, in order from
+    /// oldest to newest
+    objects: BTreeMap<(String, String), Vec<FakeVersion>>,
+
+    /// The requests received, in the form `"{method} {bucket}/{key}"`, with
+    /// `" (unsigned)"` appended for requests without an `Authorization` header
+    requests: Vec<String>,
+}
+
+/// A version of an object stored in a [`FakeS3`]
+#[derive(Clone, Debug)]
+struct FakeVersion {
+    version_id: String,
+
+    /// The object's content, or `None` if the version is a delete marker
+    body: Option<Vec<u8>>,
+
+    last_modified: OffsetDateTime,
+
+    /// Whether the version is stored in the `GLACIER` storage class
+    archived: bool,
+
+    /// `None` if restoration of the version has not been requested, `Some(true)`
+    /// if it is in progress, and `Some(false)` if it has completed
+    restore: Option<bool>,
+}
+
+impl FakeS3 {
+    /// Start a server on a random local port
+    pub(crate) async fn start() -> FakeS3 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+        let state2 = state.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    return;
+                };
+                tokio::spawn(handle_connection(stream, state2.clone()));
+            }
+        });
+        FakeS3 {
+            endpoint,
+            state,
+            handle,
+        }
+    }
+
+    /// Returns the base URL of the server
+    pub(crate) fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Store a new version of the object at `bucket`/`key`, last modified at
+    /// [`DEFAULT_MODIFIED`], and return its etag
+    pub(crate) fn put(&self, bucket: &str, key: &str, version_id: &str, body: &[u8]) -> String {
+        self.put_at(bucket, key, version_id, body, DEFAULT_MODIFIED)
+    }
+
+    /// Store a new version of the object at `bucket`/`key`, last modified at
+    /// `when`, and return its etag
+    pub(crate) fn put_at(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str,
+        body: &[u8],
+        when: OffsetDateTime,
+    ) -> String {
+        self.push(bucket, key, version_id, Some(body.to_vec()), when);
+        etag(body)
+    }
+
+    /// Add a delete marker, created at `when`, as the new latest version of
+    /// the object at `bucket`/`key`
+    pub(crate) fn delete_at(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str,
+        when: OffsetDateTime,
+    ) {
+        self.push(bucket, key, version_id, None, when);
+    }
+
+    fn push(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str,
+        body: Option<Vec<u8>>,
+        last_modified: OffsetDateTime,
+    ) {
+        self.state
+            .lock()
+            .unwrap()
+            .objects
+            .entry((bucket.to_owned(), key.to_owned()))
+            .or_default()
+            .push(FakeVersion {
+                version_id: version_id.to_owned(),
+                body,
+                last_modified,
+                archived: false,
+                restore: None,
+            });
+    }
+
+    /// Move the given version of the object at `bucket`/`key` to the
+    /// `GLACIER` storage class
+    pub(crate) fn archive(&self, bucket: &str, key: &str, version_id: &str) {
+        self.with_version(bucket, key, version_id, |v| v.archived = true);
+    }
+
+    /// Complete the in-progress restore of the given version of the object
+    /// at `bucket`/`key`
+    pub(crate) fn finish_restore(&self, bucket: &str, key: &str, version_id: &str) {
+        self.with_version(bucket, key, version_id, |v| {
+            assert_eq!(v.restore, Some(true), "restore should be in progress");
+            v.restore = Some(false);
+        });
+    }
+
+    fn with_version<F: FnOnce(&mut FakeVersion)>(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: &str,
+        func: F,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let version = state
+            .objects
+            .get_mut(&(bucket.to_owned(), key.to_owned()))
+            .and_then(|vs| vs.iter_mut().find(|v| v.version_id == version_id))
+            .expect("version should exist");
+        func(version);
+    }
+
+    /// Return the requests received so far, each in the form `"{method}
+    /// {bucket}/{key}"`, with `" (unsigned)"` appended for requests without an
+    /// `Authorization` header
+    pub(crate) fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for FakeS3 {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// The modification time of objects stored with [`FakeS3::put()`]
+pub(crate) const DEFAULT_MODIFIED: OffsetDateTime = datetime!(2025-01-01 00:00 UTC);
+
+/// The format of dates in HTTP headers
+const HTTP_DATE: &[BorrowedFormatItem<'_>] = format_description!(
+    "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+);
+
+/// Returns the etag (MD5 digest in hexadecimal) of an object with the given
+/// content
+pub(crate) fn etag(body: &[u8]) -> String {
+    hex::encode(Md5::digest(body))
+}
+
+/// A request received by a [`FakeS3`]
+struct Request {
+    method: String,
+    bucket: String,
+    key: String,
+    query: Vec<(String, String)>,
+
+    /// Whether the request had an `Authorization` header
+    signed: bool,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find_map(|(k, v)| (k == name).then_some(v.as_str()))
+    }
+}
+
+/// A response to send from a [`FakeS3`]
+struct Response {
+    status: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: &'static str, code: &str) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", "application/xml".into())],
+            body: format!("<Error><Code>{code}</Code><Message>{code}</Message></Error>")
+                .into_bytes(),
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).into_owned();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while data.len() < header_end + content_length {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    }
+    let Some(mut request) = parse_request_line(head.lines().next().unwrap_or_default()) else {
+        return;
+    };
+    request.signed = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .any(|(name, _)| name.eq_ignore_ascii_case("authorization"));
+    let response = respond(&request, &state);
+    let mut out = format!("HTTP/1.1 {}\r\n", response.status);
+    for (name, value) in &response.headers {
+        let _ = write!(out, "{name}: {value}\r\n");
+    }
+    let _ = write!(
+        out,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    );
+    let mut out = out.into_bytes();
+    if request.method != "HEAD" {
+        out.extend_from_slice(&response.body);
+    }
+    let _ = stream.write_all(&out).await;
+    let _ = stream.shutdown().await;
+}
+
+fn parse_request_line(line: &str) -> Option<Request> {
+    let mut words = line.split(' ');
+    let method = words.next()?.to_owned();
+    let target = words.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = decode(path.strip_prefix('/')?);
+    let (bucket, key) = path.split_once('/').unwrap_or((&path, ""));
+    let query = query
+        .split('&')
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let (k, v) = s.split_once('=').unwrap_or((s, ""));
+            (decode(k), decode(v))
+        })
+        .filter(|(k, _)| k != "x-id")
+        .collect();
+    Some(Request {
+        method,
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        query,
+        signed: false,
+    })
+}
+
+fn decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn respond(request: &Request, state: &Mutex<State>) -> Response {
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!(
+        "{} {}/{}{}",
+        request.method,
+        request.bucket,
+        request.key,
+        if request.signed { "" } else { " (unsigned)" }
+    ));
+    match request.method.as_str() {
+        "GET" | "HEAD" if !request.key.is_empty() => {
+            let version = match find_version(&mut state, request) {
+                Ok(v) => v,
+                Err(r) => return r,
+            };
+            let Some(ref body) = version.body else {
+                return if request.param("versionId").is_some() {
+                    Response::error("405 Method Not Allowed", "MethodNotAllowed")
+                } else {
+                    Response::error("404 Not Found", "NoSuchKey")
+                };
+            };
+            let mut headers = vec![
+                ("ETag", format!("\"{}\"", etag(body))),
+                ("x-amz-version-id", version.version_id.clone()),
+                (
+                    "Last-Modified",
+                    version.last_modified.format(HTTP_DATE).unwrap(),
+                ),
+            ];
+            if version.archived {
+                if request.method == "GET" && version.restore != Some(false) {
+                    return Response::error("403 Forbidden", "InvalidObjectState");
+                }
+                headers.push(("x-amz-storage-class", "GLACIER".into()));
+                let restore = match version.restore {
+                    Some(true) => Some(r#"ongoing-request="true""#),
+                    Some(false) => Some(
+                        r#"ongoing-request="false", expiry-date="Fri, 01 Jan 2100 00:00:00 GMT""#,
+                    ),
+                    None => None,
+                };
+                if let Some(restore) = restore {
+                    headers.push(("x-amz-restore", restore.into()));
+                }
+            }
+            Response {
+                status: "200 OK",
+                headers,
+                body: body.clone(),
+            }
+        }
+        "POST" if request.param("restore").is_some() => {
+            let version = match find_version(&mut state, request) {
+                Ok(v) => v,
+                Err(r) => return r,
+            };
+            if version.body.is_none() {
+                return Response::error("405 Method Not Allowed", "MethodNotAllowed");
+            } else if !version.archived {
+                return Response::error("403 Forbidden", "InvalidObjectState");
+            }
+            match version.restore {
+                None => {
+                    version.restore = Some(true);
+                    Response {
+                        status: "202 Accepted",
+                        headers: Vec::new(),
+                        body: Vec::new(),
+                    }
+                }
+                Some(true) => Response::error("409 Conflict", "RestoreAlreadyInProgress"),
+                Some(false) => Response {
+                    status: "200 OK",
+                    headers: Vec::new(),
+                    body: Vec::new(),
+                },
+            }
+        }
+        "GET" if request.param("versions").is_some() => list_versions(
+            &state,
+            &request.bucket,
+            request.param("prefix").unwrap_or(""),
+        ),
+        _ => Response::error("501 Not Implemented", "NotImplemented"),
+    }
+}
+
+/// Look up the object version targeted by `request`, returning an error
+/// response if it does not exist
+fn find_version<'a>(
+    state: &'a mut State,
+    request: &Request,
+) -> Result<&'a mut FakeVersion, Response> {
+    let Some(versions) = state
+        .objects
+        .get_mut(&(request.bucket.clone(), request.key.clone()))
+    else {
+        return Err(Response::error("404 Not Found", "NoSuchKey"));
+    };
+    let version = match request.param("versionId") {
+        Some(vid) => versions.iter_mut().find(|v| v.version_id == vid),
+        None => versions.last_mut(),
+    };
+    version.ok_or_else(|| Response::error("404 Not Found", "NoSuchVersion"))
+}
+
+/// Respond to a "List Object Versions" request.  All versions are returned in
+/// a single page, with object versions and delete markers listed separately,
+/// as S3 does.
+fn list_versions(state: &State, bucket: &str, prefix: &str) -> Response {
+    let mut versions = String::new();
+    let mut markers = String::new();
+    for ((b, key), vs) in &state.objects {
+        if b != bucket || !key.starts_with(prefix) {
+            continue;
+        }
+        for (i, v) in vs.iter().enumerate().rev() {
+            let common = format!(
+                "<Key>{}</Key><VersionId>{}</VersionId><IsLatest>{}</IsLatest><LastModified>{}</LastModified>",
+                xml_escape(key),
+                xml_escape(&v.version_id),
+                i + 1 == vs.len(),
+                v.last_modified.format(&Rfc3339).unwrap(),
+            );
+            match v.body {
+                Some(ref body) => {
+                    let _ = write!(
+                        versions,
+                        "<Version>{common}<ETag>&quot;{}&quot;</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Version>",
+                        etag(body),
+                        body.len(),
+                    );
+                }
+                None => {
+                    let _ = write!(markers, "<DeleteMarker>{common}</DeleteMarker>");
+                }
+            }
+        }
+    }
+    Response {
+        status: "200 OK",
+        headers: vec![("Content-Type", "application/xml".into())],
+        body: format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<ListVersionsResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\"><Name>{}</Name><Prefix>{}</Prefix><IsTruncated>false</IsTruncated>{versions}{markers}</ListVersionsResult>",
+            xml_escape(bucket),
+            xml_escape(prefix),
+        )
+        .into_bytes(),
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,360 @@
+//! Downloading S3 objects via plain HTTP(S) requests
+use super::{DownloadError, DownloadSink, S3Location};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, StatusCode};
+use std::fs::File;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Characters to percent-encode in object keys when constructing URLs
+const KEY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Characters to percent-encode in query parameter values
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Maximum number of times to attempt a request that fails with a transient
+/// error
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Maximum delay between attempts at a request
+const MAX_BACKOFF: Duration = Duration::from_secs(20);
+
+/// Client for downloading S3 objects via plain HTTP(S) GET requests, e.g.,
+/// through a CDN or caching proxy in front of a public bucket
+#[derive(Debug)]
+pub(crate) struct HttpDownloader {
+    /// The inner HTTP client
+    inner: Client,
+
+    /// The base URL to which object keys are appended
+    base_url: HttpBaseUrl,
+
+    /// Whether to emit TRACE messages for download progress
+    trace_progress: bool,
+}
+
+impl HttpDownloader {
+    pub(crate) fn new(
+        base_url: HttpBaseUrl,
+        trace_progress: bool,
+    ) -> Result<HttpDownloader, HttpClientBuildError> {
+        let inner = Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build()
+            .map_err(HttpClientBuildError)?;
+        Ok(HttpDownloader {
+            inner,
+            base_url,
+            trace_progress,
+        })
+    }
+
+    /// Return the HTTP(S) URL at which the object at `url` can be downloaded
+    fn object_url(&self, url: &S3Location) -> String {
+        let mut s = format!(
+            "{}/{}",
+            self.base_url.0.replace("{bucket}", url.bucket()),
+            utf8_percent_encode(url.key(), KEY_ENCODE_SET)
+        );
+        if let Some(v) = url.version_id() {
+            s.push_str("?versionId=");
+            s.extend(utf8_percent_encode(v, QUERY_ENCODE_SET));
+        }
+        s
+    }
+
+    /// Perform a GET request for `http_url`, retrying on connection errors,
+    /// timeouts, server-side errors, and 429 responses
+    async fn get(&self, http_url: &str) -> Result<reqwest::Response, HttpError> {
+        let mut attempt = 1;
+        loop {
+            let r = self.inner.get(http_url).send().await;
+            let retryable = match r {
+                Ok(ref resp) => {
+                    resp.status().is_server_error()
+                        || resp.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(ref e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retryable || attempt >= MAX_ATTEMPTS {
+                let resp = r.map_err(|source| HttpError::Send {
+                    url: http_url.to_owned(),
+                    source,
+                })?;
+                let status = resp.status();
+                if status.is_success() {
+                    return Ok(resp);
+                }
+                // If reading the error body fails, we still want to report the
+                // status.
+                let body = resp.text().await.unwrap_or_default();
+                return Err(HttpError::Status {
+                    url: http_url.to_owned(),
+                    status,
+                    code: extract_error_code(&body),
+                });
+            }
+            let delay = Duration::from_millis(100 << attempt.min(10)).min(MAX_BACKOFF);
+            tracing::debug!(
+                url = http_url,
+                attempt,
+                ?delay,
+                "Transient error performing HTTP request; retrying"
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Download the object at `url` via plain HTTP(S) and write its bytes to
+    /// `outfile`.  If `md5_digest` is non-`None` (in which case it must be a
+    /// 32-character lowercase hexadecimal string), it is used to validate the
+    /// download.
+    #[tracing::instrument(skip_all, fields(url = %url))]
+    pub(crate) async fn download_object(
+        &self,
+        url: &S3Location,
+        md5_digest: Option<&str>,
+        outfile: &File,
+    ) -> Result<(), DownloadError> {
+        let http_url = self.object_url(url);
+        tracing::debug!(http_url, "Downloading object to disk via HTTP");
+        let mut resp = self.get(&http_url).await?;
+        let object_size = resp.content_length().and_then(|sz| i64::try_from(sz).ok());
+        let mut sink = DownloadSink::new(url, outfile, object_size, self.trace_progress);
+        while let Some(blob) = resp
+            .chunk()
+            .await
+            .map_err(|source| DownloadError::HttpDownload {
+                url: url.to_owned(),
+                source,
+            })?
+        {
+            sink.write(&blob)?;
+        }
+        sink.finish(md5_digest)?;
+        tracing::debug!("Finished download");
+        Ok(())
+    }
+}
+
+/// Extract the error code from an S3-style XML error response body
+fn extract_error_code(body: &str) -> Option<String> {
+    let (_, after) = body.split_once("<Code>")?;
+    let (code, _) = after.split_once("</Code>")?;
+    Some(code.trim().to_owned())
+}
+
+/// A base URL for downloading objects via plain HTTP(S).  Any occurrences of
+/// `{bucket}` are replaced with the name of the bucket of the object being
+/// downloaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct HttpBaseUrl(String);
+
+impl std::str::FromStr for HttpBaseUrl {
+    type Err = ParseHttpBaseUrlError;
+
+    fn from_str(s: &str) -> Result<HttpBaseUrl, ParseHttpBaseUrlError> {
+        let lower = s.to_ascii_lowercase();
+        if !(lower.starts_with("http://") || lower.starts_with("https://")) {
+            return Err(ParseHttpBaseUrlError::BadScheme);
+        }
+        if reqwest::Url::parse(&s.replace("{bucket}", "bucket")).is_err() {
+            return Err(ParseHttpBaseUrlError::Invalid);
+        }
+        Ok(HttpBaseUrl(s.trim_end_matches('/').to_owned()))
+    }
+}
+
+/// Error returned when parsing an invalid [`HttpBaseUrl`]
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub(crate) enum ParseHttpBaseUrlError {
+    /// The URL does not use the `http` or `https` scheme
+    #[error(r#"URL does not start with "http://" or "https://""#)]
+    BadScheme,
+
+    /// The URL could not be parsed
+    #[error("invalid URL")]
+    Invalid,
+}
+
+/// Error returned by [`HttpDownloader::new()`]
+#[derive(Debug, Error)]
+#[error("failed to initialize HTTP client")]
+pub(crate) struct HttpClientBuildError(#[source] reqwest::Error);
+
+/// Error returned when an HTTP(S) GET request for an object fails
+#[derive(Debug, Error)]
+pub(crate) enum HttpError {
+    /// The request could not be sent or no response was received
+    #[error("request to {url} failed")]
+    Send { url: String, source: reqwest::Error },
+
+    /// The server returned a non-success response
+    #[error("request to {url} returned {status}{}", code.as_ref().map(|c| format!(" ({c})")).unwrap_or_default())]
+    Status {
+        url: String,
+        status: StatusCode,
+        /// The S3 error code from the response body, if any
+        code: Option<String>,
+    },
+}
+
+impl HttpError {
+    fn status(&self) -> Option<StatusCode> {
+        match self {
+            HttpError::Send { .. } => None,
+            HttpError::Status { status, .. } => Some(*status),
+        }
+    }
+
+    pub(crate) fn is_403(&self) -> bool {
+        self.status() == Some(StatusCode::FORBIDDEN)
+    }
+
+    pub(crate) fn is_404(&self) -> bool {
+        self.status() == Some(StatusCode::NOT_FOUND)
+    }
+
+    pub(crate) fn is_invalid_object_state(&self) -> bool {
+        matches!(self, HttpError::Status { code: Some(code), .. } if code == "InvalidObjectState")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve a single HTTP response on a local port.  Returns the base URL of
+    /// the server and a handle resolving to the request line received.
+    async fn serve_once(
+        status: &str,
+        body: &'static str,
+    ) -> (HttpBaseUrl, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = status.to_owned();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before end of request headers");
+                request.extend_from_slice(&buf[..n]);
+            }
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let request = String::from_utf8(request).unwrap();
+            request.lines().next().unwrap().to_owned()
+        });
+        let base_url = format!("http://{addr}/{{bucket}}").parse().unwrap();
+        (base_url, handle)
+    }
+
+    fn location() -> S3Location {
+        S3Location::new("pail".into(), "dir/hello world.txt".into()).with_version_id("v1+2")
+    }
+
+    #[tokio::test]
+    async fn download() {
+        let (base_url, handle) = serve_once("200 OK", "Hello, world!\n").await;
+        let client = HttpDownloader::new(base_url, false).unwrap();
+        let mut outfile = tempfile::tempfile().unwrap();
+        client
+            .download_object(
+                &location(),
+                Some("746308829575e17c3331bbcb00c0898b"),
+                &outfile,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            handle.await.unwrap(),
+            "GET /pail/dir/hello%20world.txt?versionId=v1%2B2 HTTP/1.1"
+        );
+        outfile.rewind().unwrap();
+        let mut content = String::new();
+        outfile.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "Hello, world!\n");
+    }
+
+    #[tokio::test]
+    async fn download_bad_md5() {
+        let (base_url, _) = serve_once("200 OK", "Goodbye, world!\n").await;
+        let client = HttpDownloader::new(base_url, false).unwrap();
+        let outfile = tempfile::tempfile().unwrap();
+        let r = client
+            .download_object(
+                &location(),
+                Some("746308829575e17c3331bbcb00c0898b"),
+                &outfile,
+            )
+            .await;
+        assert!(matches!(r, Err(DownloadError::Md5 { .. })));
+    }
+
+    #[tokio::test]
+    async fn download_404() {
+        let (base_url, _) =
+            serve_once("404 Not Found", "<Error><Code>NoSuchVersion</Code></Error>").await;
+        let client = HttpDownloader::new(base_url, false).unwrap();
+        let outfile = tempfile::tempfile().unwrap();
+        let e = client
+            .download_object(&location(), None, &outfile)
+            .await
+            .unwrap_err();
+        assert!(e.is_404());
+        assert!(!e.is_403());
+        assert!(!e.is_invalid_object_state());
+    }
+
+    #[tokio::test]
+    async fn download_archived() {
+        let (base_url, _) = serve_once(
+            "403 Forbidden",
+            "<Error><Code>InvalidObjectState</Code><Message>The operation is not valid for the object's storage class</Message></Error>",
+        )
+        .await;
+        let client = HttpDownloader::new(base_url, false).unwrap();
+        let outfile = tempfile::tempfile().unwrap();
+        let e = client
+            .download_object(&location(), None, &outfile)
+            .await
+            .unwrap_err();
+        assert!(e.is_403());
+        assert!(e.is_invalid_object_state());
+    }
+
+    #[test]
+    fn parse_base_url() {
+        assert_eq!(
+            "https://cdn.example.com/data/".parse::<HttpBaseUrl>(),
+            Ok(HttpBaseUrl("https://cdn.example.com/data".into()))
+        );
+        assert_eq!(
+            "s3://pail/".parse::<HttpBaseUrl>(),
+            Err(ParseHttpBaseUrlError::BadScheme)
+        );
+    }
+}
@@ -63,6 +63,16 @@ impl S3Location {
         }
     }
 
+    /// Return a new `S3Location` with the same key & version ID and using the
+    /// given bucket.
+    pub(crate) fn with_bucket<S: Into<String>>(&self, bucket: S) -> S3Location {
+        S3Location {
+            bucket: bucket.into(),
+            key: self.key.clone(),
+            version_id: self.version_id.clone(),
+        }
+    }
+
     /// Return a new `S3Location` with the same bucket & key and using the
     /// given version ID.
     pub(crate) fn with_version_id<S: Into<String>>(&self, version_id: S) -> S3Location {
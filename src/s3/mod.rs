@@ -1,19 +1,34 @@
 //! Working directly with AWS S3
+mod cache;
+#[cfg(test)]
+pub(crate) mod fake;
+mod http;
 mod location;
+mod restore;
 mod streams;
+pub(crate) use self::cache::InventoryCache;
+pub(crate) use self::http::{HttpBaseUrl, HttpDownloader, HttpError};
 pub(crate) use self::location::S3Location;
-use self::streams::{ListManifestDates, ListObjectsError};
-use crate::consts::CSV_GZIP_PEEK_SIZE;
-use crate::inventory::{CsvReader, CsvReaderError, InventoryEntry, InventoryList};
+pub(crate) use self::restore::{RestoreStatus, RestoreTier};
+pub(crate) use self::streams::ListVersionsError;
+use self::streams::{ListManifestDates, ListObjectVersions, ListObjectsError};
+use crate::consts::{CSV_GZIP_PEEK_SIZE, RUN_TMPDIR_PREFIX};
+use crate::inventory::{CsvReader, CsvReaderError, InventoryEntry, InventoryList, SortCheck};
 use crate::manifest::{CsvManifest, FileSpec};
+use crate::metrics::{Metrics, RetryCounter};
 use crate::timestamps::{Date, DateHM, DateMaybeHM};
 use aws_credential_types::{
     provider::{error::CredentialsError, ProvideCredentials},
     Credentials,
 };
 use aws_sdk_s3::{
-    operation::get_object::{GetObjectError, GetObjectOutput},
+    operation::{
+        get_object::{GetObjectError, GetObjectOutput},
+        head_object::HeadObjectError,
+        restore_object::RestoreObjectError,
+    },
     primitives::ByteStreamError,
+    types::{ArchiveStatus, GlacierJobParameters, RestoreRequest, StorageClass},
     Client,
 };
 use aws_smithy_runtime_api::client::{orchestrator::HttpResponse, result::SdkError};
@@ -22,6 +37,8 @@ use md5::{Digest, Md5};
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 
 /// Client for interacting with S3
@@ -37,8 +54,15 @@ pub(crate) struct S3Client {
     /// Whether to emit TRACE messages for download progress
     trace_progress: bool,
 
-    /// A temporary directory in which to download temporary files
+    /// A temporary directory in which to download temporary files.  This is
+    /// created inside the directory given by `--tmpdir` and deleted on drop.
     tmpdir: tempfile::TempDir,
+
+    /// If set, inventory list files are cached in this directory across runs
+    inventory_cache: Option<InventoryCache>,
+
+    /// Metrics in which to record request latencies & retries
+    metrics: Arc<Metrics>,
 }
 
 impl S3Client {
@@ -46,8 +70,15 @@ impl S3Client {
         region: String,
         inventory_base: S3Location,
         trace_progress: bool,
+        inventory_cache: Option<InventoryCache>,
+        tmpdir_base: &Path,
+        metrics: Arc<Metrics>,
     ) -> Result<S3Client, ClientBuildError> {
-        let tmpdir = tempfile::tempdir().map_err(ClientBuildError::Tempdir)?;
+        fs_err::create_dir_all(tmpdir_base).map_err(ClientBuildError::Tempdir)?;
+        let tmpdir = tempfile::Builder::new()
+            .prefix(RUN_TMPDIR_PREFIX)
+            .tempdir_in(tmpdir_base)
+            .map_err(ClientBuildError::Tempdir)?;
         let mut config = aws_config::from_env()
             .app_name(
                 aws_config::AppName::new(env!("CARGO_PKG_NAME"))
@@ -59,15 +90,49 @@ impl S3Client {
             Some(creds) => config.credentials_provider(creds),
             None => config.no_credentials(),
         };
-        let inner = Client::new(&config.load().await);
+        let s3_config = aws_sdk_s3::config::Builder::from(&config.load().await)
+            .interceptor(RetryCounter(metrics.clone()))
+            .build();
+        let inner = Client::from_conf(s3_config);
         Ok(S3Client {
             inner,
             inventory_base,
             trace_progress,
             tmpdir,
+            inventory_cache,
+            metrics,
         })
     }
 
+    /// Construct a client that sends all requests to the given [`FakeS3`]
+    /// server
+    #[cfg(test)]
+    pub(crate) fn for_fake(
+        server: &fake::FakeS3,
+        inventory_base: S3Location,
+        inventory_cache: Option<InventoryCache>,
+        tmpdir_base: &Path,
+    ) -> S3Client {
+        let s3_config = aws_sdk_s3::config::Builder::new()
+            .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+            .region(aws_sdk_s3::config::Region::new("us-east-1"))
+            .endpoint_url(server.endpoint())
+            .force_path_style(true)
+            .credentials_provider(Credentials::for_tests())
+            .build();
+        S3Client {
+            inner: Client::from_conf(s3_config),
+            inventory_base,
+            trace_progress: false,
+            tmpdir: tempfile::Builder::new()
+                .prefix(RUN_TMPDIR_PREFIX)
+                .tempdir_in(tmpdir_base)
+                .unwrap(),
+            inventory_cache,
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
     /// Create a temporary file at `subpath` within the temporary directory for
     /// downloading `objloc` to.  Returns a filehandle opened for reading &
     /// writing and the full path to the file.
@@ -125,6 +190,12 @@ impl S3Client {
         ListManifestDates::new(self, &self.inventory_base)
     }
 
+    /// Returns a stream yielding all versions & delete markers of the objects
+    /// in `bucket` whose keys start with `prefix`
+    pub(crate) fn list_object_versions(&self, bucket: &str, prefix: &str) -> ListObjectVersions {
+        ListObjectVersions::new(self, &S3Location::new(bucket.to_owned(), prefix.to_owned()))
+    }
+
     /// Return the full timestamp for the latest manifest, either (if `day` is
     /// `None`) out of all manifests or else the latest on the given date.
     #[tracing::instrument(skip_all, fields(day = day.map(|d| d.to_string())))]
@@ -159,12 +230,116 @@ impl S3Client {
         if let Some(v) = url.version_id() {
             op = op.version_id(v);
         }
-        op.send().await.map_err(|source| GetError {
+        let start = Instant::now();
+        let r = op.send().await;
+        self.metrics.get_object_duration.observe(start.elapsed());
+        r.map_err(|source| GetError {
+            url: url.to_owned(),
+            source,
+        })
+    }
+
+    /// Perform a "Get Object" request for the first `size` bytes of the object
+    /// at `url`.  If the object is shorter than `size` bytes, the entire
+    /// object is returned.
+    async fn get_object_prefix(
+        &self,
+        url: &S3Location,
+        size: usize,
+    ) -> Result<GetObjectOutput, GetError> {
+        let mut op = self
+            .inner
+            .get_object()
+            .bucket(url.bucket())
+            .key(url.key())
+            .range(format!("bytes=0-{}", size.saturating_sub(1)));
+        if let Some(v) = url.version_id() {
+            op = op.version_id(v);
+        }
+        let start = Instant::now();
+        let r = op.send().await;
+        self.metrics.get_object_duration.observe(start.elapsed());
+        r.map_err(|source| GetError {
             url: url.to_owned(),
             source,
         })
     }
 
+    /// Perform a "Head Object" request for the object at `url` and return the
+    /// object's restoration status
+    #[tracing::instrument(skip_all, fields(url = %url))]
+    pub(crate) async fn get_restore_status(
+        &self,
+        url: &S3Location,
+    ) -> Result<RestoreStatus, HeadError> {
+        tracing::debug!("Checking restoration status of object");
+        let mut op = self.inner.head_object().bucket(url.bucket()).key(url.key());
+        if let Some(v) = url.version_id() {
+            op = op.version_id(v);
+        }
+        let output = op.send().await.map_err(|source| HeadError {
+            url: url.to_owned(),
+            source,
+        })?;
+        Ok(RestoreStatus::from_headers(
+            output.storage_class().map(StorageClass::as_str),
+            output.archive_status().map(ArchiveStatus::as_str),
+            output.restore(),
+        ))
+    }
+
+    /// Request that the archived object at `url` be restored using the given
+    /// retrieval tier.  If `days` is non-`None`, the restored copy will be
+    /// available for that many days; it must be `None` when restoring objects
+    /// from Intelligent-Tiering archive access tiers.
+    ///
+    /// Returns the object's restoration status after making the request.
+    #[tracing::instrument(skip_all, fields(url = %url))]
+    pub(crate) async fn restore_object(
+        &self,
+        url: &S3Location,
+        tier: RestoreTier,
+        days: Option<i32>,
+    ) -> Result<RestoreStatus, RestoreError> {
+        tracing::debug!(?tier, days, "Requesting restoration of object");
+        let params = GlacierJobParameters::builder()
+            .tier(tier.into())
+            .build()
+            .expect("tier should be set on GlacierJobParameters");
+        let request = RestoreRequest::builder()
+            .set_days(days)
+            .glacier_job_parameters(params)
+            .build();
+        let mut op = self
+            .inner
+            .restore_object()
+            .bucket(url.bucket())
+            .key(url.key())
+            .restore_request(request);
+        if let Some(v) = url.version_id() {
+            op = op.version_id(v);
+        }
+        match op.send().await {
+            Ok(_) => Ok(RestoreStatus::InProgress),
+            Err(SdkError::ServiceError(e)) if e.raw().status().as_u16() == 409 => {
+                // RestoreAlreadyInProgress
+                Ok(RestoreStatus::InProgress)
+            }
+            Err(SdkError::ServiceError(e))
+                if matches!(
+                    e.err(),
+                    RestoreObjectError::ObjectAlreadyInActiveTierError(_)
+                ) =>
+            {
+                Ok(RestoreStatus::Available)
+            }
+            Err(source) => Err(RestoreError {
+                url: url.to_owned(),
+                source,
+            }),
+        }
+    }
+
     /// Download, parse, & return the manifest file for the inventory created
     /// at the timestamp `when`.
     ///
@@ -216,82 +391,154 @@ impl S3Client {
     }
 
     /// Download the CSV inventory list file described by `fspec` to a
-    /// temporary location and return a filehandle for iterating over its
-    /// entries
+    /// temporary location and return a handle for iterating over its entries.
+    /// While the file is downloaded, its entries are checked for whether
+    /// they're sorted by key.
+    ///
+    /// If an inventory cache is configured, the file is read from the cache if
+    /// present there; otherwise, it is downloaded into the cache.
     #[tracing::instrument(skip_all, fields(key = fspec.key))]
     pub(crate) async fn download_inventory_csv(
         &self,
         fspec: FileSpec,
     ) -> Result<InventoryList, CsvDownloadError> {
+        let url = self.inventory_base.with_key(&fspec.key);
+        if let Some(ref cache) = self.inventory_cache {
+            let (path, sorted) = if let Some((_, path, sorted)) = cache.get(&fspec.md5_checksum) {
+                (path, sorted)
+            } else {
+                let tmpfile = cache.tempfile().map_err(|source| TempfileError::Open {
+                    url: url.clone(),
+                    source,
+                })?;
+                let mut check = SortCheck::new(fspec.file_schema.clone());
+                self.download_object_with(
+                    &url,
+                    Some(&fspec.md5_checksum),
+                    tmpfile.as_file(),
+                    |blob| check.feed(blob),
+                )
+                .await?;
+                let sorted = check.finish();
+                let path =
+                    cache
+                        .insert(tmpfile, &fspec.md5_checksum, sorted)
+                        .map_err(|source| CsvDownloadError::Cache {
+                            url: url.clone(),
+                            source,
+                        })?;
+                (path, sorted)
+            };
+            return Ok(InventoryList::for_cached_csv(
+                path,
+                url,
+                fspec.file_schema,
+                sorted,
+            ));
+        }
         let fname = fspec
             .key
             .rsplit_once('/')
             .map_or(&*fspec.key, |(_, after)| after);
-        let url = self.inventory_base.with_key(&fspec.key);
-        let (mut outfile, path) =
+        let (outfile, path) =
             self.make_dl_tempfile(&PathBuf::from(format!("data/{fname}")), &url)?;
-        self.download_object(&url, Some(&fspec.md5_checksum), &outfile)
-            .await?;
-        outfile
-            .rewind()
-            .map_err(|source| CsvDownloadError::Rewind {
-                url: url.clone(),
-                source,
-            })?;
-        let reader = CsvReader::from_gzipped_reader(BufReader::new(outfile), fspec.file_schema);
-        Ok(InventoryList::for_downloaded_csv(path, url, reader))
+        let mut check = SortCheck::new(fspec.file_schema.clone());
+        self.download_object_with(&url, Some(&fspec.md5_checksum), &outfile, |blob| {
+            check.feed(blob);
+        })
+        .await?;
+        Ok(InventoryList::for_downloaded_csv(
+            path,
+            url,
+            fspec.file_schema,
+            check.finish(),
+        ))
     }
 
-    /// Fetch the first [`CSV_GZIP_PEEK_SIZE`] bytes of the CSV inventory list
-    /// file described by `fspec` and extract the first line.  Returns `None`
-    /// if the file is empty.
+    /// Fetch the beginning of the CSV inventory list file described by `fspec`
+    /// and extract the first line.  Returns `None` if the file is empty.
+    ///
+    /// If the file is present in the inventory cache, it is read from there.
+    /// Otherwise, the file is fetched via ranged "Get Object" requests, starting with the
+    /// first [`CSV_GZIP_PEEK_SIZE`] bytes.  If the bytes fetched do not contain
+    /// a complete first line, the size of the range is doubled and the request
+    /// retried until either a complete line is decoded or the entire file has
+    /// been fetched.
     #[tracing::instrument(skip_all, fields(key = fspec.key))]
     pub(crate) async fn peek_inventory_csv(
         &self,
         fspec: &FileSpec,
     ) -> Result<Option<InventoryEntry>, CsvPeekError> {
-        tracing::debug!("Peeking at first {CSV_GZIP_PEEK_SIZE} bytes of file");
         let url = self.inventory_base.with_key(&fspec.key);
-        let obj = self.get_object(&url).await?;
-        let mut bytestream = obj.body;
-        let mut header = std::collections::VecDeque::with_capacity(CSV_GZIP_PEEK_SIZE);
-        while let Some(blob) =
-            bytestream
-                .try_next()
+        if let Some((fp, _, _)) = self
+            .inventory_cache
+            .as_ref()
+            .and_then(|cache| cache.get(&fspec.md5_checksum))
+        {
+            return CsvReader::from_gzipped_reader(BufReader::new(fp), fspec.file_schema.clone())
+                .next()
+                .transpose()
+                .map_err(|source| CsvPeekError::Decode { url, source });
+        }
+        let mut size = CSV_GZIP_PEEK_SIZE;
+        loop {
+            tracing::debug!("Peeking at first {size} bytes of file");
+            let obj = match self.get_object_prefix(&url, size).await {
+                Ok(obj) => obj,
+                // S3 returns "416 Range Not Satisfiable" for ranged requests
+                // for empty objects
+                Err(e) if e.status_code() == Some(416) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let header = obj
+                .body
+                .collect()
                 .await
                 .map_err(|source| CsvPeekError::Download {
                     url: url.clone(),
                     source,
                 })?
-        {
-            header.extend(blob);
-            if header.len() >= CSV_GZIP_PEEK_SIZE {
-                break;
+                .into_bytes();
+            let complete = header.len() < size;
+            match CsvReader::from_gzipped_reader(&header[..], fspec.file_schema.clone())
+                .next()
+                .transpose()
+            {
+                // Decoding a truncated gzip stream results in an I/O error
+                // before the end of the first line is reached
+                Err(CsvReaderError::Csv(_)) if !complete => size = size.saturating_mul(2),
+                r => return r.map_err(|source| CsvPeekError::Decode { url, source }),
             }
         }
-        CsvReader::from_gzipped_reader(header, fspec.file_schema.clone())
-            .next()
-            .transpose()
-            .map_err(|source| CsvPeekError::Decode { url, source })
     }
 
     /// Download the object at `url` and write its bytes to `outfile`.  If
     /// `md5_digest` is non-`None` (in which case it must be a 32-character
     /// lowercase hexadecimal string), it is used to validate the download.
-    #[tracing::instrument(skip_all, fields(url = %url))]
     pub(crate) async fn download_object(
         &self,
         url: &S3Location,
         md5_digest: Option<&str>,
         outfile: &File,
+    ) -> Result<(), DownloadError> {
+        self.download_object_with(url, md5_digest, outfile, |_| ())
+            .await
+    }
+
+    /// Like [`S3Client::download_object()`], but each chunk of bytes received
+    /// is additionally passed to `inspect`
+    #[tracing::instrument(skip_all, fields(url = %url))]
+    async fn download_object_with<F: FnMut(&[u8])>(
+        &self,
+        url: &S3Location,
+        md5_digest: Option<&str>,
+        outfile: &File,
+        mut inspect: F,
     ) -> Result<(), DownloadError> {
         tracing::debug!("Downloading object to disk");
         let obj = self.get_object(url).await?;
-        let mut total_received = 0;
-        let object_size = obj.content_length;
+        let mut sink = DownloadSink::new(url, outfile, obj.content_length, self.trace_progress);
         let mut bytestream = obj.body;
-        let mut outfile = BufWriter::new(outfile);
-        let mut hasher = Md5::new();
         while let Some(blob) =
             bytestream
                 .try_next()
@@ -301,38 +548,104 @@ impl S3Client {
                     source,
                 })?
         {
-            total_received += blob.len();
-            if self.trace_progress {
-                tracing::trace!(
-                    chunk_size = blob.len(),
-                    total_received,
-                    object_size,
-                    "Received chunk"
-                );
+            sink.write(&blob)?;
+            inspect(&blob);
+        }
+        sink.finish(md5_digest)?;
+        tracing::debug!("Finished download");
+        Ok(())
+    }
+}
+
+/// A destination for the bytes of an object being downloaded that keeps track
+/// of the object's size & MD5 digest
+struct DownloadSink<'a> {
+    /// The location of the object being downloaded
+    url: &'a S3Location,
+
+    /// The file to which the object is being written
+    outfile: BufWriter<&'a File>,
+
+    /// Hasher for computing the MD5 digest of the object
+    hasher: Md5,
+
+    /// The number of bytes received so far
+    total_received: usize,
+
+    /// The size of the object as reported by the server, if known
+    object_size: Option<i64>,
+
+    /// Whether to emit TRACE messages for download progress
+    trace_progress: bool,
+}
+
+impl<'a> DownloadSink<'a> {
+    fn new(
+        url: &'a S3Location,
+        outfile: &'a File,
+        object_size: Option<i64>,
+        trace_progress: bool,
+    ) -> Self {
+        DownloadSink {
+            url,
+            outfile: BufWriter::new(outfile),
+            hasher: Md5::new(),
+            total_received: 0,
+            object_size,
+            trace_progress,
+        }
+    }
+
+    /// Write a chunk of the object to disk
+    fn write(&mut self, blob: &[u8]) -> Result<(), DownloadError> {
+        self.total_received += blob.len();
+        if self.trace_progress {
+            tracing::trace!(
+                chunk_size = blob.len(),
+                total_received = self.total_received,
+                object_size = self.object_size,
+                "Received chunk"
+            );
+        }
+        self.outfile
+            .write_all(blob)
+            .map_err(|source| DownloadError::Write {
+                url: self.url.to_owned(),
+                source,
+            })?;
+        self.hasher.update(blob);
+        Ok(())
+    }
+
+    /// Flush the output file and verify the size of the object and — if
+    /// `md5_digest` is non-`None` (in which case it must be a 32-character
+    /// lowercase hexadecimal string) — its MD5 digest
+    fn finish(mut self, md5_digest: Option<&str>) -> Result<(), DownloadError> {
+        self.outfile
+            .flush()
+            .map_err(|source| DownloadError::Write {
+                url: self.url.to_owned(),
+                source,
+            })?;
+        if let Some(expected_size) = self.object_size {
+            if i64::try_from(self.total_received).ok() != Some(expected_size) {
+                return Err(DownloadError::Size {
+                    url: self.url.to_owned(),
+                    expected_size,
+                    actual_size: self.total_received,
+                });
             }
-            outfile
-                .write(&blob)
-                .map_err(|source| DownloadError::Write {
-                    url: url.to_owned(),
-                    source,
-                })?;
-            hasher.update(&blob);
         }
-        outfile.flush().map_err(|source| DownloadError::Write {
-            url: url.to_owned(),
-            source,
-        })?;
-        let actual_md5 = hex::encode(hasher.finalize());
+        let actual_md5 = hex::encode(self.hasher.finalize());
         if let Some(expected_md5) = md5_digest {
             if actual_md5 != expected_md5 {
                 return Err(DownloadError::Md5 {
-                    url: url.to_owned(),
+                    url: self.url.to_owned(),
                     expected_md5: expected_md5.to_owned(),
                     actual_md5,
                 });
             }
         }
-        tracing::debug!("Finished download");
         Ok(())
     }
 }
@@ -454,6 +767,17 @@ pub(crate) enum DownloadError {
         source: ByteStreamError,
     },
 
+    /// Failed to perform plain HTTP(S) GET request
+    #[error(transparent)]
+    Http(Box<HttpError>),
+
+    /// Error while receiving bytes for the object over plain HTTP(S)
+    #[error("failed downloading contents for {url} over HTTP")]
+    HttpDownload {
+        url: S3Location,
+        source: reqwest::Error,
+    },
+
     /// Error while writing bytes to disk
     #[error("failed writing contents of {url} to disk")]
     Write {
@@ -461,6 +785,14 @@ pub(crate) enum DownloadError {
         source: std::io::Error,
     },
 
+    /// The number of bytes received did not match the object's reported size
+    #[error("size verification for object at {url} failed; expected {expected_size} bytes, got {actual_size}")]
+    Size {
+        url: S3Location,
+        expected_size: i64,
+        actual_size: usize,
+    },
+
     /// Object's computed MD5 digest did not match the expected MD5 digest
     #[error("checksum verification for object at {url} failed; expected MD5 {expected_md5:?}, got {actual_md5:?}")]
     Md5 {
@@ -470,12 +802,49 @@ pub(crate) enum DownloadError {
     },
 }
 
+impl DownloadError {
+    /// Returns `true` if the error was caused by access to the object being
+    /// denied
+    pub(crate) fn is_403(&self) -> bool {
+        match self {
+            DownloadError::Get(ge) => ge.is_403(),
+            DownloadError::Http(he) => he.is_403(),
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the error was caused by the object not existing
+    pub(crate) fn is_404(&self) -> bool {
+        match self {
+            DownloadError::Get(ge) => ge.is_404(),
+            DownloadError::Http(he) => he.is_404(),
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the error was caused by the object being in an
+    /// invalid state (usually because it is archived)
+    pub(crate) fn is_invalid_object_state(&self) -> bool {
+        match self {
+            DownloadError::Get(ge) => ge.is_invalid_object_state(),
+            DownloadError::Http(he) => he.is_invalid_object_state(),
+            _ => false,
+        }
+    }
+}
+
 impl From<GetError> for DownloadError {
     fn from(e: GetError) -> DownloadError {
         DownloadError::Get(Box::new(e))
     }
 }
 
+impl From<HttpError> for DownloadError {
+    fn from(e: HttpError) -> DownloadError {
+        DownloadError::Http(Box::new(e))
+    }
+}
+
 /// Error returned by [`S3Client::download_inventory_csv()`]
 #[derive(Debug, Error)]
 pub(crate) enum CsvDownloadError {
@@ -487,9 +856,9 @@ pub(crate) enum CsvDownloadError {
     #[error(transparent)]
     Download(#[from] DownloadError),
 
-    /// Failed to rewind filehandle after downloading
-    #[error("failed to rewind tempfile after downloading {url}")]
-    Rewind {
+    /// Failed to move the downloaded file into the inventory cache
+    #[error("failed to add {url} to inventory cache")]
+    Cache {
         url: S3Location,
         source: std::io::Error,
     },
@@ -558,6 +927,36 @@ impl GetError {
     }
 }
 
+/// Error returned by [`S3Client::get_restore_status()`] when a "Head Object"
+/// request fails
+#[derive(Debug, Error)]
+#[error("failed to get metadata for object at {url}")]
+pub(crate) struct HeadError {
+    url: S3Location,
+    source: SdkError<HeadObjectError, HttpResponse>,
+}
+
+impl HeadError {
+    pub(crate) fn is_404(&self) -> bool {
+        matches!(self.source, SdkError::ServiceError(ref e) if e.raw().status().as_u16() == 404)
+    }
+}
+
+/// Error returned by [`S3Client::restore_object()`] when a "Restore Object"
+/// request fails
+#[derive(Debug, Error)]
+#[error("failed to request restoration of object at {url}")]
+pub(crate) struct RestoreError {
+    url: S3Location,
+    source: SdkError<RestoreObjectError, HttpResponse>,
+}
+
+impl RestoreError {
+    pub(crate) fn is_404(&self) -> bool {
+        matches!(self.source, SdkError::ServiceError(ref e) if e.raw().status().as_u16() == 404)
+    }
+}
+
 /// Determine the region that the given S3 bucket belongs to
 // cf. <https://github.com/awslabs/aws-sdk-rust/issues/1052>
 pub(crate) async fn get_bucket_region(bucket: &str) -> Result<String, GetBucketRegionError> {
@@ -0,0 +1,115 @@
+use aws_sdk_s3::types::Tier;
+
+/// The retrieval tier to use when restoring an archived object
+///
+/// See
+/// <https://docs.aws.amazon.com/AmazonS3/latest/userguide/restoring-objects-retrieval-options.html>
+/// for the costs & speeds of each tier.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum RestoreTier {
+    /// Expedited retrieval (not available for `DEEP_ARCHIVE` or Intelligent-Tiering)
+    Expedited,
+
+    /// Standard retrieval
+    #[default]
+    Standard,
+
+    /// Bulk retrieval
+    Bulk,
+}
+
+impl From<RestoreTier> for Tier {
+    fn from(value: RestoreTier) -> Tier {
+        match value {
+            RestoreTier::Expedited => Tier::Expedited,
+            RestoreTier::Standard => Tier::Standard,
+            RestoreTier::Bulk => Tier::Bulk,
+        }
+    }
+}
+
+/// The restoration status of an archived object
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RestoreStatus {
+    /// The object is archived, and no restore is in progress
+    Archived,
+
+    /// A restore of the object has been requested but has not completed yet
+    InProgress,
+
+    /// The object can now be downloaded
+    Available,
+}
+
+impl RestoreStatus {
+    /// Determine an object's restoration status from the values of the
+    /// `x-amz-storage-class`, `x-amz-archive-status`, and `x-amz-restore`
+    /// headers in a "Head Object" response
+    pub(crate) fn from_headers(
+        storage_class: Option<&str>,
+        archive_status: Option<&str>,
+        restore: Option<&str>,
+    ) -> RestoreStatus {
+        if let Some(restore) = restore {
+            if restore.contains(r#"ongoing-request="true""#) {
+                return RestoreStatus::InProgress;
+            } else if restore.contains(r#"ongoing-request="false""#) {
+                return RestoreStatus::Available;
+            }
+        }
+        match storage_class {
+            // Restored Intelligent-Tiering objects are moved back to an access
+            // tier rather than given a temporary copy, and so they are
+            // available once they lack an archive status.
+            Some("INTELLIGENT_TIERING") if archive_status.is_none() => RestoreStatus::Available,
+            Some("GLACIER" | "DEEP_ARCHIVE" | "INTELLIGENT_TIERING") => RestoreStatus::Archived,
+            _ => RestoreStatus::Available,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(Some("GLACIER"), None, None, RestoreStatus::Archived)]
+    #[case(
+        Some("GLACIER"),
+        None,
+        Some(r#"ongoing-request="true""#),
+        RestoreStatus::InProgress
+    )]
+    #[case(
+        Some("DEEP_ARCHIVE"),
+        None,
+        Some(r#"ongoing-request="false", expiry-date="Fri, 21 Dec 2012 00:00:00 GMT""#),
+        RestoreStatus::Available
+    )]
+    #[case(
+        Some("INTELLIGENT_TIERING"),
+        Some("ARCHIVE_ACCESS"),
+        None,
+        RestoreStatus::Archived
+    )]
+    #[case(
+        Some("INTELLIGENT_TIERING"),
+        Some("DEEP_ARCHIVE_ACCESS"),
+        Some(r#"ongoing-request="true""#),
+        RestoreStatus::InProgress
+    )]
+    #[case(Some("INTELLIGENT_TIERING"), None, None, RestoreStatus::Available)]
+    #[case(None, None, None, RestoreStatus::Available)]
+    fn test_from_headers(
+        #[case] storage_class: Option<&str>,
+        #[case] archive_status: Option<&str>,
+        #[case] restore: Option<&str>,
+        #[case] status: RestoreStatus,
+    ) {
+        assert_eq!(
+            RestoreStatus::from_headers(storage_class, archive_status, restore),
+            status
+        );
+    }
+}
@@ -1,17 +1,28 @@
 use super::location::S3Location;
 use super::S3Client;
+use crate::inventory::{Directory, InventoryEntry, InventoryItem, ItemDetails};
+use crate::keypath::{KeyPath, KeyPathFromStringError};
 use crate::timestamps::DateHM;
 use aws_sdk_s3::{
-    operation::list_objects_v2::{ListObjectsV2Error, ListObjectsV2Output},
-    types::CommonPrefix,
+    operation::{
+        list_object_versions::{ListObjectVersionsError, ListObjectVersionsOutput},
+        list_objects_v2::{ListObjectsV2Error, ListObjectsV2Output},
+    },
+    primitives::DateTime,
+    types::{CommonPrefix, DeleteMarkerEntry, ObjectVersion},
+    Client,
 };
 use aws_smithy_async::future::pagination_stream::PaginationStream;
 use aws_smithy_runtime_api::client::{orchestrator::HttpResponse, result::SdkError};
 use futures_util::Stream;
+use std::cmp::Reverse;
 use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
 use thiserror::Error;
+use time::OffsetDateTime;
 
 type InnerListError = SdkError<ListObjectsV2Error, HttpResponse>;
 
@@ -97,3 +108,282 @@ pub(crate) struct ListObjectsError {
     url: S3Location,
     source: InnerListError,
 }
+
+type InnerListVersionsError = SdkError<ListObjectVersionsError, HttpResponse>;
+
+type ListVersionsFuture =
+    Pin<Box<dyn Future<Output = Result<ListObjectVersionsOutput, InnerListVersionsError>> + Send>>;
+
+/// A [`Stream`] that paginates over all versions & delete markers in an S3
+/// bucket with a given key prefix, yielding them as [`InventoryEntry`] values
+#[must_use = "streams do nothing unless polled"]
+pub(crate) struct ListObjectVersions {
+    client: Client,
+    url: S3Location,
+    // The SDK does not provide a paginator for "List Object Versions", so we
+    // have to handle the key & version ID markers ourselves.
+    inner: Option<ListVersionsFuture>,
+    results: VecDeque<Result<InventoryEntry, ListVersionsError>>,
+}
+
+impl ListObjectVersions {
+    /// Construct a new `ListObjectVersions` that uses `client` to paginate
+    /// over object versions that have the bucket & key prefix given by `url`.
+    pub(super) fn new(client: &S3Client, url: &S3Location) -> Self {
+        let client = client.inner.clone();
+        let inner = Some(Self::request(&client, url, None, None));
+        ListObjectVersions {
+            client,
+            url: url.clone(),
+            inner,
+            results: VecDeque::new(),
+        }
+    }
+
+    /// Start a "List Object Versions" request for the page of results
+    /// beginning after the given markers
+    fn request(
+        client: &Client,
+        url: &S3Location,
+        key_marker: Option<String>,
+        version_id_marker: Option<String>,
+    ) -> ListVersionsFuture {
+        Box::pin(
+            client
+                .list_object_versions()
+                .bucket(url.bucket())
+                .prefix(url.key())
+                .set_key_marker(key_marker)
+                .set_version_id_marker(version_id_marker)
+                .send(),
+        )
+    }
+
+    /// Convert an object version in a "List Object Versions" response to an
+    /// [`InventoryEntry`].  Returns `None` if the version lacks a key.
+    fn convert_version(
+        &self,
+        v: ObjectVersion,
+    ) -> Option<Result<InventoryEntry, ListVersionsError>> {
+        let key = v.key?;
+        let version_id = v.version_id;
+        if key.ends_with('/') && v.size.is_none_or(|sz| sz == 0) {
+            return Some(Ok(InventoryEntry::Directory(Directory {
+                bucket: self.url.bucket().to_owned(),
+                key,
+                version_id,
+            })));
+        }
+        let url = self.url.with_key(&key);
+        let key = match KeyPath::try_from(key) {
+            Ok(key) => key,
+            Err(source) => return Some(Err(ListVersionsError::Key { url, source })),
+        };
+        let Some(etag) = v.e_tag else {
+            return Some(Err(ListVersionsError::NoEtag { url }));
+        };
+        Some(Ok(InventoryEntry::Item(InventoryItem {
+            bucket: self.url.bucket().to_owned(),
+            key,
+            version_id,
+            is_latest: v.is_latest.unwrap_or(true),
+            last_modified_date: v.last_modified.and_then(convert_datetime),
+            storage_class: v.storage_class.map(|sc| sc.as_str().to_owned()),
+            // Version listings do not report Intelligent-Tiering access tiers
+            access_tier: None,
+            details: ItemDetails::Present {
+                size: v.size,
+                etag: etag.trim_matches('"').to_owned(),
+                // Unlike inventory lists, version listings do not say whether
+                // an object was uploaded in multiple parts or is encrypted
+                // with KMS, so we cannot trust the etag to be an MD5 digest.
+                etag_is_md5: false,
+            },
+        })))
+    }
+
+    /// Convert a delete marker in a "List Object Versions" response to an
+    /// [`InventoryEntry`].  Returns `None` if the delete marker lacks a key.
+    fn convert_delete_marker(
+        &self,
+        dm: DeleteMarkerEntry,
+    ) -> Option<Result<InventoryEntry, ListVersionsError>> {
+        let key = dm.key?;
+        if key.ends_with('/') {
+            return Some(Ok(InventoryEntry::Directory(Directory {
+                bucket: self.url.bucket().to_owned(),
+                key,
+                version_id: dm.version_id,
+            })));
+        }
+        let url = self.url.with_key(&key);
+        let key = match KeyPath::try_from(key) {
+            Ok(key) => key,
+            Err(source) => return Some(Err(ListVersionsError::Key { url, source })),
+        };
+        Some(Ok(InventoryEntry::Item(InventoryItem {
+            bucket: self.url.bucket().to_owned(),
+            key,
+            version_id: dm.version_id,
+            is_latest: dm.is_latest.unwrap_or(true),
+            last_modified_date: dm.last_modified.and_then(convert_datetime),
+            storage_class: None,
+            access_tier: None,
+            details: ItemDetails::Deleted,
+        })))
+    }
+}
+
+impl Stream for ListObjectVersions {
+    type Item = Result<InventoryEntry, ListVersionsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(r) = self.results.pop_front() {
+                return Some(r).into();
+            }
+            let Some(inner) = self.inner.as_mut() else {
+                return None.into();
+            };
+            let page = match ready!(inner.as_mut().poll(cx)) {
+                Ok(page) => page,
+                Err(source) => {
+                    self.inner = None;
+                    return Some(Err(ListVersionsError::List {
+                        url: self.url.clone(),
+                        source: Box::new(source),
+                    }))
+                    .into();
+                }
+            };
+            let mut entries = page
+                .versions
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|v| self.convert_version(v))
+                .collect::<Vec<_>>();
+            entries.extend(
+                page.delete_markers
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|dm| self.convert_delete_marker(dm)),
+            );
+            // Object versions and delete markers are returned in separate
+            // lists, each sorted by key and then from newest to oldest; merge
+            // them so that the entries for each key are yielded together.
+            entries.sort_by(|a, b| version_order(a).cmp(&version_order(b)));
+            self.results.extend(entries);
+            self.inner = (page.is_truncated == Some(true)).then(|| {
+                Self::request(
+                    &self.client,
+                    &self.url,
+                    page.next_key_marker,
+                    page.next_version_id_marker,
+                )
+            });
+        }
+    }
+}
+
+impl fmt::Debug for ListObjectVersions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListObjectVersions")
+            .field("url", &self.url)
+            .field("results", &self.results)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the key by which entries from a "List Object Versions" response are
+/// sorted: the key, then latest versions first, then newest versions first
+fn version_order(
+    r: &Result<InventoryEntry, ListVersionsError>,
+) -> (&str, bool, Reverse<Option<OffsetDateTime>>) {
+    match r {
+        Ok(InventoryEntry::Item(item)) => (
+            item.key.as_ref(),
+            !item.is_latest,
+            Reverse(item.last_modified_date),
+        ),
+        Ok(entry @ InventoryEntry::Directory(_)) => (entry.key(), false, Reverse(None)),
+        Err(
+            ListVersionsError::List { url, .. }
+            | ListVersionsError::Key { url, .. }
+            | ListVersionsError::NoEtag { url },
+        ) => (url.key(), false, Reverse(None)),
+    }
+}
+
+/// Convert an AWS SDK timestamp to an [`OffsetDateTime`]
+fn convert_datetime(dt: DateTime) -> Option<OffsetDateTime> {
+    OffsetDateTime::from_unix_timestamp_nanos(dt.as_nanos()).ok()
+}
+
+/// Error yielded by [`ListObjectVersions`]
+#[derive(Debug, Error)]
+pub(crate) enum ListVersionsError {
+    /// A "List Object Versions" request failed
+    #[error("failed to list S3 object versions in {url}")]
+    List {
+        url: S3Location,
+        source: Box<InnerListVersionsError>,
+    },
+
+    /// An object's key was not a valid path
+    #[error("object {url} has an invalid key")]
+    Key {
+        url: S3Location,
+        source: KeyPathFromStringError,
+    },
+
+    /// An object version was listed without an etag
+    #[error("object {url} was listed without an etag")]
+    NoEtag { url: S3Location },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::fake::FakeS3;
+    use futures_util::StreamExt;
+    use time::macros::datetime;
+
+    #[tokio::test]
+    async fn versions_and_delete_markers_merged() {
+        let server = FakeS3::start().await;
+        server.put_at("b", "apple", "1", b"a", datetime!(2025-01-01 00:00 UTC));
+        server.delete_at("b", "apple", "2", datetime!(2025-01-02 00:00 UTC));
+        server.put_at("b", "apple", "3", b"a", datetime!(2025-01-03 00:00 UTC));
+        server.delete_at("b", "banana", "1", datetime!(2025-01-01 00:00 UTC));
+        server.put_at("b", "cherry", "1", b"c", datetime!(2025-01-01 00:00 UTC));
+        let tmpdir = tempfile::tempdir().unwrap();
+        let client = S3Client::for_fake(
+            &server,
+            S3Location::new("inventories".into(), String::new()),
+            None,
+            tmpdir.path(),
+        );
+        let entries = client
+            .list_object_versions("b", "")
+            .map(|r| match r.unwrap() {
+                InventoryEntry::Item(item) => (
+                    String::from(&item.key),
+                    item.version_id.clone().unwrap(),
+                    item.is_deleted(),
+                ),
+                InventoryEntry::Directory(d) => panic!("Unexpected directory {d:?}"),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            entries,
+            [
+                ("apple".into(), "3".into(), false),
+                ("apple".into(), "2".into(), true),
+                ("apple".into(), "1".into(), false),
+                ("banana".into(), "1".into(), true),
+                ("cherry".into(), "1".into(), false),
+            ]
+        );
+    }
+}
@@ -1,6 +1,10 @@
-use crate::consts::RESERVED_PREFIX;
+use crate::consts::{MAX_RUN_HISTORY, RESERVED_PREFIX};
+use crate::syncer::RunCounts;
+use crate::timestamps::DateHM;
 use anyhow::Context;
+use bytesize::ByteSize;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
 use time::OffsetDateTime;
@@ -62,7 +66,19 @@ impl StateFileManager {
         Ok(())
     }
 
-    pub(crate) fn start(&self, require_last_success: bool) -> anyhow::Result<()> {
+    /// Record the start of a backup, returning the start time.  If
+    /// `require_last_success` is true and the previous backup did not
+    /// complete successfully, an error is returned.
+    ///
+    /// If `retry_failures` is true, the run is a `--retry-failures` run, which
+    /// does not count as a backup, and so its start is not recorded in the
+    /// state file; this way, a successful retry run does not make
+    /// `--require-last-success` fail on the next run.
+    pub(crate) fn start(
+        &self,
+        require_last_success: bool,
+        retry_failures: bool,
+    ) -> anyhow::Result<OffsetDateTime> {
         let mut state = self.load()?;
         if require_last_success {
             if let Some(last_start) = state.last_backup_started {
@@ -74,21 +90,263 @@ impl StateFileManager {
                 }
             }
         }
-        state.last_backup_started = Some(OffsetDateTime::now_utc());
-        self.store(state)
+        let now = OffsetDateTime::now_utc();
+        if !retry_failures {
+            state.last_backup_started = Some(now);
+            self.store(state)?;
+        }
+        Ok(now)
+    }
+
+    /// Returns the date of the inventory manifest synced by the last
+    /// successful backup, if any
+    pub(crate) fn last_manifest_date(&self) -> anyhow::Result<Option<DateHM>> {
+        Ok(self.load()?.synced_manifest_date)
+    }
+
+    /// Check that `manifest_date` is not older than the date of the manifest
+    /// synced by the last successful backup.  If it is older, an error is
+    /// returned unless `allow_rewind` is true, in which case a warning is
+    /// emitted instead.
+    pub(crate) fn check_manifest_date(
+        &self,
+        manifest_date: DateHM,
+        allow_rewind: bool,
+    ) -> anyhow::Result<()> {
+        if let Some(last_date) = self.last_manifest_date()? {
+            if manifest_date < last_date {
+                if allow_rewind {
+                    tracing::warn!(%manifest_date, %last_date, "Syncing inventory manifest older than that of the last successful backup");
+                } else {
+                    anyhow::bail!("Inventory manifest date {manifest_date} is older than that of the last successful backup ({last_date}); pass --allow-rewind to sync it anyway");
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub(crate) fn end(&self) -> anyhow::Result<()> {
+    /// Record the end of a backup by appending `record` to the run history,
+    /// discarding the oldest entries if the history is full.  If the backup
+    /// was successful (not counting `--retry-failures` runs), the time at
+    /// which it finished and the date of the manifest synced are also
+    /// recorded.
+    pub(crate) fn end(&self, record: RunRecord) -> anyhow::Result<()> {
         let mut state = self.load()?;
-        state.last_successful_backup_finished = Some(OffsetDateTime::now_utc());
+        if record.status == RunStatus::Success {
+            state.last_successful_backup_finished = Some(record.finished);
+            state.synced_manifest_date = Some(record.manifest_date);
+        }
+        state.history.push(record);
+        let excess = state.history.len().saturating_sub(MAX_RUN_HISTORY);
+        state.history.drain(..excess);
         self.store(state)
     }
+
+    /// Returns the recorded history of runs, from oldest to newest
+    pub(crate) fn history(&self) -> anyhow::Result<Vec<RunRecord>> {
+        Ok(self.load()?.history)
+    }
 }
 
-#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 struct State {
     #[serde(with = "time::serde::rfc3339::option")]
     last_backup_started: Option<OffsetDateTime>,
     #[serde(with = "time::serde::rfc3339::option")]
     last_successful_backup_finished: Option<OffsetDateTime>,
+    synced_manifest_date: Option<DateHM>,
+    #[serde(default)]
+    history: Vec<RunRecord>,
+}
+
+/// An entry in the run history
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct RunRecord {
+    /// The date of the inventory manifest being synced.  (Runs that fail
+    /// before the manifest is fetched & checked are not recorded.)
+    pub(crate) manifest_date: DateHM,
+
+    /// The time at which the run started
+    #[serde(with = "time::serde::rfc3339")]
+    pub(crate) started: OffsetDateTime,
+
+    /// The time at which the run finished
+    #[serde(with = "time::serde::rfc3339")]
+    pub(crate) finished: OffsetDateTime,
+
+    /// How the run ended
+    pub(crate) status: RunStatus,
+
+    /// Counts of the actions taken on objects during the run
+    #[serde(flatten)]
+    pub(crate) counts: RunCounts,
+
+    /// The version of s3invsync that performed the run
+    pub(crate) version: String,
+}
+
+impl fmt::Display for RunRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rfc3339 = &time::format_description::well_known::Rfc3339;
+        write!(
+            f,
+            "{} -- {}: {}; manifest {}",
+            self.started.format(rfc3339).map_err(|_| fmt::Error)?,
+            self.finished.format(rfc3339).map_err(|_| fmt::Error)?,
+            self.status,
+            self.manifest_date,
+        )?;
+        let RunCounts {
+            downloaded,
+            renamed,
+            deleted,
+            skipped,
+            bytes,
+            ref ignored_errors,
+        } = self.counts;
+        write!(
+            f,
+            "; {downloaded} downloaded ({}), {renamed} renamed, {deleted} deleted, {skipped} skipped",
+            ByteSize(bytes)
+        )?;
+        for (warning, count) in ignored_errors {
+            write!(f, ", {count} ignored {}", <&str>::from(warning))?;
+        }
+        write!(f, "; s3invsync {}", self.version)
+    }
+}
+
+/// How a run ended
+#[derive(Clone, Copy, Debug, Deserialize, strum::Display, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum RunStatus {
+    /// The backup completed successfully
+    Success,
+
+    /// A `--retry-failures` run completed successfully.  As such a run only
+    /// processes some objects, it is not recorded as a successful backup.
+    Retried,
+
+    /// The backup failed due to an error
+    Failed,
+
+    /// The backup was interrupted by Ctrl-C
+    Interrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(started: OffsetDateTime, manifest_date: DateHM, status: RunStatus) -> RunRecord {
+        RunRecord {
+            manifest_date,
+            started,
+            finished: started,
+            status,
+            counts: RunCounts::default(),
+            version: String::from("0.0.0"),
+        }
+    }
+
+    #[test]
+    fn check_manifest_date() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sfm = StateFileManager::new(tmpdir.path());
+        let older = "2025-03-01T01-00Z".parse::<DateHM>().unwrap();
+        let newer = "2025-03-02T01-00Z".parse::<DateHM>().unwrap();
+        assert!(sfm.check_manifest_date(newer, false).is_ok());
+        let started = sfm.start(false, false).unwrap();
+        sfm.end(record(started, newer, RunStatus::Success)).unwrap();
+        assert_eq!(sfm.last_manifest_date().unwrap(), Some(newer));
+        assert!(sfm.check_manifest_date(newer, false).is_ok());
+        let e = sfm.check_manifest_date(older, false).unwrap_err();
+        assert!(e.to_string().contains("--allow-rewind"));
+        assert!(sfm.check_manifest_date(older, true).is_ok());
+    }
+
+    #[test]
+    fn retried_run_is_not_success() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sfm = StateFileManager::new(tmpdir.path());
+        let older = "2025-03-01T01-00Z".parse::<DateHM>().unwrap();
+        let newer = "2025-03-02T01-00Z".parse::<DateHM>().unwrap();
+        let started = sfm.start(false, false).unwrap();
+        sfm.end(record(started, older, RunStatus::Success)).unwrap();
+        let state = sfm.load().unwrap();
+        let retried = sfm.start(false, true).unwrap();
+        sfm.end(record(retried, newer, RunStatus::Retried)).unwrap();
+        let new_state = sfm.load().unwrap();
+        assert_eq!(new_state.synced_manifest_date, Some(older));
+        assert_eq!(
+            new_state.last_successful_backup_finished,
+            state.last_successful_backup_finished
+        );
+        assert_eq!(
+            new_state.history.last().map(|r| r.status),
+            Some(RunStatus::Retried)
+        );
+    }
+
+    #[test]
+    fn retried_run_does_not_block_require_last_success() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sfm = StateFileManager::new(tmpdir.path());
+        let date = "2025-03-01T01-00Z".parse::<DateHM>().unwrap();
+        let started = sfm.start(true, false).unwrap();
+        sfm.end(record(started, date, RunStatus::Success)).unwrap();
+        let retried = sfm.start(true, true).unwrap();
+        sfm.end(record(retried, date, RunStatus::Retried)).unwrap();
+        assert!(sfm.start(true, false).is_ok());
+    }
+
+    #[test]
+    fn load_without_manifest_date() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sfm = StateFileManager::new(tmpdir.path());
+        fs_err::write(
+            sfm.path(),
+            r#"{"last_backup_started": "2025-03-01T00:00:00Z", "last_successful_backup_finished": null}"#,
+        )
+        .unwrap();
+        assert_eq!(sfm.last_manifest_date().unwrap(), None);
+    }
+
+    #[test]
+    fn history() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let sfm = StateFileManager::new(tmpdir.path());
+        let date = "2025-03-01T01-00Z".parse::<DateHM>().unwrap();
+        let started = sfm.start(false, false).unwrap();
+        sfm.end(record(started, date, RunStatus::Failed)).unwrap();
+        assert_eq!(sfm.last_manifest_date().unwrap(), None);
+        assert!(sfm.start(true, false).is_err());
+        for _ in 0..MAX_RUN_HISTORY {
+            sfm.end(record(started, date, RunStatus::Success)).unwrap();
+        }
+        let history = sfm.history().unwrap();
+        assert_eq!(history.len(), MAX_RUN_HISTORY);
+        assert!(history.iter().all(|r| r.status == RunStatus::Success));
+        assert_eq!(sfm.last_manifest_date().unwrap(), Some(date));
+    }
+
+    #[test]
+    fn display_record() {
+        let mut rec = record(
+            time::macros::datetime!(2025-03-01 12:00:00 UTC),
+            "2025-03-01T01-00Z".parse::<DateHM>().unwrap(),
+            RunStatus::Success,
+        );
+        rec.finished = time::macros::datetime!(2025-03-01 13:00:00 UTC);
+        rec.counts.downloaded = 3;
+        rec.counts.bytes = 2048;
+        rec.counts
+            .ignored_errors
+            .insert(crate::errorset::DownloadWarning::AccessDenied, 2);
+        assert_eq!(
+            rec.to_string(),
+            "2025-03-01T12:00:00Z -- 2025-03-01T13:00:00Z: success; manifest 2025-03-01T01-00Z; 3 downloaded (2.0 KiB), 0 renamed, 0 deleted, 0 skipped, 2 ignored access-denied; s3invsync 0.0.0"
+        );
+    }
 }
@@ -0,0 +1,495 @@
+use super::Syncer;
+use crate::consts::RESERVED_PREFIX;
+use crate::inventory::{ArchiveTier, InventoryItem, ItemDetails};
+use crate::s3::{RestoreStatus, RestoreTier};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+
+/// What to do with archived objects (those stored in an archive storage class
+/// or Intelligent-Tiering archive access tier) that need to be downloaded
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum ArchivePolicy {
+    /// Attempt to download archived objects like any other object
+    #[default]
+    Download,
+
+    /// Skip archived objects, logging a message at DEBUG level
+    Skip,
+
+    /// Skip archived objects, emitting a warning for each one
+    Warn,
+
+    /// Skip archived objects and list them in `.s3invsync.archived.json`
+    Queue,
+
+    /// Request restoration of archived objects, track the pending restores in
+    /// `.s3invsync.restores.json`, and download the objects on a later run
+    /// once they have been restored
+    Restore,
+}
+
+/// Parameters for restoring archived objects under [`ArchivePolicy::Restore`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct RestoreParams {
+    /// The retrieval tier to use
+    pub(crate) tier: RestoreTier,
+
+    /// The number of days for which restored copies should be kept available
+    pub(crate) days: i32,
+}
+
+/// A record of an archived object version that was not downloaded
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(super) struct ArchivedRecord {
+    /// The bucket on which the object is located
+    pub(super) bucket: String,
+
+    /// The object's key
+    pub(super) key: String,
+
+    /// The object's version ID
+    pub(super) version_id: Option<String>,
+
+    /// The object's etag
+    pub(super) etag: String,
+
+    /// The object's size
+    pub(super) size: Option<i64>,
+
+    /// Whether the version is the latest version of the key
+    pub(super) is_latest: bool,
+
+    /// The version's date of last modification
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub(super) last_modified: Option<OffsetDateTime>,
+
+    /// The archive tier in which the version is stored
+    pub(super) archive_tier: ArchiveTier,
+}
+
+impl ArchivedRecord {
+    /// Construct a record for `item`.  Returns `None` if `item` is a delete
+    /// marker.
+    fn for_item(item: &InventoryItem, archive_tier: ArchiveTier) -> Option<ArchivedRecord> {
+        let ItemDetails::Present { ref etag, size, .. } = item.details else {
+            return None;
+        };
+        Some(ArchivedRecord {
+            bucket: item.bucket.clone(),
+            key: String::from(&item.key),
+            version_id: item.version_id.clone(),
+            etag: etag.clone(),
+            size,
+            is_latest: item.is_latest,
+            last_modified: item.last_modified_date,
+            archive_tier,
+        })
+    }
+}
+
+/// A record of a restore request for an archived object version that has not
+/// yet been downloaded
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(super) struct PendingRestore {
+    /// The bucket on which the object is located
+    pub(super) bucket: String,
+
+    /// The object's key
+    pub(super) key: String,
+
+    /// The object's version ID
+    pub(super) version_id: Option<String>,
+
+    /// The object's etag
+    pub(super) etag: String,
+
+    /// The archive tier in which the version is stored
+    pub(super) archive_tier: ArchiveTier,
+
+    /// The time at which restoration of the object was last requested
+    #[serde(with = "time::serde::rfc3339")]
+    pub(super) requested: OffsetDateTime,
+}
+
+/// Key identifying a [`PendingRestore`]: bucket, key, and version ID
+type RestoreKey = (String, String, Option<String>);
+
+impl PendingRestore {
+    fn restore_key(&self) -> RestoreKey {
+        (
+            self.bucket.clone(),
+            self.key.clone(),
+            self.version_id.clone(),
+        )
+    }
+}
+
+/// Counts of restore-related events during a run
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct RestoreCounts {
+    /// Number of restore requests issued
+    requested: usize,
+
+    /// Number of objects whose restores were still in progress
+    in_progress: usize,
+
+    /// Number of objects that were found to be restored and thus downloaded
+    available: usize,
+}
+
+/// Object for applying an [`ArchivePolicy`] to archived objects and keeping
+/// track of the archived objects encountered during a run
+#[derive(Debug)]
+pub(super) struct ArchiveTracker {
+    policy: ArchivePolicy,
+
+    /// Parameters for restore requests under [`ArchivePolicy::Restore`]
+    restore_params: RestoreParams,
+
+    /// Number & total size of archived objects that needed to be downloaded,
+    /// per archive tier
+    summary: Mutex<BTreeMap<ArchiveTier, (usize, i64)>>,
+
+    /// Archived objects that were skipped under [`ArchivePolicy::Queue`]
+    queue: Mutex<Vec<ArchivedRecord>>,
+
+    /// Pending restores loaded from the restores file that have not yet been
+    /// encountered during this run
+    prev_restores: Mutex<HashMap<RestoreKey, PendingRestore>>,
+
+    /// Restores that are pending as of this run
+    restores: Mutex<Vec<PendingRestore>>,
+
+    /// Tally of restore-related events
+    restore_counts: Mutex<RestoreCounts>,
+}
+
+impl ArchiveTracker {
+    pub(super) fn new(policy: ArchivePolicy, restore_params: RestoreParams) -> ArchiveTracker {
+        ArchiveTracker {
+            policy,
+            restore_params,
+            summary: Mutex::new(BTreeMap::new()),
+            queue: Mutex::new(Vec::new()),
+            prev_restores: Mutex::new(HashMap::new()),
+            restores: Mutex::new(Vec::new()),
+            restore_counts: Mutex::new(RestoreCounts::default()),
+        }
+    }
+
+    /// If the policy is [`ArchivePolicy::Restore`], load the pending restores
+    /// recorded by previous runs from the restores file in `outdir`
+    pub(super) fn load_restores(&self, outdir: &Path) -> anyhow::Result<()> {
+        if self.policy != ArchivePolicy::Restore {
+            return Ok(());
+        }
+        let restores = RecordsFile::restores(outdir).load::<PendingRestore>()?;
+        if !restores.is_empty() {
+            tracing::info!(
+                count = restores.len(),
+                "Loaded pending restores of archived objects from previous runs"
+            );
+        }
+        let mut prev = self
+            .prev_restores
+            .lock()
+            .expect("previous restores mutex should not be poisoned");
+        *prev = restores
+            .into_iter()
+            .map(|rec| (rec.restore_key(), rec))
+            .collect();
+        Ok(())
+    }
+
+    /// Called whenever `item` needs to be downloaded.  If `item` is archived,
+    /// it is counted in the summary and the policy is applied to it, with any
+    /// requests for the object made via `syncer`.  Returns `true` if the item
+    /// should be downloaded.
+    pub(super) async fn check(
+        &self,
+        syncer: &Syncer,
+        item: &InventoryItem,
+    ) -> anyhow::Result<bool> {
+        let Some(tier) = item.archive_tier() else {
+            return Ok(true);
+        };
+        {
+            let mut summary = self
+                .summary
+                .lock()
+                .expect("archive summary mutex should not be poisoned");
+            let entry = summary.entry(tier).or_default();
+            entry.0 += 1;
+            entry.1 += item.size().unwrap_or_default();
+        }
+        match self.policy {
+            ArchivePolicy::Download => {
+                tracing::debug!(%tier, "Object is archived; attempting download anyway");
+                Ok(true)
+            }
+            ArchivePolicy::Skip => {
+                tracing::debug!(%tier, "Object is archived; skipping");
+                Ok(false)
+            }
+            ArchivePolicy::Warn => {
+                tracing::warn!(%tier, "Object is archived; skipping");
+                Ok(false)
+            }
+            ArchivePolicy::Queue => {
+                tracing::info!(%tier, "Object is archived; adding to queue of archived objects");
+                if let Some(rec) = ArchivedRecord::for_item(item, tier) {
+                    self.queue
+                        .lock()
+                        .expect("archive queue mutex should not be poisoned")
+                        .push(rec);
+                }
+                Ok(false)
+            }
+            ArchivePolicy::Restore => self.check_restore(syncer, item, tier).await,
+        }
+    }
+
+    /// Apply [`ArchivePolicy::Restore`] to the archived object `item`: if a
+    /// restore was previously requested, check whether it has completed, and
+    /// otherwise request one.  Returns `true` if the object is now available
+    /// for download.
+    async fn check_restore(
+        &self,
+        syncer: &Syncer,
+        item: &InventoryItem,
+        tier: ArchiveTier,
+    ) -> anyhow::Result<bool> {
+        let ItemDetails::Present { ref etag, .. } = item.details else {
+            return Ok(false);
+        };
+        let url = item.url();
+        let prev = {
+            let mut guard = self
+                .prev_restores
+                .lock()
+                .expect("previous restores mutex should not be poisoned");
+            guard.remove(&(
+                item.bucket.clone(),
+                String::from(&item.key),
+                item.version_id.clone(),
+            ))
+        }
+        .filter(|rec| &rec.etag == etag);
+        let mut status = if prev.is_some() {
+            syncer.get_restore_status(&url).await?
+        } else {
+            RestoreStatus::Archived
+        };
+        let mut requested = prev.map_or_else(OffsetDateTime::now_utc, |rec| rec.requested);
+        if status == RestoreStatus::Archived {
+            tracing::info!(%tier, restore_tier = ?self.restore_params.tier, "Object is archived; requesting restore");
+            // Restored Intelligent-Tiering objects do not expire, and so S3
+            // rejects restore requests for them that specify a number of days.
+            let days = matches!(tier, ArchiveTier::Glacier | ArchiveTier::DeepArchive)
+                .then_some(self.restore_params.days);
+            status = syncer
+                .restore_object(&url, self.restore_params.tier, days)
+                .await?;
+            requested = OffsetDateTime::now_utc();
+            self.bump_counts(|c| c.requested += 1);
+        } else if status == RestoreStatus::InProgress {
+            self.bump_counts(|c| c.in_progress += 1);
+        }
+        if status == RestoreStatus::Available {
+            tracing::info!(%tier, "Archived object has been restored; downloading");
+            self.bump_counts(|c| c.available += 1);
+            return Ok(true);
+        }
+        tracing::info!(%tier, "Restore of archived object is in progress; will download on a later run");
+        self.restores
+            .lock()
+            .expect("restores mutex should not be poisoned")
+            .push(PendingRestore {
+                bucket: item.bucket.clone(),
+                key: String::from(&item.key),
+                version_id: item.version_id.clone(),
+                etag: etag.clone(),
+                archive_tier: tier,
+                requested,
+            });
+        Ok(false)
+    }
+
+    fn bump_counts<F: FnOnce(&mut RestoreCounts)>(&self, func: F) {
+        let mut guard = self
+            .restore_counts
+            .lock()
+            .expect("restore counts mutex should not be poisoned");
+        func(&mut guard);
+    }
+
+    /// Log the number & total size of archived objects encountered, per
+    /// archive tier, along with counts of restore-related events
+    pub(super) fn log_summary(&self) {
+        {
+            let summary = self
+                .summary
+                .lock()
+                .expect("archive summary mutex should not be poisoned");
+            if summary.is_empty() {
+                return;
+            }
+            let mut total_count = 0;
+            let mut total_size = 0;
+            for (tier, &(count, size)) in summary.iter() {
+                tracing::info!(%tier, count, size, "Archived objects in need of download");
+                total_count += count;
+                total_size += size;
+            }
+            tracing::info!(
+                count = total_count,
+                size = total_size,
+                policy = ?self.policy,
+                "Total archived objects in need of download"
+            );
+        }
+        if self.policy == ArchivePolicy::Restore {
+            let counts = *self
+                .restore_counts
+                .lock()
+                .expect("restore counts mutex should not be poisoned");
+            tracing::info!(
+                requested = counts.requested,
+                in_progress = counts.in_progress,
+                available = counts.available,
+                "Restores of archived objects"
+            );
+        }
+    }
+
+    /// Write the queued records (under [`ArchivePolicy::Queue`]) or pending
+    /// restores (under [`ArchivePolicy::Restore`]) to the appropriate file in
+    /// `outdir`.
+    ///
+    /// If `complete` is false, the run did not finish, and so any pending
+    /// restores from previous runs that were not encountered during this run
+    /// are kept.
+    pub(super) fn store(&self, outdir: &Path, complete: bool) -> anyhow::Result<()> {
+        match self.policy {
+            ArchivePolicy::Queue if complete => {
+                let mut queue = std::mem::take(
+                    &mut *self
+                        .queue
+                        .lock()
+                        .expect("archive queue mutex should not be poisoned"),
+                );
+                queue.sort_by(|a, b| (&a.key, &a.version_id).cmp(&(&b.key, &b.version_id)));
+                RecordsFile::archived(outdir).store(&queue)
+            }
+            ArchivePolicy::Restore => {
+                let mut restores = std::mem::take(
+                    &mut *self
+                        .restores
+                        .lock()
+                        .expect("restores mutex should not be poisoned"),
+                );
+                if !complete {
+                    restores.extend(
+                        std::mem::take(
+                            &mut *self
+                                .prev_restores
+                                .lock()
+                                .expect("previous restores mutex should not be poisoned"),
+                        )
+                        .into_values(),
+                    );
+                }
+                restores.sort_by_key(PendingRestore::restore_key);
+                RecordsFile::restores(outdir).store(&restores)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Handle for reading & writing a file in which a list of records (either
+/// [`ArchivedRecord`]s or [`PendingRestore`]s) is stored between runs
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct RecordsFile {
+    path: PathBuf,
+
+    /// Prefix for temporary files used when updating the file
+    tmp_prefix: String,
+}
+
+impl RecordsFile {
+    fn new(outdir: &Path, name: &str) -> Self {
+        RecordsFile {
+            path: outdir.join(format!("{RESERVED_PREFIX}.{name}.json")),
+            tmp_prefix: format!("{RESERVED_PREFIX}.{name}."),
+        }
+    }
+
+    /// Returns a handle for the file listing archived objects queued under
+    /// [`ArchivePolicy::Queue`]
+    fn archived(outdir: &Path) -> Self {
+        RecordsFile::new(outdir, "archived")
+    }
+
+    /// Returns a handle for the file listing pending restores under
+    /// [`ArchivePolicy::Restore`]
+    fn restores(outdir: &Path) -> Self {
+        RecordsFile::new(outdir, "restores")
+    }
+
+    /// Read & parse the file.  If the file does not exist, return an empty
+    /// list.
+    fn load<T: DeserializeOwned>(&self) -> anyhow::Result<Vec<T>> {
+        let content = match fs_err::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to deserialize contents of {}", self.path.display()))
+    }
+
+    /// Set the content of the file to the serialized list.  If the list is
+    /// empty, the file is deleted instead.
+    fn store<T: Serialize>(&self, records: &[T]) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return match fs_err::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let fp = tempfile::Builder::new()
+            .prefix(&self.tmp_prefix)
+            .tempfile_in(
+                self.path
+                    .parent()
+                    .expect("records file path should have a parent"),
+            )
+            .with_context(|| {
+                format!(
+                    "failed to create temporary records file for updating {}",
+                    self.path.display()
+                )
+            })?;
+        serde_json::to_writer_pretty(fp.as_file(), records)
+            .with_context(|| format!("failed to serialize records to {}", self.path.display()))?;
+        fp.as_file().write_all(b"\n").with_context(|| {
+            format!(
+                "failed to write terminating newline to {}",
+                self.path.display()
+            )
+        })?;
+        fp.persist(&self.path).with_context(|| {
+            format!(
+                "failed to persist temporary records file to {}",
+                self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
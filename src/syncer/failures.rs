@@ -0,0 +1,327 @@
+use crate::consts::RESERVED_PREFIX;
+use crate::errorset::{DownloadWarning, ErrorSet};
+use crate::s3::{DownloadError, S3Location};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A record of an object that could not be backed up, as stored in
+/// `.s3invsync.failures.jsonl`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(super) struct FailureRecord {
+    /// The object's key
+    pub(super) key: String,
+
+    /// The object's version ID
+    pub(super) version_id: Option<String>,
+
+    /// The object's URL
+    pub(super) url: String,
+
+    /// The category of error
+    #[serde(rename = "type")]
+    pub(super) kind: FailureKind,
+
+    /// The error message
+    pub(super) error: String,
+
+    /// Whether the error was ignored due to `--ignore-errors`
+    pub(super) ignored: bool,
+}
+
+/// The category of error that caused an object to not be backed up
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(super) enum FailureKind {
+    AccessDenied,
+    InvalidObjectState,
+    MissingOldVersion,
+
+    /// Any other error returned when downloading the object
+    Download,
+
+    /// An error not involving the download itself, such as a failure to
+    /// manipulate local files
+    Other,
+}
+
+impl FailureKind {
+    /// Categorize an error that occurred while processing an object
+    fn for_error(e: &anyhow::Error, is_old: bool) -> FailureKind {
+        match e.downcast_ref::<DownloadError>() {
+            Some(de) => ErrorSet::all()
+                .download_error_to_warning(de, is_old)
+                .map_or(FailureKind::Download, FailureKind::from),
+            None => FailureKind::Other,
+        }
+    }
+}
+
+impl From<DownloadWarning> for FailureKind {
+    fn from(value: DownloadWarning) -> FailureKind {
+        match value {
+            DownloadWarning::AccessDenied => FailureKind::AccessDenied,
+            DownloadWarning::InvalidObjectState => FailureKind::InvalidObjectState,
+            DownloadWarning::MissingOldVersion => FailureKind::MissingOldVersion,
+        }
+    }
+}
+
+/// A thread-safe collection of the objects that failed during a run
+#[derive(Debug, Default)]
+pub(super) struct FailureTracker(Mutex<Vec<FailureRecord>>);
+
+impl FailureTracker {
+    /// Record that downloading the object at `url` failed with an error that
+    /// was ignored
+    pub(super) fn ignored(&self, url: &S3Location, warning: DownloadWarning, e: &anyhow::Error) {
+        self.push(url, warning.into(), e, true);
+    }
+
+    /// Record that processing the object at `url` failed with a fatal error
+    pub(super) fn fatal(&self, url: &S3Location, is_old: bool, e: &anyhow::Error) {
+        self.push(url, FailureKind::for_error(e, is_old), e, false);
+    }
+
+    fn push(&self, url: &S3Location, kind: FailureKind, e: &anyhow::Error, ignored: bool) {
+        self.0
+            .lock()
+            .expect("failures mutex should not be poisoned")
+            .push(FailureRecord {
+                key: url.key().to_owned(),
+                version_id: url.version_id().map(ToOwned::to_owned),
+                url: url.to_string(),
+                kind,
+                error: format!("{e:#}"),
+                ignored,
+            });
+    }
+
+    /// Return the records of the failed objects combined with the records in
+    /// `previous`, sorted by key & version ID.  Where an object has records in
+    /// both, only the record from this run is kept.
+    pub(super) fn records_with(&self, previous: Vec<FailureRecord>) -> Vec<FailureRecord> {
+        let current = self
+            .0
+            .lock()
+            .expect("failures mutex should not be poisoned")
+            .clone();
+        let mut records = BTreeMap::new();
+        for rec in previous.into_iter().chain(current) {
+            records.insert((rec.key.clone(), rec.version_id.clone()), rec);
+        }
+        records.into_values().collect()
+    }
+}
+
+/// Given a list of inventory list file specs paired with the keys on their
+/// first lines, sorted by those keys, return only those files whose key
+/// ranges may contain one of `keys` or a key that affects its local path
+/// (i.e., a key beginning with one of `keys` followed by a slash), assuming
+/// that the files' key ranges do not overlap
+pub(super) fn lists_for_keys<T>(
+    fspecs: Vec<(String, T)>,
+    keys: &BTreeSet<&str>,
+) -> Vec<(String, T)> {
+    let uppers = fspecs
+        .iter()
+        .skip(1)
+        .map(|(first, _)| Some(first.clone()))
+        .chain(std::iter::once(None))
+        .collect::<Vec<_>>();
+    fspecs
+        .into_iter()
+        .zip(uppers)
+        .filter(|((first, _), upper)| {
+            let mut candidates = keys.range::<&str, _>(first.as_str()..);
+            let in_range = match upper {
+                Some(upper) => candidates.next().is_some_and(|k| k <= &upper.as_str()),
+                None => candidates.next().is_some(),
+            };
+            // A file can also start partway through the keys under a wanted
+            // key followed by a slash, and any key starting with a wanted key
+            // followed by a character less than '0' sorts between that key
+            // and those keys, so check for such prefixes of the first key.
+            in_range
+                || first
+                    .char_indices()
+                    .any(|(i, c)| c < '0' && keys.contains(&first[..i]))
+        })
+        .map(|(fspec, _)| fspec)
+        .collect()
+}
+
+/// Manager for the `.s3invsync.failures.jsonl` file in the root of a backup,
+/// which lists the objects that failed during the most recent run
+pub(super) struct FailuresFileManager {
+    path: PathBuf,
+}
+
+impl FailuresFileManager {
+    pub(super) fn new(outdir: &Path) -> Self {
+        FailuresFileManager {
+            path: outdir.join(format!("{RESERVED_PREFIX}.failures.jsonl")),
+        }
+    }
+
+    /// Read & parse the failures file.  If the file does not exist, return an
+    /// empty list.
+    pub(super) fn load(&self) -> anyhow::Result<Vec<FailureRecord>> {
+        let content = match fs_err::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("failed to deserialize contents of {}", self.path.display()))
+    }
+
+    /// Set the content of the failures file to the serialized records, one
+    /// per line.  If the list is empty, the file is deleted instead.
+    pub(super) fn store(&self, records: &[FailureRecord]) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return match fs_err::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let fp = tempfile::Builder::new()
+            .prefix(&format!("{RESERVED_PREFIX}.failures."))
+            .tempfile_in(
+                self.path
+                    .parent()
+                    .expect("failures file path should have a parent"),
+            )
+            .with_context(|| {
+                format!(
+                    "failed to create temporary failures file for updating {}",
+                    self.path.display()
+                )
+            })?;
+        for rec in records {
+            serde_json::to_writer(fp.as_file(), rec).with_context(|| {
+                format!("failed to serialize failures to {}", self.path.display())
+            })?;
+            fp.as_file()
+                .write_all(b"\n")
+                .with_context(|| format!("failed to write to {}", self.path.display()))?;
+        }
+        fp.persist(&self.path).with_context(|| {
+            format!(
+                "failed to persist temporary failures file to {}",
+                self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_lists() {
+        let fspecs = vec![
+            ("a".to_owned(), ("list1")),
+            ("g".to_owned(), ("list2")),
+            ("m".to_owned(), ("list3")),
+            ("t".to_owned(), ("list4")),
+        ];
+        let keys = BTreeSet::from(["b", "m", "z"]);
+        let selected = lists_for_keys(fspecs, &keys)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>();
+        // "m" may also be at the end of list2, as versions of a key can span
+        // two files.
+        assert_eq!(selected, ["list1", "list2", "list3", "list4"]);
+
+        let fspecs = vec![
+            ("a".to_owned(), ("list1")),
+            ("g".to_owned(), ("list2")),
+            ("m".to_owned(), ("list3")),
+        ];
+        let keys = BTreeSet::from(["h"]);
+        let selected = lists_for_keys(fspecs, &keys)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(selected, ["list2"]);
+    }
+
+    #[test]
+    fn select_lists_with_keys_under_failed_key() {
+        let fspecs = vec![
+            ("a".to_owned(), ("list1")),
+            ("foo/bar".to_owned(), ("list2")),
+            ("foo0".to_owned(), ("list3")),
+            ("q".to_owned(), ("list4")),
+        ];
+        let keys = BTreeSet::from(["foo"]);
+        let selected = lists_for_keys(fspecs, &keys)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(selected, ["list1", "list2"]);
+    }
+
+    #[test]
+    fn store_and_load() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let manager = FailuresFileManager::new(tmpdir.path());
+        assert_eq!(manager.load().unwrap(), Vec::new());
+        let tracker = FailureTracker::default();
+        let url = S3Location::new("bucket".into(), "foo/bar".into()).with_version_id("v1");
+        tracker.ignored(
+            &url,
+            DownloadWarning::AccessDenied,
+            &anyhow::anyhow!("access denied"),
+        );
+        tracker.fatal(&url.with_key("quux"), false, &anyhow::anyhow!("disk full"));
+        let previous = vec![FailureRecord {
+            key: "foo/bar".into(),
+            version_id: Some("v1".into()),
+            url: url.to_string(),
+            kind: FailureKind::Download,
+            error: "timed out".into(),
+            ignored: false,
+        }];
+        let records = tracker.records_with(previous);
+        assert_eq!(
+            records,
+            [
+                FailureRecord {
+                    key: "foo/bar".into(),
+                    version_id: Some("v1".into()),
+                    url: "s3://bucket/foo/bar?versionId=v1".into(),
+                    kind: FailureKind::AccessDenied,
+                    error: "access denied".into(),
+                    ignored: true,
+                },
+                FailureRecord {
+                    key: "quux".into(),
+                    version_id: None,
+                    url: "s3://bucket/quux".into(),
+                    kind: FailureKind::Other,
+                    error: "disk full".into(),
+                    ignored: false,
+                },
+            ]
+        );
+        manager.store(&records).unwrap();
+        let content =
+            fs_err::read_to_string(tmpdir.path().join(".s3invsync.failures.jsonl")).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert_eq!(manager.load().unwrap(), records);
+        manager.store(&[]).unwrap();
+        assert!(!tmpdir.path().join(".s3invsync.failures.jsonl").exists());
+    }
+}
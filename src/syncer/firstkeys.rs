@@ -0,0 +1,71 @@
+use crate::consts::RESERVED_PREFIX;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// A mapping from the MD5 checksums of inventory list files to the keys on the
+/// files' first lines (or, for unsorted files that could not be merged in
+/// order, their smallest keys), or `None` for empty files
+pub(super) type FirstKeys = BTreeMap<String, Option<String>>;
+
+/// A manager for the file in the root of the backup in which the first keys of
+/// the most recently peeked-at inventory list files are cached, so that
+/// subsequent runs against the same inventory do not need to peek at them
+/// again
+pub(super) struct FirstKeysManager {
+    path: PathBuf,
+}
+
+impl FirstKeysManager {
+    pub(super) fn new(outdir: &Path) -> Self {
+        FirstKeysManager {
+            path: outdir.join(format!("{RESERVED_PREFIX}.first-keys.json")),
+        }
+    }
+
+    /// Read & parse the cache file.  If the file does not exist, return an
+    /// empty mapping.
+    pub(super) fn load(&self) -> anyhow::Result<FirstKeys> {
+        let content = match fs_err::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(FirstKeys::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to deserialize contents of {}", self.path.display()))
+    }
+
+    /// Set the content of the cache file to the serialized mapping
+    pub(super) fn store(&self, firsts: &FirstKeys) -> anyhow::Result<()> {
+        let fp = tempfile::Builder::new()
+            .prefix(&format!("{RESERVED_PREFIX}.first-keys."))
+            .tempfile_in(
+                self.path
+                    .parent()
+                    .expect("cache file path should have a parent"),
+            )
+            .with_context(|| {
+                format!(
+                    "failed to create temporary cache file for updating {}",
+                    self.path.display()
+                )
+            })?;
+        serde_json::to_writer_pretty(fp.as_file(), firsts).with_context(|| {
+            format!("failed to serialize first keys to {}", self.path.display())
+        })?;
+        fp.as_file().write_all(b"\n").with_context(|| {
+            format!(
+                "failed to write terminating newline to {}",
+                self.path.display()
+            )
+        })?;
+        fp.persist(&self.path).with_context(|| {
+            format!(
+                "failed to persist temporary cache file to {}",
+                self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
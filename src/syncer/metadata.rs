@@ -1,5 +1,5 @@
 use super::*;
-use crate::consts::{METADATA_FILENAME, RESERVED_PREFIX};
+use crate::consts::{METADATA_FILENAME, NAMES_FILENAME, RESERVED_PREFIX};
 use crate::util::make_old_filename;
 use serde::{Deserialize, Serialize};
 use std::io::{ErrorKind, Write};
@@ -12,6 +12,11 @@ pub(super) struct Metadata {
 
     /// The object's etag
     pub(super) etag: String,
+
+    /// The object's key, if it differs from the path (relative to the backup
+    /// root) at which the object is backed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) key: Option<String>,
 }
 
 impl Metadata {
@@ -158,3 +163,72 @@ impl<'a> MetadataManager<'a> {
         Ok(())
     }
 }
+
+/// Handle for reading & writing the file in which the original key components
+/// of entries in a directory are stored for entries whose local names differ
+/// from them
+pub(super) struct NamesManager<'a> {
+    /// The local directory in which the entries and the names file are located
+    dirpath: &'a Path,
+
+    /// The path to the names file
+    path: PathBuf,
+}
+
+impl<'a> NamesManager<'a> {
+    pub(super) fn new(dirpath: &'a Path) -> NamesManager<'a> {
+        NamesManager {
+            dirpath,
+            path: dirpath.join(NAMES_FILENAME),
+        }
+    }
+
+    /// Read & parse the names file.  If the file does not exist, return an
+    /// empty map.
+    pub(super) fn load(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let content = match fs_err::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::from("{}"),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to deserialize contents of {}", self.path.display()))
+    }
+
+    /// Set the content of the names file to the serialized map.  If the map
+    /// is empty, the file is deleted instead.
+    pub(super) fn store(&self, data: &BTreeMap<String, String>) -> anyhow::Result<()> {
+        if data.is_empty() {
+            if let Err(e) = fs_err::remove_file(&self.path) {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e.into());
+                }
+            }
+            return Ok(());
+        }
+        let fp = tempfile::Builder::new()
+            .prefix(&format!("{RESERVED_PREFIX}.names."))
+            .tempfile_in(self.dirpath)
+            .with_context(|| {
+                format!(
+                    "failed to create temporary file for updating {}",
+                    self.path.display()
+                )
+            })?;
+        serde_json::to_writer_pretty(fp.as_file(), data)
+            .with_context(|| format!("failed to serialize names to {}", self.path.display()))?;
+        fp.as_file().write_all(b"\n").with_context(|| {
+            format!(
+                "failed to write terminating newline to {}",
+                self.path.display()
+            )
+        })?;
+        fp.persist(&self.path).with_context(|| {
+            format!(
+                "failed to persist temporary file to {}",
+                self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
@@ -1,27 +1,51 @@
+mod archived;
+mod failures;
+mod firstkeys;
 mod metadata;
+mod prefetch;
+mod progress;
+mod recent;
+mod stats;
 mod treetracker;
+use self::archived::*;
+pub(crate) use self::archived::{ArchivePolicy, RestoreParams};
+use self::failures::*;
+use self::firstkeys::*;
 use self::metadata::*;
+use self::prefetch::*;
+use self::progress::Progress;
+pub(crate) use self::progress::ProgressMode;
+use self::recent::*;
+use self::stats::Rename;
+pub(crate) use self::stats::{RunCounts, RunStats, RunSummary, Totals};
+pub(crate) use self::treetracker::NameOptions;
 use self::treetracker::*;
+use crate::bucketmap::BucketMap;
 use crate::consts::RESERVED_PREFIX;
 use crate::errorset::ErrorSet;
-use crate::inventory::{CsvReaderError, InventoryEntry, InventoryItem, ItemDetails};
-use crate::keypath::is_special_component;
+use crate::inventory::{CsvReaderError, InventoryEntry, InventoryItem, ItemDetails, KeyMerge};
+use crate::keypath::{is_old_filename, is_reserved_name, is_temporary_name, local_name};
 use crate::manifest::{CsvManifest, FileSpec};
+use crate::metrics::Metrics;
 use crate::nursery::{Nursery, NurseryStream};
-use crate::s3::S3Client;
+use crate::s3::{
+    DownloadError, HttpDownloader, ListVersionsError, RestoreStatus, RestoreTier, S3Client,
+    S3Location,
+};
 use crate::timestamps::DateHM;
 use crate::util::*;
 use anyhow::Context;
 use futures_util::StreamExt;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::future::Future;
 use std::io::ErrorKind;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
+    Arc, Mutex, OnceLock,
 };
+use time::OffsetDateTime;
 use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
@@ -31,7 +55,101 @@ const CHANNEL_SIZE: usize = 65535;
 /// Lock guard returned by [`Syncer::lock_path()`]
 type Guard<'a> = <lockable::LockPool<PathBuf> as lockable::Lockable<PathBuf, ()>>::Guard<'a>;
 
-type ObjChannelItem = (InventoryItem, Option<Arc<Notify>>);
+/// An object to process, along with the local path (relative to the backup
+/// root) at which its key's latest version is stored and the `Completion` to
+/// signal once it's been processed
+type ObjChannelItem = (InventoryItem, LocalPath, Option<Arc<Completion>>);
+
+/// The phase of a backup run during which an object is processed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Phase {
+    /// Objects are being read from the inventory (or retried from a previous
+    /// run's failures)
+    Inventory,
+
+    /// Objects are being read from a listing of recent changes
+    Recent,
+}
+
+/// A signal from the task processing an object to the `cleanup_dir()` task
+/// for the object's directory that the object has been processed, along with
+/// whether processing succeeded
+#[derive(Debug, Default)]
+struct Completion {
+    notify: Notify,
+    failed: AtomicBool,
+}
+
+impl Completion {
+    /// Signal that the object has been processed
+    fn finish(&self, success: bool) {
+        if !success {
+            self.failed.store(true, Ordering::Release);
+        }
+        self.notify.notify_one();
+    }
+
+    /// Wait for the object to be processed, and return whether processing
+    /// succeeded
+    async fn wait(&self) -> bool {
+        self.notify.notified().await;
+        !self.failed.load(Ordering::Acquire)
+    }
+}
+
+/// User-configurable options controlling how a [`Syncer`] performs a backup
+#[derive(Debug)]
+pub(crate) struct SyncOptions {
+    /// The number of concurrent downloads jobs
+    pub(crate) jobs: NonZeroUsize,
+
+    /// The maximum number of inventory list files to download at once
+    pub(crate) prefetch_lists: NonZeroUsize,
+
+    /// Only download objects whose keys match the given regex
+    pub(crate) path_filter: Option<regex::Regex>,
+
+    /// If set, log messages about objects skipped due to `path_filter` are
+    /// emitted only for every this many objects
+    pub(crate) compress_filter_msgs: Option<NonZeroUsize>,
+
+    /// Which errors should be warned about and discarded rather than causing a
+    /// shutdown
+    pub(crate) ignore_errors: ErrorSet,
+
+    /// Buckets from which to download objects in place of the buckets listed
+    /// in the inventory
+    pub(crate) bucket_map: BucketMap,
+
+    /// If set, objects are downloaded via plain HTTP(S) using this client
+    /// rather than via the S3 API
+    pub(crate) http: Option<HttpDownloader>,
+
+    /// If set, after the inventory has been synced, object versions under
+    /// these key prefixes that are newer than the inventory are listed &
+    /// synced as well
+    pub(crate) recent_prefixes: Option<Vec<String>>,
+
+    /// How to handle archived objects
+    pub(crate) archive_policy: ArchivePolicy,
+
+    /// Parameters for restore requests made under [`ArchivePolicy::Restore`]
+    pub(crate) restore_params: RestoreParams,
+
+    /// Options controlling how key components are mapped to local names
+    pub(crate) name_options: NameOptions,
+
+    /// If true, only the objects recorded as failed by the previous run are
+    /// processed, and no directories are cleaned up
+    pub(crate) retry_failures: bool,
+
+    /// If true, errors that occur while processing individual objects do not
+    /// cause a shutdown and are returned at the end of the run instead
+    pub(crate) keep_going: bool,
+
+    /// If set, the progress of the run is displayed in the given mode
+    pub(crate) progress_mode: Option<ProgressMode>,
+}
 
 /// Object responsible for syncing an S3 bucket to a local backup by means of
 /// the bucket's S3 Inventory
@@ -51,6 +169,9 @@ pub(crate) struct Syncer {
     /// The number of concurrent downloads jobs
     jobs: NonZeroUsize,
 
+    /// The maximum number of inventory list files to download at once
+    prefetch_lists: NonZeroUsize,
+
     /// Only download objects whose keys match the given regex
     path_filter: Option<regex::Regex>,
 
@@ -78,20 +199,87 @@ pub(crate) struct Syncer {
     /// Which errors should be warned about and discarded rather than causing a
     /// shutdown
     ignore_errors: ErrorSet,
+
+    /// Buckets from which to download objects in place of the buckets listed
+    /// in the inventory
+    bucket_map: BucketMap,
+
+    /// If set, objects are downloaded via plain HTTP(S) using this client
+    /// rather than via the S3 API
+    http: Option<HttpDownloader>,
+
+    /// If set, after the inventory has been synced, object versions under
+    /// these key prefixes that are newer than the inventory are listed &
+    /// synced as well
+    recent_prefixes: Option<Vec<String>>,
+
+    /// Object versions synced by previous runs' `--recent-changes` phases that
+    /// are still newer than the inventory.  This is set at the start of
+    /// [`Syncer::run()`].
+    recent: OnceLock<RecentChanges>,
+
+    /// Object for handling & tallying archived objects
+    archived: ArchiveTracker,
+
+    /// Options controlling how key components are mapped to local names
+    name_options: NameOptions,
+
+    /// If true, only the objects recorded as failed by the previous run are
+    /// processed, and no directories are cleaned up
+    retry_failures: bool,
+
+    /// Record of the objects that failed during this run
+    failures: FailureTracker,
+
+    /// If true, errors that occur while processing individual objects do not
+    /// cause a shutdown and are instead collected in `deferred_errors`
+    keep_going: bool,
+
+    /// Errors from processing objects that were set aside due to `keep_going`
+    /// and are returned at the end of the run
+    deferred_errors: Mutex<Vec<anyhow::Error>>,
+
+    /// Temporary files found while cleaning up directories.  Files among
+    /// these that still exist once all objects have been processed are stale
+    /// leftovers from previous runs and are deleted at the end of the run.
+    temporaries: Mutex<Vec<PathBuf>>,
+
+    /// Metrics for monitoring the run, including the tally of actions taken
+    /// on objects
+    metrics: Arc<Metrics>,
+
+    /// Tally of how much of the inventory has been discovered & processed
+    progress: Arc<Progress>,
+
+    /// If set, the progress of the run is displayed in the given mode
+    progress_mode: Option<ProgressMode>,
 }
 
 impl Syncer {
-    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         client: S3Client,
         outdir: PathBuf,
         manifest_date: DateHM,
         start_time: std::time::Instant,
-        jobs: NonZeroUsize,
-        path_filter: Option<regex::Regex>,
-        compress_filter_msgs: Option<NonZeroUsize>,
-        ignore_errors: ErrorSet,
+        metrics: Arc<Metrics>,
+        options: SyncOptions,
     ) -> Arc<Syncer> {
+        let SyncOptions {
+            jobs,
+            prefetch_lists,
+            path_filter,
+            compress_filter_msgs,
+            ignore_errors,
+            bucket_map,
+            http,
+            recent_prefixes,
+            archive_policy,
+            restore_params,
+            name_options,
+            retry_failures,
+            keep_going,
+            progress_mode,
+        } = options;
         let (obj_sender, obj_receiver) = async_channel::bounded(CHANNEL_SIZE);
         Arc::new(Syncer {
             client: Arc::new(client),
@@ -99,6 +287,7 @@ impl Syncer {
             manifest_date,
             start_time,
             jobs,
+            prefetch_lists,
             path_filter,
             locks: lockable::LockPool::new(),
             token: CancellationToken::new(),
@@ -107,21 +296,150 @@ impl Syncer {
             terminated: AtomicBool::new(false),
             filterlog: FilterLogger::new(compress_filter_msgs),
             ignore_errors,
+            bucket_map,
+            http,
+            recent_prefixes,
+            recent: OnceLock::new(),
+            archived: ArchiveTracker::new(archive_policy, restore_params),
+            name_options,
+            retry_failures,
+            failures: FailureTracker::default(),
+            keep_going,
+            deferred_errors: Mutex::new(Vec::new()),
+            temporaries: Mutex::new(Vec::new()),
+            metrics,
+            progress: Arc::new(Progress::default()),
+            progress_mode,
         })
     }
 
     pub(crate) async fn run(self: Arc<Self>, manifest: CsvManifest) -> Result<(), MultiError> {
+        let failures_manager = FailuresFileManager::new(&self.outdir);
+        let previous_failures = failures_manager.load()?;
+        if self.retry_failures && previous_failures.is_empty() {
+            tracing::info!("No failed objects recorded by previous run; nothing to retry");
+            return Ok(());
+        }
         self.spawn_cltrc_listener();
-        let fspecs = self.sort_csvs_by_first_line(manifest.files).await?;
+        let recent_manager = RecentFileManager::new(&self.outdir);
+        let mut records = recent_manager.load()?;
+        records.retain(|rec| rec.last_modified > manifest.creation_timestamp);
+        if !records.is_empty() {
+            tracing::info!(
+                count = records.len(),
+                "Keeping objects synced by previous runs that are newer than the inventory"
+            );
+        }
+        self.recent
+            .set(RecentChanges::new(&records, self.name_options.portable))
+            .expect("Syncer::run() should only be called once");
+        self.archived.load_restores(&self.outdir)?;
+        self.metrics
+            .inventory_lists
+            .set(i64::try_from(manifest.files.len()).unwrap_or(i64::MAX));
+        let mut fspecs = self.sort_csvs_by_first_line(manifest.files).await?;
+        if self.retry_failures {
+            // With case-insensitive names, the local path of a failed object
+            // also depends on whether any of its ancestors' names collide
+            // with their siblings', which can be anywhere in the inventory, so
+            // all lists are read.
+            if !self.name_options.fold {
+                let keys = previous_failures
+                    .iter()
+                    .map(|rec| rec.key.as_str())
+                    .collect::<BTreeSet<_>>();
+                fspecs = lists_for_keys(fspecs, &keys);
+            }
+            tracing::info!(
+                objects = previous_failures.len(),
+                lists = fspecs.len(),
+                "Retrying objects that failed in previous run ..."
+            );
+        }
+        self.progress.set_lists(&fspecs);
+        let display = self.progress_mode.map(|mode| self.progress.display(mode));
         let (nursery, nursery_stream) = Nursery::new();
-        self.spawn_inventory_task(&nursery, fspecs);
-        self.spawn_object_tasks(&nursery);
+        if self.retry_failures {
+            self.spawn_retry_task(&nursery, fspecs, &previous_failures);
+        } else {
+            self.spawn_inventory_task(&nursery, fspecs);
+        }
+        self.spawn_object_tasks(&nursery, &self.obj_receiver, Phase::Inventory);
         drop(nursery);
-        let r = self.await_nursery(nursery_stream).await;
+        let mut r = self.await_nursery(nursery_stream).await;
+        if r.is_ok() {
+            if let Some(ref prefixes) = self.recent_prefixes {
+                r = self
+                    .sync_recent(
+                        manifest.source_bucket,
+                        manifest.creation_timestamp,
+                        prefixes,
+                    )
+                    .await
+                    .map(|new_records| {
+                        records = std::mem::take(&mut records)
+                            .into_iter()
+                            .chain(new_records)
+                            .map(|rec| ((rec.key.clone(), rec.version_id.clone()), rec))
+                            .collect::<BTreeMap<_, _>>()
+                            .into_values()
+                            .collect();
+                    });
+            }
+        }
+        let deferred = std::mem::take(
+            &mut *self
+                .deferred_errors
+                .lock()
+                .expect("deferred_errors mutex should not be poisoned"),
+        );
+        if !deferred.is_empty() {
+            tracing::error!(
+                count = deferred.len(),
+                "Some objects could not be processed; backup is incomplete"
+            );
+            r = match r {
+                Ok(()) => Err(MultiError(deferred)),
+                Err(MultiError(errors)) => {
+                    Err(MultiError(deferred.into_iter().chain(errors).collect()))
+                }
+            };
+        }
+        if let Some(display) = display {
+            display.finish();
+        }
         self.filterlog.finish();
+        self.archived.log_summary();
+        self.sweep_temporaries();
+        let archived_stored = self.archived.store(&self.outdir, r.is_ok());
+        // If the run did not complete, objects that failed previously may not
+        // have been reached, so keep their records.
+        let failures = self.failures.records_with(if r.is_ok() {
+            Vec::new()
+        } else {
+            previous_failures
+        });
+        let failures_stored = failures_manager.store(&failures);
+        if r.is_ok() {
+            archived_stored?;
+            failures_stored?;
+            recent_manager.store(&records)?;
+        } else {
+            if let Err(e) = archived_stored {
+                tracing::warn!(error = ?e, "Failed to record archived objects");
+            }
+            if let Err(e) = failures_stored {
+                tracing::warn!(error = ?e, "Failed to record failed objects");
+            }
+        }
         r
     }
 
+    /// Returns whether the backup was terminated by Ctrl-C
+    pub(crate) fn terminated(&self) -> bool {
+        self.terminated.load(Ordering::Acquire)
+    }
+
     fn spawn_cltrc_listener(self: &Arc<Self>) {
         tokio::spawn({
             let this = self.clone();
@@ -138,7 +456,7 @@ impl Syncer {
     fn spawn_inventory_task(
         self: &Arc<Self>,
         nursery: &Nursery<anyhow::Result<()>>,
-        fspecs: Vec<FileSpec>,
+        fspecs: Vec<(String, FileSpec)>,
     ) {
         let obj_sender = {
             let guard = self
@@ -154,40 +472,67 @@ impl Syncer {
         let subnursery = nursery.clone();
         nursery.spawn(
             self.until_cancelled_ok(async move {
-                let mut tracker = TreeTracker::new();
-                for spec in fspecs {
-                    let entries = this.client.download_inventory_csv(spec).await?;
-                    for entry in entries {
-                        match entry {
-                            Ok(InventoryEntry::Directory(d)) => {
-                                tracing::debug!(url = %d.url(), "Ignoring directory entry in inventory list");
+                let mut tracker = TreeTracker::new(this.name_options);
+                let mut held = HeldFiles::new();
+                let mut merge = KeyMerge::new();
+                let mut lists = ListPrefetcher::new(this.clone(), subnursery.clone(), fspecs, this.prefetch_lists);
+                loop {
+                    lists.feed(&mut merge).await?;
+                    let Some(entry) = merge.next() else {
+                        break;
+                    };
+                    this.metrics.inventory_entries.inc();
+                    match entry {
+                        Ok(InventoryEntry::Directory(d)) => {
+                            tracing::debug!(url = %d.url(), "Ignoring directory entry in inventory list");
+                        }
+                        Ok(InventoryEntry::Item(mut item)) => {
+                            if item.is_latest && this.recent().supersedes(item.key.as_ref()) {
+                                tracing::debug!(url = %item.url(), "Latest version of key is newer than inventory; treating inventory's latest version as old");
+                                item.is_latest = false;
                             }
-                            Ok(InventoryEntry::Item(item)) => {
-                                let notify = if !item.is_deleted() {
-                                    let notify = Arc::new(Notify::new());
-                                    for dir in tracker.add(&item.key, notify.clone(), item.old_filename())? {
-                                        subnursery.spawn({
-                                            this.until_cancelled_ok({
-                                                let this = this.clone();
-                                                async move { this.cleanup_dir(dir).await }
-                                            })
-                                        });
-                                    }
-                                    Some(notify)
-                                } else {
-                                    None
-                                };
-                                if obj_sender.send((item, notify)).await.is_err() {
+                            let local = tracker.local_path(&item.key);
+                            let mut ready = Vec::new();
+                            let completion = if !item.is_deleted() {
+                                let completion = Arc::new(Completion::default());
+                                for dir in tracker.add(&item.key, completion.clone(), item.old_filename(this.name_options.portable))? {
+                                    subnursery.spawn({
+                                        this.until_cancelled_ok({
+                                            let this = this.clone();
+                                            async move { this.cleanup_dir(dir).await }
+                                        })
+                                    });
+                                }
+                                ready.extend(held.release(&item.key, &tracker));
+                                Some(completion)
+                            } else {
+                                None
+                            };
+                            if item.is_latest {
+                                held.hold(item.key.clone(), local, (item, completion));
+                            } else {
+                                ready.push((local, (item, completion)));
+                            }
+                            for (local, (item, completion)) in ready {
+                                this.progress.discovered(item_bytes(&item));
+                                if obj_sender.send((item, local, completion)).await.is_err() {
                                     // Assume we're shutting down
                                     return Ok(());
                                 }
                             }
-                            Err(e) if matches!(e.source, CsvReaderError::Parse(_)) && this.ignore_errors.invalid_entry => {
-                                let e = anyhow::Error::from(e);
-                                tracing::warn!(error = ?e, "invalid entry in inventory list file; ignoring");
-                            }
-                            Err(e) => return Err(e).context("error reading from inventory list file"),
                         }
+                        Err(e) if matches!(e.source, CsvReaderError::Parse(_)) && this.ignore_errors.invalid_entry => {
+                            let e = anyhow::Error::from(e);
+                            tracing::warn!(error = ?e, "invalid entry in inventory list file; ignoring");
+                        }
+                        Err(e) => return Err(e).context("error reading from inventory list file"),
+                    }
+                }
+                for (local, (item, completion)) in held.finish() {
+                    this.progress.discovered(item_bytes(&item));
+                    if obj_sender.send((item, local, completion)).await.is_err() {
+                        // Assume we're shutting down
+                        return Ok(());
                     }
                 }
                 for dir in tracker.finish() {
@@ -210,70 +555,376 @@ impl Syncer {
         }
     }
 
-    fn spawn_object_tasks(self: &Arc<Self>, nursery: &Nursery<anyhow::Result<()>>) {
+    /// Read the inventory list files in `fspecs` and send off only the objects
+    /// listed in `failures` for processing.  Unlike
+    /// [`Syncer::spawn_inventory_task()`], no directories are cleaned up.
+    fn spawn_retry_task(
+        self: &Arc<Self>,
+        nursery: &Nursery<anyhow::Result<()>>,
+        fspecs: Vec<(String, FileSpec)>,
+        failures: &[FailureRecord],
+    ) {
+        let mut wanted = failures
+            .iter()
+            .map(|rec| (rec.key.clone(), rec.version_id.clone()))
+            .collect::<BTreeSet<_>>();
+        let obj_sender = self
+            .obj_sender
+            .lock()
+            .expect("obj_sender mutex should not be poisoned")
+            .take()
+            .expect("obj_sender should not be None");
+        let this = self.clone();
+        let subnursery = nursery.clone();
+        nursery.spawn(
+            self.until_cancelled_ok(async move {
+                // Only used for determining local paths:
+                let mut tracker = TreeTracker::new(this.name_options);
+                let mut held = HeldFiles::new();
+                let mut merge = KeyMerge::new();
+                let mut lists = ListPrefetcher::new(this.clone(), subnursery, fspecs, this.prefetch_lists);
+                loop {
+                    lists.feed(&mut merge).await?;
+                    let Some(entry) = merge.next() else {
+                        break;
+                    };
+                    this.metrics.inventory_entries.inc();
+                    match entry {
+                        Ok(InventoryEntry::Directory(_)) => (),
+                        Ok(InventoryEntry::Item(mut item)) => {
+                            let local = tracker.local_path(&item.key);
+                            let mut ready = Vec::new();
+                            if !item.is_deleted() {
+                                tracker.add(&item.key, (), item.old_filename(this.name_options.portable))?;
+                                ready.extend(held.release(&item.key, &tracker));
+                            }
+                            if wanted.remove(&(String::from(&item.key), item.version_id.clone())) {
+                                if item.is_latest && this.recent().supersedes(item.key.as_ref()) {
+                                    item.is_latest = false;
+                                }
+                                if item.is_latest {
+                                    held.hold(item.key.clone(), local, item);
+                                } else {
+                                    ready.push((local, item));
+                                }
+                            }
+                            for (local, item) in ready {
+                                if !this.retry_item(&obj_sender, item, local).await {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        Err(e) if matches!(e.source, CsvReaderError::Parse(_)) && this.ignore_errors.invalid_entry => {
+                            let e = anyhow::Error::from(e);
+                            tracing::warn!(error = ?e, "invalid entry in inventory list file; ignoring");
+                        }
+                        Err(e) => return Err(e).context("error reading from inventory list file"),
+                    }
+                }
+                for (local, item) in held.finish() {
+                    if !this.retry_item(&obj_sender, item, local).await {
+                        return Ok(());
+                    }
+                }
+                for (key, version_id) in wanted {
+                    tracing::info!(key, version_id, "Previously failed object is no longer in the inventory; not retrying");
+                }
+                Ok(())
+            })
+        );
+    }
+
+    /// Send `item`, a previously failed object to be stored at `local`, off
+    /// for processing.  Returns `false` if the object tasks have shut down.
+    async fn retry_item(
+        &self,
+        obj_sender: &async_channel::Sender<ObjChannelItem>,
+        item: InventoryItem,
+        local: LocalPath,
+    ) -> bool {
+        tracing::debug!(url = %item.url(), "Retrying previously failed object");
+        self.progress.discovered(item_bytes(&item));
+        obj_sender.send((item, local, None)).await.is_ok()
+    }
+
+    fn spawn_object_tasks(
+        self: &Arc<Self>,
+        nursery: &Nursery<anyhow::Result<()>>,
+        receiver: &async_channel::Receiver<ObjChannelItem>,
+        phase: Phase,
+    ) {
         for _ in 0..self.jobs.get() {
             let this = self.clone();
-            let recv = self.obj_receiver.clone();
+            let recv = receiver.clone();
             nursery.spawn(async move {
-                while let Ok((item, notify)) = recv.recv().await {
+                while let Ok((item, local, completion)) = recv.recv().await {
                     if this.token.is_cancelled() {
                         return Ok(());
                     }
-                    let r = Box::pin(this.process_item(item)).await;
-                    if r.is_ok() {
-                        if let Some(n) = notify {
-                            n.notify_one();
+                    this.metrics.jobs_in_flight.inc();
+                    let size = item_bytes(&item);
+                    let url = item.url();
+                    let is_old = !item.is_latest;
+                    let r = Box::pin(this.process_item(item, local, phase)).await;
+                    this.metrics.jobs_in_flight.dec();
+                    this.progress.completed(size);
+                    if let Some(c) = completion {
+                        c.finish(r.is_ok());
+                    }
+                    if let Err(e) = r {
+                        this.failures.fatal(&url, is_old, &e);
+                        if !this.keep_going {
+                            return Err(e);
                         }
+                        tracing::error!(error = ?e, "Failed to process object; continuing due to --keep-going");
+                        this.deferred_errors
+                            .lock()
+                            .expect("deferred_errors mutex should not be poisoned")
+                            .push(e);
                     }
-                    // If r.is_err(), the cleanup_dir() task at the other end
-                    // of the Notify will be cancelled due to everything
-                    // shutting down.
-                    r?;
                 }
                 Ok(())
             });
         }
     }
 
+    /// List all object versions in `bucket` under `prefixes` that were
+    /// modified after `since` and sync them, returning records of the
+    /// versions that were synced successfully.
+    ///
+    /// `prefixes` must be sorted, and none may be a prefix of another, so
+    /// that the combined listings are in sorted order.
+    async fn sync_recent(
+        self: &Arc<Self>,
+        bucket: String,
+        since: OffsetDateTime,
+        prefixes: &[String],
+    ) -> Result<Vec<RecentRecord>, MultiError> {
+        tracing::info!(%since, "Syncing objects modified since inventory was created ...");
+        let (nursery, nursery_stream) = Nursery::new();
+        let (obj_sender, obj_receiver) = async_channel::bounded(CHANNEL_SIZE);
+        let records = Arc::new(Mutex::new(Vec::new()));
+        nursery.spawn(self.until_cancelled_ok({
+            let this = self.clone();
+            let records = records.clone();
+            let prefixes = prefixes.to_vec();
+            let subnursery = nursery.clone();
+            async move {
+                // Only used for determining local paths:
+                let mut tracker = TreeTracker::new(this.name_options);
+                let mut held = HeldFiles::new();
+                for prefix in prefixes {
+                    let mut stream = this.client.list_object_versions(&bucket, &prefix);
+                    while let Some(r) = stream.next().await {
+                        match r {
+                            Ok(InventoryEntry::Directory(d)) => {
+                                tracing::debug!(url = %d.url(), "Ignoring directory object in version listing");
+                            }
+                            Ok(InventoryEntry::Item(item)) => {
+                                let local = tracker.local_path(&item.key);
+                                let mut ready = Vec::new();
+                                if !item.is_deleted() {
+                                    tracker.add(&item.key, (), item.old_filename(this.name_options.portable))?;
+                                    ready.extend(held.release(&item.key, &tracker));
+                                }
+                                if item.is_latest {
+                                    held.hold(item.key.clone(), local, item);
+                                } else {
+                                    ready.push((local, item));
+                                }
+                                for (local, item) in ready {
+                                    if !this.send_recent(&subnursery, &obj_sender, &records, since, item, local).await {
+                                        return Ok(());
+                                    }
+                                }
+                            }
+                            Err(e @ ListVersionsError::Key { .. })
+                                if this.ignore_errors.invalid_entry =>
+                            {
+                                let e = anyhow::Error::from(e);
+                                tracing::warn!(error = ?e, "invalid entry in version listing; ignoring");
+                            }
+                            Err(e) => return Err(e).context("error listing object versions"),
+                        }
+                    }
+                }
+                for (local, item) in held.finish() {
+                    if !this.send_recent(&subnursery, &obj_sender, &records, since, item, local).await {
+                        return Ok(());
+                    }
+                }
+                Ok(())
+            }
+        }));
+        self.spawn_object_tasks(&nursery, &obj_receiver, Phase::Recent);
+        drop(nursery);
+        self.await_nursery(nursery_stream).await?;
+        let records = std::mem::take(
+            &mut *records
+                .lock()
+                .expect("records mutex should not be poisoned"),
+        );
+        Ok(records)
+    }
+
+    /// If `item`, whose latest version is to be stored at `local`, was
+    /// modified after `since`, send it off for processing, and add a record of
+    /// it to `records` once it's been processed successfully.  Returns `false`
+    /// if the object tasks have shut down.
+    async fn send_recent(
+        self: &Arc<Self>,
+        nursery: &Nursery<anyhow::Result<()>>,
+        obj_sender: &async_channel::Sender<ObjChannelItem>,
+        records: &Arc<Mutex<Vec<RecentRecord>>>,
+        since: OffsetDateTime,
+        item: InventoryItem,
+        local: LocalPath,
+    ) -> bool {
+        let Some(rec) = RecentRecord::for_item(&item, &local, self.name_options.portable)
+            .filter(|rec| rec.last_modified > since)
+        else {
+            return true;
+        };
+        if let Some(path) = self.local_clash(&item, &local) {
+            tracing::warn!(url = %item.url(), path = %path.display(), "Backing up object would replace an existing local entry of a different type; leaving object for a later inventory to back up");
+            return true;
+        }
+        let completion = Arc::new(Completion::default());
+        nursery.spawn(self.until_cancelled_ok({
+            let completion = completion.clone();
+            let records = records.clone();
+            async move {
+                if completion.wait().await {
+                    records
+                        .lock()
+                        .expect("records mutex should not be poisoned")
+                        .push(rec);
+                }
+                Ok(())
+            }
+        }));
+        self.progress.discovered(item_bytes(&item));
+        obj_sender
+            .send((item, local, Some(completion)))
+            .await
+            .is_ok()
+    }
+
+    /// Returns the path to an existing entry in the backup that would have to
+    /// be replaced with an entry of a different type in order to back up
+    /// `item` at `local`, if any.
+    ///
+    /// When syncing recent changes, local paths are computed from the version
+    /// listing alone, which may not include all of the keys that determined
+    /// the current layout of the backup (e.g., a file `foo` when listing
+    /// `foo/`).  Such entries are left for the next inventory to sort out.
+    fn local_clash(&self, item: &InventoryItem, local: &LocalPath) -> Option<PathBuf> {
+        let mut path = self.outdir.clone();
+        if let Some(ref dirname) = local.dirname {
+            for name in dirname.split('/') {
+                path.push(name);
+                match fs_err::symlink_metadata(&path) {
+                    Ok(md) if !md.is_dir() => return Some(path),
+                    Ok(_) => (),
+                    Err(_) => return None,
+                }
+            }
+        }
+        if item.is_latest && !item.is_deleted() {
+            path.push(&local.filename);
+            if fs_err::symlink_metadata(&path).is_ok_and(|md| md.is_dir()) {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    /// Returns the records of object versions synced by previous runs'
+    /// `--recent-changes` phases
+    fn recent(&self) -> &RecentChanges {
+        self.recent
+            .get()
+            .expect("recent changes should be loaded before syncing")
+    }
+
     /// Fetch the first line of each inventory list file in `specs` and sort
-    /// the list by the keys in those lines
+    /// the list by the keys in those lines.  Each spec is returned paired with
+    /// the key from its first line.  Empty files are omitted.
+    ///
+    /// The first keys are cached by the files' MD5 checksums in the root of
+    /// the backup, and files whose first keys are already cached are not
+    /// fetched.
     async fn sort_csvs_by_first_line(
         self: &Arc<Self>,
         specs: Vec<FileSpec>,
-    ) -> Result<Vec<FileSpec>, MultiError> {
-        tracing::info!("Peeking at inventory lists in order to sort by first line ...");
-        let (nursery, nursery_stream) = Nursery::new();
-        let mut receiver = {
-            let specs = Arc::new(Mutex::new(specs));
-            let (output_sender, output_receiver) = tokio::sync::mpsc::channel(CHANNEL_SIZE);
-            for _ in 0..self.jobs.get() {
-                let clnt = self.client.clone();
-                let specs = specs.clone();
-                let sender = output_sender.clone();
-                nursery.spawn(self.until_cancelled_ok(async move {
-                    while let Some(fspec) = {
-                        let mut guard = specs.lock().expect("specs mutex should not be poisoned");
-                        guard.pop()
-                    } {
-                        if let Some(entry) = clnt.peek_inventory_csv(&fspec).await? {
+    ) -> Result<Vec<(String, FileSpec)>, MultiError> {
+        let cache_manager = FirstKeysManager::new(&self.outdir);
+        let cache = cache_manager.load()?;
+        let mut new_cache = FirstKeys::new();
+        let mut firsts = Vec::new();
+        let mut to_peek = Vec::new();
+        for fspec in specs {
+            match cache.get(&fspec.md5_checksum) {
+                Some(first) => {
+                    new_cache.insert(fspec.md5_checksum.clone(), first.clone());
+                    if let Some(key) = first {
+                        firsts.push((key.clone(), fspec));
+                    }
+                }
+                None => to_peek.push(fspec),
+            }
+        }
+        if to_peek.is_empty() {
+            tracing::info!("Using cached first lines of inventory lists");
+        } else {
+            tracing::info!(
+                count = to_peek.len(),
+                "Peeking at inventory lists in order to sort by first line ..."
+            );
+            let (nursery, nursery_stream) = Nursery::new();
+            let mut receiver = {
+                let specs = Arc::new(Mutex::new(to_peek));
+                let (output_sender, output_receiver) = tokio::sync::mpsc::channel(CHANNEL_SIZE);
+                for _ in 0..self.jobs.get() {
+                    let clnt = self.client.clone();
+                    let specs = specs.clone();
+                    let sender = output_sender.clone();
+                    nursery.spawn(self.until_cancelled_ok(async move {
+                        while let Some(fspec) = {
+                            let mut guard =
+                                specs.lock().expect("specs mutex should not be poisoned");
+                            guard.pop()
+                        } {
+                            let entry = clnt.peek_inventory_csv(&fspec).await?;
                             if sender.send((fspec, entry)).await.is_err() {
                                 // Assume we're shutting down
                                 return Ok(());
                             }
                         }
-                    }
-                    Ok(())
-                }));
+                        Ok(())
+                    }));
+                }
+                output_receiver
+            };
+            drop(nursery);
+            while let Some((fspec, entry)) = receiver.recv().await {
+                let first = entry.map(|e| e.key().to_owned());
+                new_cache.insert(fspec.md5_checksum.clone(), first.clone());
+                if let Some(key) = first {
+                    firsts.push((key, fspec));
+                }
             }
-            output_receiver
-        };
-        drop(nursery);
-        let mut firsts2fspecs = BTreeMap::new();
-        while let Some((fspec, entry)) = receiver.recv().await {
-            firsts2fspecs.insert(entry.key().to_owned(), fspec);
+            self.await_nursery(nursery_stream).await?;
         }
-        self.await_nursery(nursery_stream).await?;
-        Ok(firsts2fspecs.into_values().collect())
+        if new_cache != cache {
+            if let Err(e) = cache_manager.store(&new_cache) {
+                tracing::warn!(error = ?e, "Failed to cache first lines of inventory lists");
+            }
+        }
+        // Multiple files can start with the same key, so break ties by the
+        // files' own keys for a deterministic order.
+        firsts.sort_by(|(k1, fs1), (k2, fs2)| (k1, &fs1.key).cmp(&(k2, &fs2.key)));
+        Ok(firsts)
     }
 
     /// Run the given future to completion, cancelling it if `token` is
@@ -329,10 +980,16 @@ impl Syncer {
     }
 
     #[tracing::instrument(skip_all, fields(url = %item.url()))]
-    async fn process_item(&self, item: InventoryItem) -> anyhow::Result<()> {
+    async fn process_item(
+        &self,
+        item: InventoryItem,
+        local: LocalPath,
+        phase: Phase,
+    ) -> anyhow::Result<()> {
         if let Some(ref rgx) = self.path_filter {
             if !rgx.is_match(&item.key) {
                 self.filterlog.log();
+                self.metrics.run.filtered(item_bytes(&item));
                 return Ok(());
             }
         }
@@ -340,18 +997,34 @@ impl Syncer {
 
         let etag = match item.details {
             ItemDetails::Present { ref etag, .. } => etag,
+            // During the inventory phase, the backups of deleted keys are
+            // instead dealt with by `cleanup_dir()`.
+            ItemDetails::Deleted if item.is_latest && phase == Phase::Recent => {
+                tracing::info!("Object is latest version of key and is a delete marker");
+                self.metrics.run.delete_marker();
+                return self.retire_latest(&item, &local).await;
+            }
             ItemDetails::Deleted => {
                 tracing::info!("Object is delete marker; not doing anything");
+                self.metrics.run.delete_marker();
                 return Ok(());
             }
         };
+        let filename = &*local.filename;
+        // The base name for "old" filenames does not depend on collisions
+        // with other keys:
+        let basename = local_name(item.key.name(), self.name_options.portable);
+        let local_path = match local.dirname {
+            Some(ref p) => format!("{p}/{filename}"),
+            None => filename.to_owned(),
+        };
         let md = Metadata {
             version_id: item.version_id.clone(),
             etag: etag.to_owned(),
+            key: (local_path != item.key.as_ref()).then(|| String::from(&item.key)),
         };
 
-        let (dirname, filename) = item.key.split();
-        let parentdir = if let Some(p) = dirname {
+        let parentdir = if let Some(ref p) = local.dirname {
             let pd = self.outdir.join(p);
             tracing::trace!(path = %pd.display(), "Creating output directory");
             force_create_dir_all(&self.outdir, p.split('/'))?;
@@ -372,11 +1045,13 @@ impl Syncer {
                     .with_context(|| format!("failed to get local metadata for {}", item.url()))?;
                 if md == current_md {
                     tracing::info!(path = %latest_path.display(), "Backup path already exists and metadata matches; doing nothing");
+                    self.metrics.run.unchanged(item_bytes(&item));
                 } else {
                     tracing::info!(path = %latest_path.display(), "Backup path already exists but metadata does not match; renaming current file and downloading correct version");
                     self.move_object_file(
                         &latest_path,
-                        &parentdir.join(current_md.old_filename(filename)),
+                        &parentdir.join(current_md.old_filename(&basename)),
+                        Rename::LatestToOld,
                     )?;
                     if self
                         .download_item(&item, &parentdir, latest_path, false)
@@ -392,10 +1067,10 @@ impl Syncer {
                     }
                 }
             } else {
-                let oldpath = parentdir.join(md.old_filename(filename));
+                let oldpath = parentdir.join(md.old_filename(&basename));
                 if ensure_file(&oldpath).await? {
                     tracing::info!(path = %latest_path.display(), oldpath = %oldpath.display(), "Backup path does not exist but \"old\" path does; will rename");
-                    self.move_object_file(&oldpath, &latest_path)?;
+                    self.move_object_file(&oldpath, &latest_path, Rename::OldToLatest)?;
                     mdmanager.set(md).await.with_context(|| {
                         format!("failed to set local metadata for {}", item.url())
                     })?;
@@ -413,9 +1088,10 @@ impl Syncer {
             }
         } else {
             tracing::info!("Object is old version of key");
-            let oldpath = parentdir.join(md.old_filename(filename));
+            let oldpath = parentdir.join(md.old_filename(&basename));
             if ensure_file(&oldpath).await? {
                 tracing::info!(path = %oldpath.display(), "Backup path already exists; doing nothing");
+                self.metrics.run.unchanged(item_bytes(&item));
             } else {
                 let latest_path = parentdir.join(filename);
                 let guard = self.lock_path(latest_path.clone()).await;
@@ -429,7 +1105,7 @@ impl Syncer {
                         })?
                 {
                     tracing::info!(path = %oldpath.display(), "Backup path does not exist, but \"latest\" file has matching metadata; renaming \"latest\" file");
-                    self.move_object_file(&latest_path, &oldpath)?;
+                    self.move_object_file(&latest_path, &oldpath, Rename::LatestToOld)?;
                     mdmanager.delete().await.with_context(|| {
                         format!(
                             "failed to delete local metadata for latest version of {}",
@@ -450,9 +1126,46 @@ impl Syncer {
         Ok(())
     }
 
-    fn move_object_file(&self, src: &Path, dest: &Path) -> std::io::Result<()> {
+    /// If a backup of the latest version of `item`'s key exists, rename it to
+    /// its "old" filename, as the key's latest version is now a delete marker
+    async fn retire_latest(&self, item: &InventoryItem, local: &LocalPath) -> anyhow::Result<()> {
+        let filename = &*local.filename;
+        let basename = local_name(item.key.name(), self.name_options.portable);
+        let parentdir = match local.dirname {
+            Some(ref p) => self.outdir.join(p),
+            None => self.outdir.clone(),
+        };
+        let latest_path = parentdir.join(filename);
+        let _guard = self.lock_path(latest_path.clone()).await;
+        if fs_err::symlink_metadata(&latest_path).is_ok_and(|md| md.is_file()) {
+            let mdmanager = FileMetadataManager::new(self, &parentdir, filename);
+            let current_md = mdmanager
+                .get()
+                .await
+                .with_context(|| format!("failed to get local metadata for {}", item.url()))?;
+            tracing::info!(path = %latest_path.display(), "Renaming backup of previous latest version to \"old\" path");
+            self.move_object_file(
+                &latest_path,
+                &parentdir.join(current_md.old_filename(&basename)),
+                Rename::LatestToOld,
+            )?;
+            mdmanager
+                .delete()
+                .await
+                .with_context(|| format!("failed to delete local metadata for {}", item.url()))?;
+        } else {
+            tracing::info!("No backup of previous latest version exists; doing nothing");
+        }
+        tracing::info!("Finished processing object");
+        Ok(())
+    }
+
+    fn move_object_file(&self, src: &Path, dest: &Path, rename: Rename) -> std::io::Result<()> {
         tracing::debug!(src = %src.display(), dest = %dest.display(), "Moving object file");
-        fs_err::rename(src, dest)
+        let size = fs_err::symlink_metadata(src).map_or(0, |md| md.len());
+        fs_err::rename(src, dest)?;
+        self.metrics.run.renamed(rename, size);
+        Ok(())
     }
 
     #[tracing::instrument(skip_all)]
@@ -463,6 +1176,10 @@ impl Syncer {
         path: PathBuf,
         is_old: bool,
     ) -> anyhow::Result<bool> {
+        if !self.archived.check(self, item).await? {
+            self.metrics.run.archived(item_bytes(item));
+            return Ok(false);
+        }
         tracing::trace!("Opening temporary output file");
         let outfile = tempfile::Builder::new()
             .prefix(&format!("{RESERVED_PREFIX}.download."))
@@ -472,11 +1189,7 @@ impl Syncer {
             })?;
         match self
             .token
-            .run_until_cancelled(self.client.download_object(
-                &item.url(),
-                item.details.md5_digest(),
-                outfile.as_file(),
-            ))
+            .run_until_cancelled(self.download_object(item, outfile.as_file()))
             .await
         {
             Some(Ok(())) => {
@@ -491,6 +1204,9 @@ impl Syncer {
                     fp.set_modified(mtime.into())
                         .with_context(|| format!("failed to set mtime on {}", path.display()))?;
                 }
+                self.metrics
+                    .run
+                    .downloaded(is_old, fp.metadata().map_or(0, |md| md.len()));
                 Ok(true)
             }
             Some(Err(e)) => {
@@ -499,6 +1215,10 @@ impl Syncer {
                 {
                     let e = anyhow::Error::from(e);
                     tracing::warn!(error = ?e, "{warning}; ignoring");
+                    self.failures.ignored(&item.url(), warning, &e);
+                    self.metrics
+                        .run
+                        .ignored(warning, item.url().to_string(), format!("{e:#}"));
                     Ok(false)
                 } else {
                     let e = anyhow::Error::from(e);
@@ -518,6 +1238,91 @@ impl Syncer {
         }
     }
 
+    /// Download the object described by `item` to `outfile`.  If the object's
+    /// bucket is remapped by `--source-bucket-map`, the object is downloaded
+    /// from the mapped bucket, falling back to the original bucket if the
+    /// object is not found there.
+    async fn download_object(
+        &self,
+        item: &InventoryItem,
+        outfile: &std::fs::File,
+    ) -> Result<(), DownloadError> {
+        let url = item.url();
+        let md5_digest = item.details.md5_digest();
+        if let Some(alt_url) = self.bucket_map.remap(&url) {
+            match self.fetch_object(&alt_url, md5_digest, outfile).await {
+                Err(e) if e.is_404() => {
+                    tracing::info!(alt_url = %alt_url, "Object not found in mapped bucket; falling back to original bucket");
+                }
+                r => return r,
+            }
+        }
+        self.fetch_object(&url, md5_digest, outfile).await
+    }
+
+    /// Download the object at `url` to `outfile` using either plain HTTP(S)
+    /// (if `--http-base-url` was given) or the S3 API
+    async fn fetch_object(
+        &self,
+        url: &S3Location,
+        md5_digest: Option<&str>,
+        outfile: &std::fs::File,
+    ) -> Result<(), DownloadError> {
+        if let Some(ref http) = self.http {
+            http.download_object(url, md5_digest, outfile).await
+        } else {
+            self.client.download_object(url, md5_digest, outfile).await
+        }
+    }
+
+    /// Return the restoration status of the archived object at `url`.  The
+    /// status is checked via the S3 API in the bucket from which the object
+    /// would be downloaded by [`Syncer::download_object()`].
+    async fn get_restore_status(&self, url: &S3Location) -> anyhow::Result<RestoreStatus> {
+        if let Some(alt_url) = self.bucket_map.remap(url) {
+            match self.client.get_restore_status(&alt_url).await {
+                Err(e) if e.is_404() => {
+                    tracing::info!(alt_url = %alt_url, "Object not found in mapped bucket; checking original bucket");
+                }
+                r => {
+                    return r.with_context(|| {
+                        format!("failed to check restoration status of {alt_url}")
+                    })
+                }
+            }
+        }
+        self.client
+            .get_restore_status(url)
+            .await
+            .with_context(|| format!("failed to check restoration status of {url}"))
+    }
+
+    /// Request restoration of the archived object at `url`, returning the
+    /// object's restoration status afterwards.  The request is sent via the S3
+    /// API to the bucket from which the object would be downloaded by
+    /// [`Syncer::download_object()`].
+    async fn restore_object(
+        &self,
+        url: &S3Location,
+        tier: RestoreTier,
+        days: Option<i32>,
+    ) -> anyhow::Result<RestoreStatus> {
+        if let Some(alt_url) = self.bucket_map.remap(url) {
+            match self.client.restore_object(&alt_url, tier, days).await {
+                Err(e) if e.is_404() => {
+                    tracing::info!(alt_url = %alt_url, "Object not found in mapped bucket; requesting restore from original bucket");
+                }
+                r => {
+                    return r.with_context(|| format!("failed to request restoration of {alt_url}"))
+                }
+            }
+        }
+        self.client
+            .restore_object(url, tier, days)
+            .await
+            .with_context(|| format!("failed to request restoration of {url}"))
+    }
+
     #[tracing::instrument(skip_all, fields(path = %dlfile.display()))]
     fn cleanup_download_path(
         &self,
@@ -535,6 +1340,26 @@ impl Syncer {
         Ok(())
     }
 
+    /// Delete the temporary files found while cleaning up directories that
+    /// still exist.  This must only be called once all objects have been
+    /// processed, at which point any remaining temporary files cannot belong
+    /// to this run, and the run lock ensures they do not belong to any other.
+    fn sweep_temporaries(&self) {
+        let temporaries = std::mem::take(
+            &mut *self
+                .temporaries
+                .lock()
+                .expect("temporaries mutex should not be poisoned"),
+        );
+        let tally = SweepTally::default();
+        for p in temporaries {
+            if fs_err::symlink_metadata(&p).is_ok() {
+                tally.delete(&p);
+            }
+        }
+        tally.log_summary();
+    }
+
     async fn lock_path(&self, path: PathBuf) -> Guard<'_> {
         tracing::trace!(path = %path.display(), "Acquiring internal lock for path");
         self.locks.async_lock(path).await
@@ -557,14 +1382,26 @@ impl Syncer {
     }
 
     #[tracing::instrument(skip_all, fields(dirpath = %dir.path().unwrap_or("<root>")))]
-    async fn cleanup_dir(&self, dir: Directory<Arc<Notify>>) -> anyhow::Result<()> {
-        let mut notifiers = Vec::new();
-        let dir = dir.map(|n| {
-            notifiers.push(n);
+    async fn cleanup_dir(&self, dir: Directory<Arc<Completion>>) -> anyhow::Result<()> {
+        let mut completions = Vec::new();
+        let dir = dir.map(|c| {
+            completions.push(c);
         });
-        for n in notifiers {
-            n.notified().await;
+        let mut all_succeeded = true;
+        for c in completions {
+            all_succeeded &= c.wait().await;
+        }
+        if !all_succeeded {
+            tracing::warn!("Not cleaning up directory, as an object in it failed to be processed");
+            return Ok(());
         }
+        for key in dir.collisions() {
+            tracing::warn!(
+                key,
+                "Key collides with another key when ignoring case & Unicode normalization; backing up under disambiguated name"
+            );
+        }
+        let recent = self.recent();
         let dirpath = match dir.path() {
             Some(p) => self.outdir.join(p),
             None => self.outdir.clone(),
@@ -581,12 +1418,32 @@ impl Syncer {
             let entry = entry?;
             let is_dir = entry.file_type()?.is_dir();
             let to_delete = match entry.file_name().to_str() {
+                Some(name) if recent.is_protected(dir.path(), name) => {
+                    tracing::debug!(
+                        name,
+                        "Entry was synced from a listing newer than the inventory; keeping"
+                    );
+                    false
+                }
+                Some(name) if !is_dir && is_temporary_name(name) => {
+                    // Objects in this directory that are not tracked by
+                    // `completions` (e.g., delete markers) may still be in the
+                    // middle of updating files here, so temporary files can
+                    // only be judged stale once the run is over.
+                    self.temporaries
+                        .lock()
+                        .expect("temporaries mutex should not be poisoned")
+                        .push(entry.path());
+                    false
+                }
                 Some(name) => {
                     if is_dir {
-                        !dir.contains_dir(name)
+                        // Reserved directories (e.g., the default `--tmpdir`)
+                        // are managed elsewhere.
+                        !dir.contains_dir(name) && !is_reserved_name(name)
                     } else {
-                        let b = !dir.contains_file(name) && !name.starts_with(RESERVED_PREFIX);
-                        if b && !is_special_component(name) {
+                        let b = !dir.contains_file(name) && !is_reserved_name(name);
+                        if b && !is_old_filename(name) {
                             dbdeletions.push(name.to_owned());
                         }
                         b
@@ -604,14 +1461,21 @@ impl Syncer {
         }
         for p in files_to_delete {
             tracing::debug!(path = %p.display(), "File does not belong in backup; deleting");
-            if let Err(e) = fs_err::remove_file(&p) {
-                tracing::warn!(error = %e, path = %p.display(), "Failed to delete file");
+            let size = fs_err::symlink_metadata(&p).map_or(0, |md| md.len());
+            match fs_err::remove_file(&p) {
+                Ok(()) => self.metrics.run.deleted_file(size),
+                Err(e) => {
+                    tracing::warn!(error = %e, path = %p.display(), "Failed to delete file");
+                }
             }
         }
         for p in dirs_to_delete {
             tracing::debug!(path = %p.display(), "Directory does not belong in backup; deleting");
-            if let Err(e) = fs_err::tokio::remove_dir_all(&p).await {
-                tracing::warn!(error = %e, path = %p.display(), "Failed to delete directory");
+            match fs_err::tokio::remove_dir_all(&p).await {
+                Ok(()) => self.metrics.run.deleted_directory(),
+                Err(e) => {
+                    tracing::warn!(error = %e, path = %p.display(), "Failed to delete directory");
+                }
             }
         }
         if !dbdeletions.is_empty() {
@@ -622,10 +1486,21 @@ impl Syncer {
             }
             manager.store(data)?;
         }
+        let names = NamesManager::new(&dirpath);
+        if names.load()? != *dir.names() {
+            names.store(dir.names())?;
+        }
         Ok(())
     }
 }
 
+/// Returns the size of `item` in bytes, or 0 if it is not known
+fn item_bytes(item: &InventoryItem) -> u64 {
+    item.size()
+        .and_then(|size| u64::try_from(size).ok())
+        .unwrap_or(0)
+}
+
 /// An emitter of log messages about objects skipped due to `--path-filter`
 #[derive(Debug)]
 enum FilterLogger {
@@ -692,3 +1567,422 @@ impl FilterLogger {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::s3::fake::FakeS3;
+    use flate2::{write::GzEncoder, Compression};
+    use rstest::rstest;
+    use std::io::Write;
+
+    /// The name of the bucket being backed up in tests
+    static SOURCE: &str = "source";
+
+    /// The name of the bucket containing the inventory in tests
+    static INVENTORIES: &str = "inventories";
+
+    /// The name of the bucket to which the source bucket is mapped in tests
+    /// of `--source-bucket-map`
+    static REPLICA: &str = "replica";
+
+    static SCHEMA: &str = "Bucket, Key, VersionId, IsLatest, IsDeleteMarker, Size, LastModifiedDate, ETag, StorageClass";
+
+    /// A local backup of a [`FakeS3`] bucket
+    struct TestBackup {
+        server: FakeS3,
+        tmpdir: tempfile::TempDir,
+        archived: ArchivePolicy,
+        bucket_map: BucketMap,
+
+        /// Whether to download objects via plain HTTP rather than the S3 API
+        use_http: bool,
+
+        /// Whether to only retry the objects that failed in the previous run
+        retry_failures: bool,
+    }
+
+    impl TestBackup {
+        async fn new() -> TestBackup {
+            let tmpdir = tempfile::tempdir().unwrap();
+            fs_err::create_dir(tmpdir.path().join("backup")).unwrap();
+            TestBackup {
+                server: FakeS3::start().await,
+                tmpdir,
+                archived: ArchivePolicy::default(),
+                bucket_map: BucketMap::default(),
+                use_http: false,
+                retry_failures: false,
+            }
+        }
+
+        fn outdir(&self) -> PathBuf {
+            self.tmpdir.path().join("backup")
+        }
+
+        /// Store an object in the source bucket and return the inventory
+        /// list row for it
+        fn put_object(&self, key: &str, version_id: &str, body: &str) -> String {
+            let etag = self.server.put(SOURCE, key, version_id, body.as_bytes());
+            format!(
+                "{SOURCE},{key},{version_id},true,false,{},2025-01-01T00:00:00.000Z,{etag},STANDARD",
+                body.len()
+            )
+        }
+
+        /// Store an object in the `GLACIER` storage class in `bucket` and
+        /// return the inventory list row for it as an object in the source
+        /// bucket
+        fn put_archived(&self, bucket: &str, key: &str, version_id: &str, body: &str) -> String {
+            let etag = self.server.put(bucket, key, version_id, body.as_bytes());
+            self.server.archive(bucket, key, version_id);
+            format!(
+                "{SOURCE},{key},{version_id},true,false,{},2025-01-01T00:00:00.000Z,{etag},GLACIER",
+                body.len()
+            )
+        }
+
+        /// Store an inventory list file with the given rows in the inventory
+        /// bucket and return a `FileSpec` for it
+        fn put_list(&self, key: &str, rows: &[&str]) -> FileSpec {
+            let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+            for row in rows {
+                writeln!(enc, "{row}").unwrap();
+            }
+            let data = enc.finish().unwrap();
+            let md5_checksum = self.server.put(INVENTORIES, key, "null", &data);
+            FileSpec {
+                key: key.to_owned(),
+                size: i64::try_from(data.len()).unwrap(),
+                md5_checksum,
+                file_schema: SCHEMA.parse().unwrap(),
+            }
+        }
+
+        /// Run a backup of the inventory consisting of the given list files
+        async fn run(&self, files: Vec<FileSpec>) -> Result<(), MultiError> {
+            self.run_with(files, None).await
+        }
+
+        /// Run a backup of the inventory consisting of the given list files,
+        /// followed by a sync of recent changes under the given prefixes
+        async fn run_with(
+            &self,
+            files: Vec<FileSpec>,
+            recent_prefixes: Option<Vec<String>>,
+        ) -> Result<(), MultiError> {
+            let client = S3Client::for_fake(
+                &self.server,
+                S3Location::new(INVENTORIES.into(), String::new()),
+                None,
+                self.tmpdir.path(),
+            );
+            let syncer = Syncer::new(
+                client,
+                self.outdir(),
+                "2025-01-02T00-00Z".parse().unwrap(),
+                std::time::Instant::now(),
+                Arc::new(Metrics::default()),
+                SyncOptions {
+                    jobs: NonZeroUsize::new(2).unwrap(),
+                    prefetch_lists: NonZeroUsize::new(1).unwrap(),
+                    path_filter: None,
+                    compress_filter_msgs: None,
+                    ignore_errors: ErrorSet::default(),
+                    bucket_map: self.bucket_map.clone(),
+                    http: self.use_http.then(|| {
+                        let base_url = format!("{}/{{bucket}}", self.server.endpoint());
+                        HttpDownloader::new(base_url.parse().unwrap(), false).unwrap()
+                    }),
+                    recent_prefixes,
+                    archive_policy: self.archived,
+                    restore_params: RestoreParams {
+                        tier: RestoreTier::default(),
+                        days: 1,
+                    },
+                    name_options: NameOptions::default(),
+                    retry_failures: self.retry_failures,
+                    keep_going: false,
+                    progress_mode: None,
+                },
+            );
+            syncer
+                .run(CsvManifest {
+                    source_bucket: SOURCE.into(),
+                    creation_timestamp: time::macros::datetime!(2025-01-02 00:00 UTC),
+                    files,
+                })
+                .await
+        }
+
+        fn read(&self, path: &str) -> String {
+            fs_err::read_to_string(self.outdir().join(path)).unwrap()
+        }
+
+        fn exists(&self, path: &str) -> bool {
+            self.outdir().join(path).exists()
+        }
+
+        /// Return the pending restores recorded in the backup
+        fn pending_restores(&self) -> Vec<PendingRestore> {
+            serde_json::from_str(&self.read(".s3invsync.restores.json")).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn unsorted_list_overlapping_another() {
+        let backup = TestBackup::new().await;
+        let rows = ["a", "b", "c", "d", "e"]
+            .map(|key| backup.put_object(key, "v1", &format!("Contents of {key}\n")));
+        let [a, b, c, d, e] = rows.each_ref().map(String::as_str);
+        let files = vec![
+            backup.put_list("lists/1.csv.gz", &[a, b, e]),
+            backup.put_list("lists/2.csv.gz", &[d, c]),
+        ];
+        backup.run(files).await.unwrap();
+        for key in ["a", "b", "c", "d", "e"] {
+            assert_eq!(backup.read(key), format!("Contents of {key}\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn unsorted_list_below_merged_keys() {
+        let backup = TestBackup::new().await;
+        let rows = ["a", "b", "c", "d"]
+            .map(|key| backup.put_object(key, "v1", &format!("Contents of {key}\n")));
+        let [a, b, c, d] = rows.each_ref().map(String::as_str);
+        let files = vec![
+            backup.put_list("lists/1.csv.gz", &[b, c]),
+            backup.put_list("lists/2.csv.gz", &[d, a]),
+        ];
+        let e = backup.run(files.clone()).await.unwrap_err();
+        assert!(
+            format!("{e:?}").contains("could not be merged in order"),
+            "{e:?}"
+        );
+        backup.run(files).await.unwrap();
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(backup.read(key), format!("Contents of {key}\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_failures_reads_only_relevant_lists() {
+        let mut backup = TestBackup::new().await;
+        let e = backup.put_object("e", "v1", "Contents of e\n");
+        let foo = backup.put_object("foo", "v1", "Contents of foo\n");
+        let foo_bar = backup.put_object("foo/bar", "v1", "Contents of foo/bar\n");
+        // Lists that do not exist on the server but whose first keys are
+        // cached, so that reading them would fail:
+        let missing = |key: &str, md5: &str| FileSpec {
+            key: key.to_owned(),
+            size: 100,
+            md5_checksum: md5.to_owned(),
+            file_schema: SCHEMA.parse().unwrap(),
+        };
+        let files = vec![
+            missing("lists/1.csv.gz", "md5-1"),
+            backup.put_list("lists/2.csv.gz", &[&e, &foo]),
+            backup.put_list("lists/3.csv.gz", &[&foo_bar]),
+            missing("lists/4.csv.gz", "md5-4"),
+        ];
+        FirstKeysManager::new(&backup.outdir())
+            .store(&FirstKeys::from([
+                ("md5-1".to_owned(), Some("a".to_owned())),
+                ("md5-4".to_owned(), Some("q".to_owned())),
+            ]))
+            .unwrap();
+        FailuresFileManager::new(&backup.outdir())
+            .store(&[FailureRecord {
+                key: "foo".into(),
+                version_id: Some("v1".into()),
+                url: format!("s3://{SOURCE}/foo?versionId=v1"),
+                kind: FailureKind::Download,
+                error: "connection reset".into(),
+                ignored: false,
+            }])
+            .unwrap();
+        backup.retry_failures = true;
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read(".s3invsync.esc.foo%2F"), "Contents of foo\n");
+        assert!(!backup.exists("e"));
+        assert!(!backup.exists("foo/bar"));
+        assert!(!backup.exists(".s3invsync.failures.jsonl"));
+    }
+
+    #[tokio::test]
+    async fn file_and_directory_with_same_name() {
+        let backup = TestBackup::new().await;
+        let foo = backup.put_object("foo", "v1", "Contents of foo\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&foo])];
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read("foo"), "Contents of foo\n");
+        let rows = ["foo!bar", "foo/bar", "foo/bar/baz"]
+            .map(|key| backup.put_object(key, "v1", &format!("Contents of {key}\n")));
+        let [foo_bar, foo_slash_bar, baz] = rows.each_ref().map(String::as_str);
+        let files = vec![backup.put_list("lists/2.csv.gz", &[&foo, foo_bar, foo_slash_bar, baz])];
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read(".s3invsync.esc.foo%2F"), "Contents of foo\n");
+        assert_eq!(backup.read("foo!bar"), "Contents of foo!bar\n");
+        assert_eq!(
+            backup.read("foo/.s3invsync.esc.bar%2F"),
+            "Contents of foo/bar\n"
+        );
+        assert_eq!(backup.read("foo/bar/baz"), "Contents of foo/bar/baz\n");
+    }
+
+    #[tokio::test]
+    async fn recent_changes_survive_older_inventory() {
+        let backup = TestBackup::new().await;
+        let a = backup.put_object("a", "v1", "a v1\n");
+        let b = backup.put_object("b", "v1", "b v1\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&a, &b])];
+        let later = time::macros::datetime!(2025-01-03 00:00 UTC);
+        backup.server.put_at(SOURCE, "a", "v2", b"a v2\n", later);
+        backup.server.delete_at(SOURCE, "b", "v2", later);
+        backup.server.put_at(SOURCE, "c", "v1", b"c v1\n", later);
+        let a_old = format!("a.old.v1.{}", crate::s3::fake::etag(b"a v1\n"));
+        let b_old = format!("b.old.v1.{}", crate::s3::fake::etag(b"b v1\n"));
+        backup
+            .run_with(files.clone(), Some(vec![String::new()]))
+            .await
+            .unwrap();
+        assert!(backup.exists(".s3invsync.recent.json"));
+        assert_eq!(backup.read("a"), "a v2\n");
+        assert_eq!(backup.read(&a_old), "a v1\n");
+        assert!(!backup.exists("b"));
+        assert_eq!(backup.read(&b_old), "b v1\n");
+        assert_eq!(backup.read("c"), "c v1\n");
+        // A later run from the same inventory must not undo the recent
+        // changes:
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read("a"), "a v2\n");
+        assert_eq!(backup.read(&a_old), "a v1\n");
+        assert!(!backup.exists("b"));
+        assert_eq!(backup.read(&b_old), "b v1\n");
+        assert_eq!(backup.read("c"), "c v1\n");
+    }
+
+    #[tokio::test]
+    async fn recent_key_under_existing_file() {
+        let backup = TestBackup::new().await;
+        let foo = backup.put_object("foo", "v1", "Contents of foo\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&foo])];
+        backup.server.put_at(
+            SOURCE,
+            "foo/bar",
+            "v1",
+            b"Contents of foo/bar\n",
+            time::macros::datetime!(2025-01-03 00:00 UTC),
+        );
+        backup
+            .run_with(files, Some(vec!["foo/".into()]))
+            .await
+            .unwrap();
+        assert_eq!(backup.read("foo"), "Contents of foo\n");
+        assert!(!backup.exists(".s3invsync.recent.json"));
+    }
+
+    #[tokio::test]
+    async fn cleanup_keeps_reserved_dirs() {
+        let backup = TestBackup::new().await;
+        fs_err::create_dir_all(backup.outdir().join(".s3invsync.tmp/s3invsync.abc123")).unwrap();
+        fs_err::create_dir(backup.outdir().join("stray")).unwrap();
+        let foo = backup.put_object("foo", "v1", "Contents of foo\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&foo])];
+        backup.run(files).await.unwrap();
+        assert!(backup.exists(".s3invsync.tmp/s3invsync.abc123"));
+        assert!(!backup.exists("stray"));
+    }
+
+    #[tokio::test]
+    async fn restore_archived_object_across_runs() {
+        let mut backup = TestBackup::new().await;
+        backup.archived = ArchivePolicy::Restore;
+        let a = backup.put_archived(SOURCE, "a", "v1", "Contents of a\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&a])];
+        backup.run(files.clone()).await.unwrap();
+        assert!(!backup.exists("a"));
+        let restores = backup.pending_restores();
+        assert_eq!(restores.len(), 1);
+        assert_eq!(restores[0].key, "a");
+        assert_eq!(restores[0].version_id.as_deref(), Some("v1"));
+        assert!(backup
+            .server
+            .requests()
+            .contains(&"POST source/a".to_owned()));
+
+        // Restore still in progress: the pending restore is kept as-is, and
+        // no new restore is requested.
+        let before = backup.server.requests().len();
+        backup.run(files.clone()).await.unwrap();
+        assert!(!backup.exists("a"));
+        assert_eq!(backup.pending_restores(), restores);
+        let requests = backup.server.requests().split_off(before);
+        assert!(requests.contains(&"HEAD source/a".to_owned()));
+        assert!(!requests.iter().any(|r| r.starts_with("POST ")));
+
+        backup.server.finish_restore(SOURCE, "a", "v1");
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read("a"), "Contents of a\n");
+        assert!(!backup.exists(".s3invsync.restores.json"));
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn restore_archived_object_from_mapped_bucket(#[case] use_http: bool) {
+        let mut backup = TestBackup::new().await;
+        backup.archived = ArchivePolicy::Restore;
+        backup.bucket_map = [format!("{SOURCE}={REPLICA}").parse().unwrap()]
+            .into_iter()
+            .collect();
+        backup.use_http = use_http;
+        let a = backup.put_archived(REPLICA, "a", "v1", "Contents of a\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&a])];
+        backup.run(files.clone()).await.unwrap();
+        assert!(!backup.exists("a"));
+        assert_eq!(backup.pending_restores().len(), 1);
+        backup.server.finish_restore(REPLICA, "a", "v1");
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read("a"), "Contents of a\n");
+        assert!(!backup.exists(".s3invsync.restores.json"));
+        // Restores are always requested & checked via the signed S3 API, even
+        // when objects are downloaded via plain HTTP.
+        let requests = backup.server.requests();
+        assert!(requests.contains(&"POST replica/a".to_owned()));
+        assert!(requests.contains(&"HEAD replica/a".to_owned()));
+        let get = if use_http {
+            "GET replica/a (unsigned)"
+        } else {
+            "GET replica/a"
+        };
+        assert!(requests.contains(&get.to_owned()));
+        assert!(!requests.iter().any(
+            |r| r.ends_with("(unsigned)") && (r.starts_with("POST ") || r.starts_with("HEAD "))
+        ));
+        assert!(!requests
+            .iter()
+            .any(|r| r.starts_with("POST source/") || r.starts_with("HEAD source/")));
+    }
+
+    #[tokio::test]
+    async fn stale_temporaries_swept_at_end() {
+        let backup = TestBackup::new().await;
+        let bar = backup.put_object("foo/bar", "v1", "Contents of foo/bar\n");
+        let files = vec![backup.put_list("lists/1.csv.gz", &[&bar])];
+        fs_err::create_dir(backup.outdir().join("foo")).unwrap();
+        fs_err::write(
+            backup
+                .outdir()
+                .join("foo")
+                .join(".s3invsync.download.a1B2c3"),
+            "partial",
+        )
+        .unwrap();
+        backup.run(files).await.unwrap();
+        assert_eq!(backup.read("foo/bar"), "Contents of foo/bar\n");
+        assert!(!backup.exists("foo/.s3invsync.download.a1B2c3"));
+    }
+}
@@ -0,0 +1,215 @@
+use super::firstkeys::FirstKeysManager;
+use super::progress::TrackedList;
+use super::Syncer;
+use crate::inventory::{InventoryList, InventoryListError, KeyMerge, OutOfOrderError};
+use crate::manifest::FileSpec;
+use crate::nursery::Nursery;
+use anyhow::Context;
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio::sync::oneshot::{self, error::TryRecvError};
+
+/// A downloaded inventory list file, along with whether its entries were
+/// already sorted by key
+type Downloaded = (bool, TrackedList<InventoryList>);
+
+/// An inventory list file that is being downloaded
+struct Pending {
+    /// The key on the file's first line
+    first_key: String,
+
+    /// The file's MD5 checksum
+    md5_checksum: String,
+
+    /// A receiver for the download result
+    receiver: oneshot::Receiver<anyhow::Result<Downloaded>>,
+}
+
+/// An object for downloading inventory list files, with up to a fixed number
+/// of files downloading or waiting to be merged at once, and adding them to a
+/// [`KeyMerge`] in order.
+///
+/// Whether a list file's entries are sorted by key is only known once the file
+/// has been downloaded.  Hence, the merge is only allowed to yield keys less
+/// than the first key of the earliest file still being downloaded, and each
+/// unsorted file is added to the merge as soon as it has been downloaded &
+/// sorted.  Each sorted file is added only once the merge reaches the key on
+/// its first line so that, when the files' ranges don't overlap, only a
+/// bounded number of files are open at a time.
+///
+/// If an unsorted file turns out to contain a key smaller than one the merge
+/// has already yielded, an error is returned, and the file's smallest key is
+/// saved in the first keys cache so that the next run downloads the file in
+/// the right position.
+pub(super) struct ListPrefetcher {
+    /// The syncer whose client is used to download the files
+    syncer: Arc<Syncer>,
+
+    /// The nursery in which to spawn download tasks
+    nursery: Nursery<anyhow::Result<()>>,
+
+    /// The list files that have not been downloaded yet, paired with the keys
+    /// on their first lines
+    specs: std::vec::IntoIter<(String, FileSpec)>,
+
+    /// The files currently being downloaded, in order
+    queue: VecDeque<Pending>,
+
+    /// Downloaded sorted files that have not been added to the merge yet,
+    /// paired with the keys on their first lines, in order
+    ready: VecDeque<(String, TrackedList<InventoryList>)>,
+
+    /// The maximum number of files to have downloading or waiting to be added
+    /// to the merge at once
+    depth: usize,
+}
+
+impl ListPrefetcher {
+    pub(super) fn new(
+        syncer: Arc<Syncer>,
+        nursery: Nursery<anyhow::Result<()>>,
+        specs: Vec<(String, FileSpec)>,
+        depth: NonZeroUsize,
+    ) -> ListPrefetcher {
+        let depth = depth.get();
+        let mut prefetcher = ListPrefetcher {
+            syncer,
+            nursery,
+            specs: specs.into_iter(),
+            queue: VecDeque::with_capacity(depth),
+            ready: VecDeque::new(),
+            depth,
+        };
+        prefetcher.fill();
+        prefetcher
+    }
+
+    /// Add list files to `merge` as needed before the next entry is read from
+    /// it, waiting for downloads to finish if the merge's next key could come
+    /// after the first key of a file that is still downloading
+    pub(super) async fn feed(
+        &mut self,
+        merge: &mut KeyMerge<TrackedList<InventoryList>, InventoryListError>,
+    ) -> anyhow::Result<()> {
+        loop {
+            let mut i = 0;
+            while let Some(pending) = self.queue.get_mut(i) {
+                match pending.receiver.try_recv() {
+                    Ok(r) => {
+                        let pending = self.queue.remove(i).expect("index should be within queue");
+                        self.add(pending, r?, merge)?;
+                    }
+                    Err(TryRecvError::Empty) => i += 1,
+                    Err(TryRecvError::Closed) => {
+                        anyhow::bail!("inventory list download task exited without returning")
+                    }
+                }
+            }
+            while self.ready.front().is_some_and(|(first_key, _)| {
+                merge.peek_key().is_none_or(|k| first_key.as_str() <= k)
+            }) {
+                if let Some((_, list)) = self.ready.pop_front() {
+                    merge
+                        .push(list)
+                        .context("inventory list files could not be merged in order")?;
+                }
+            }
+            self.fill();
+            let Some(pending) = self.queue.front() else {
+                return Ok(());
+            };
+            if merge
+                .peek_key()
+                .is_some_and(|k| k < pending.first_key.as_str())
+            {
+                return Ok(());
+            }
+            let mut pending = self.queue.pop_front().expect("queue should be nonempty");
+            let r = match (&mut pending.receiver).await {
+                Ok(r) => r?,
+                Err(_) => anyhow::bail!("inventory list download task exited without returning"),
+            };
+            self.add(pending, r, merge)?;
+        }
+    }
+
+    /// Add a downloaded list file to `merge` if it was unsorted, or else to
+    /// `ready`
+    fn add(
+        &mut self,
+        pending: Pending,
+        (sorted, list): Downloaded,
+        merge: &mut KeyMerge<TrackedList<InventoryList>, InventoryListError>,
+    ) -> anyhow::Result<()> {
+        if sorted {
+            let pos = self
+                .ready
+                .partition_point(|(first_key, _)| first_key <= &pending.first_key);
+            self.ready.insert(pos, (pending.first_key, list));
+        } else if let Err(e) = merge.push(list) {
+            self.save_smallest_key(&pending.md5_checksum, &e);
+            return Err(e).context("inventory list files could not be merged in order");
+        }
+        Ok(())
+    }
+
+    /// Record the smallest key of an unsorted list file that could not be
+    /// merged in the first keys cache, so that subsequent runs order the file
+    /// by that key
+    fn save_smallest_key(&self, md5_checksum: &str, e: &OutOfOrderError) {
+        let manager = FirstKeysManager::new(&self.syncer.outdir);
+        let r = manager.load().and_then(|mut firsts| {
+            firsts.insert(md5_checksum.to_owned(), Some(e.after.clone()));
+            manager.store(&firsts)
+        });
+        if let Err(e) = r {
+            tracing::warn!(error = ?e, "Failed to save smallest key of unsorted inventory list file");
+        }
+    }
+
+    /// Start downloading list files until `depth` files are downloading or
+    /// waiting to be added to the merge
+    fn fill(&mut self) {
+        while self.queue.len() + self.ready.len() < self.depth {
+            let Some((first_key, spec)) = self.specs.next() else {
+                break;
+            };
+            let md5_checksum = spec.md5_checksum.clone();
+            let (sender, receiver) = oneshot::channel();
+            let syncer = self.syncer.clone();
+            let size = u64::try_from(spec.size).unwrap_or(0);
+            self.nursery
+                .spawn(self.syncer.until_cancelled_ok(async move {
+                    tracing::debug!(key = spec.key, "Downloading inventory list file");
+                    let r = match syncer.client.download_inventory_csv(spec).await {
+                        Ok(list) => {
+                            let sorted = list.was_sorted();
+                            list.into_sorted()
+                                .map(|list| (sorted, list))
+                                .map_err(Into::into)
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    if r.is_ok() {
+                        syncer.metrics.inventory_lists_downloaded.inc();
+                    }
+                    let r = r.map(|(sorted, list)| {
+                        (
+                            sorted,
+                            TrackedList::new(list, size, syncer.progress.clone()),
+                        )
+                    });
+                    // If the receiver has been dropped, we're shutting down,
+                    // and the list file will be deleted on drop.
+                    let _ = sender.send(r);
+                    Ok(())
+                }));
+            self.queue.push_back(Pending {
+                first_key,
+                md5_checksum,
+                receiver,
+            });
+        }
+    }
+}
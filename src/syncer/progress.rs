@@ -0,0 +1,406 @@
+use crate::consts::{PROGRESS_LOG_INTERVAL, PROGRESS_TTY_INTERVAL, THROUGHPUT_WINDOW};
+use crate::inventory::InventoryEntry;
+use crate::manifest::FileSpec;
+use bytesize::ByteSize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{stderr, IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How to display the progress of a run
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum ProgressMode {
+    /// Continually redraw a status line on stderr.  If stderr is not a
+    /// terminal, `log` is used instead.
+    Tty,
+
+    /// Periodically emit the status as an INFO log message
+    Log,
+}
+
+/// A thread-safe tracker of how much of the inventory has been discovered &
+/// processed
+#[derive(Debug, Default)]
+pub(super) struct Progress {
+    /// Number of nonempty inventory list files in the inventory
+    lists_total: AtomicU64,
+
+    /// Total size in bytes of the nonempty inventory list files
+    list_bytes_total: AtomicU64,
+
+    /// Number of inventory list files that have been read to the end
+    lists_done: AtomicU64,
+
+    /// Total size in bytes of the inventory list files that have been read
+    /// to the end
+    list_bytes_done: AtomicU64,
+
+    /// Number of objects read from the inventory and sent off for processing
+    objects_discovered: AtomicU64,
+
+    /// Total size of the objects read from the inventory
+    bytes_discovered: AtomicU64,
+
+    /// Number of objects that have finished processing
+    objects_completed: AtomicU64,
+
+    /// Total size of the objects that have finished processing
+    bytes_completed: AtomicU64,
+}
+
+impl Progress {
+    /// Record the inventory list files that will be read
+    pub(super) fn set_lists(&self, specs: &[(String, FileSpec)]) {
+        let size = specs
+            .iter()
+            .map(|(_, spec)| u64::try_from(spec.size).unwrap_or(0))
+            .sum();
+        self.lists_total
+            .store(specs.len() as u64, Ordering::Relaxed);
+        self.list_bytes_total.store(size, Ordering::Relaxed);
+    }
+
+    /// Record that an inventory list file of size `size` has been read to
+    /// the end
+    fn list_done(&self, size: u64) {
+        self.lists_done.fetch_add(1, Ordering::Relaxed);
+        self.list_bytes_done.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Record that an object of size `size` has been sent off for processing
+    pub(super) fn discovered(&self, size: u64) {
+        self.objects_discovered.fetch_add(1, Ordering::Relaxed);
+        self.bytes_discovered.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Record that an object of size `size` has finished processing
+    pub(super) fn completed(&self, size: u64) {
+        self.objects_completed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_completed.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            lists_total: self.lists_total.load(Ordering::Relaxed),
+            list_bytes_total: self.list_bytes_total.load(Ordering::Relaxed),
+            lists_done: self.lists_done.load(Ordering::Relaxed),
+            list_bytes_done: self.list_bytes_done.load(Ordering::Relaxed),
+            objects_discovered: self.objects_discovered.load(Ordering::Relaxed),
+            bytes_discovered: self.bytes_discovered.load(Ordering::Relaxed),
+            objects_completed: self.objects_completed.load(Ordering::Relaxed),
+            bytes_completed: self.bytes_completed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Spawn a task that displays the progress in the given mode until the
+    /// returned [`ProgressDisplay`] is finished
+    pub(super) fn display(self: &Arc<Self>, mut mode: ProgressMode) -> ProgressDisplay {
+        if mode == ProgressMode::Tty && !stderr().is_terminal() {
+            mode = ProgressMode::Log;
+        }
+        let interval = match mode {
+            ProgressMode::Tty => PROGRESS_TTY_INTERVAL,
+            ProgressMode::Log => PROGRESS_LOG_INTERVAL,
+        };
+        let this = self.clone();
+        let handle = tokio::spawn(async move {
+            let mut meter = Throughput::new(THROUGHPUT_WINDOW);
+            let mut timer = tokio::time::interval(interval);
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                let snapshot = this.snapshot();
+                let status = Status {
+                    rate: meter.update(Instant::now(), snapshot.bytes_completed),
+                    snapshot,
+                };
+                match mode {
+                    ProgressMode::Tty => {
+                        let mut err = stderr().lock();
+                        let _ = write!(err, "\r\x1b[2K{status}");
+                        let _ = err.flush();
+                    }
+                    ProgressMode::Log => tracing::info!("Progress: {status}"),
+                }
+            }
+        });
+        ProgressDisplay { handle, mode }
+    }
+}
+
+/// Handle to a task spawned by [`Progress::display()`]
+#[derive(Debug)]
+pub(super) struct ProgressDisplay {
+    handle: tokio::task::JoinHandle<()>,
+    mode: ProgressMode,
+}
+
+impl ProgressDisplay {
+    /// Stop displaying progress
+    pub(super) fn finish(self) {
+        self.handle.abort();
+        if self.mode == ProgressMode::Tty {
+            let mut err = stderr().lock();
+            let _ = writeln!(err);
+            let _ = err.flush();
+        }
+    }
+}
+
+/// An iterator adaptor around an inventory list file that records the file
+/// as done in a [`Progress`] once it has been read to the end
+#[derive(Debug)]
+pub(super) struct TrackedList<I> {
+    inner: I,
+    size: u64,
+    progress: Option<Arc<Progress>>,
+}
+
+impl<I> TrackedList<I> {
+    pub(super) fn new(inner: I, size: u64, progress: Arc<Progress>) -> Self {
+        TrackedList {
+            inner,
+            size,
+            progress: Some(progress),
+        }
+    }
+}
+
+impl<I, E> Iterator for TrackedList<I>
+where
+    I: Iterator<Item = Result<InventoryEntry, E>>,
+{
+    type Item = Result<InventoryEntry, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r = self.inner.next();
+        if r.is_none() {
+            if let Some(progress) = self.progress.take() {
+                progress.list_done(self.size);
+            }
+        }
+        r
+    }
+}
+
+/// A point-in-time copy of the values in a [`Progress`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Snapshot {
+    lists_total: u64,
+    list_bytes_total: u64,
+    lists_done: u64,
+    list_bytes_done: u64,
+    objects_discovered: u64,
+    bytes_discovered: u64,
+    objects_completed: u64,
+    bytes_completed: u64,
+}
+
+impl Snapshot {
+    /// Estimate the total number & size of the objects in the inventory by
+    /// extrapolating from the fraction of the inventory list files (by size)
+    /// that have been read so far.  Returns `None` if no list files have been
+    /// read to the end yet.
+    fn estimated_totals(&self) -> Option<(u64, u64)> {
+        if self.lists_done >= self.lists_total {
+            return Some((self.objects_discovered, self.bytes_discovered));
+        }
+        if self.list_bytes_done == 0 {
+            return None;
+        }
+        let scale = |n: u64| {
+            let est = u128::from(n) * u128::from(self.list_bytes_total)
+                / u128::from(self.list_bytes_done);
+            u64::try_from(est).unwrap_or(u64::MAX).max(n)
+        };
+        Some((scale(self.objects_discovered), scale(self.bytes_discovered)))
+    }
+}
+
+/// The progress information displayed at each tick
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Status {
+    snapshot: Snapshot,
+
+    /// Current throughput in bytes per second, if known
+    rate: Option<u64>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = &self.snapshot;
+        write!(
+            f,
+            "lists {}/{}, objects {}/{}",
+            s.lists_done, s.lists_total, s.objects_completed, s.objects_discovered
+        )?;
+        let estimate = s.estimated_totals();
+        if let Some((objects, _)) = estimate.filter(|_| s.lists_done < s.lists_total) {
+            write!(f, " (est. {objects})")?;
+        }
+        write!(
+            f,
+            ", bytes {}/{}",
+            ByteSize(s.bytes_completed),
+            ByteSize(s.bytes_discovered)
+        )?;
+        if let Some((_, bytes)) = estimate.filter(|_| s.lists_done < s.lists_total) {
+            write!(f, " (est. {})", ByteSize(bytes))?;
+        }
+        match self.rate {
+            Some(rate) => write!(f, ", {}/s", ByteSize(rate))?,
+            None => write!(f, ", -/s")?,
+        }
+        match (estimate, self.rate) {
+            (Some((_, total)), Some(rate)) if rate > 0 => {
+                let remaining = total.saturating_sub(s.bytes_completed);
+                write!(f, ", ETA {}", FmtEta(remaining / rate))
+            }
+            _ => write!(f, ", ETA unknown"),
+        }
+    }
+}
+
+/// Display a number of seconds as days, hours, minutes, and seconds
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct FmtEta(u64);
+
+impl fmt::Display for FmtEta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0 % 60;
+        let mins = (self.0 / 60) % 60;
+        let hours = (self.0 / 3600) % 24;
+        let days = self.0 / 86400;
+        if days > 0 {
+            write!(f, "{days}d{hours:02}h{mins:02}m")
+        } else if hours > 0 {
+            write!(f, "{hours}h{mins:02}m{secs:02}s")
+        } else if mins > 0 {
+            write!(f, "{mins}m{secs:02}s")
+        } else {
+            write!(f, "{secs}s")
+        }
+    }
+}
+
+/// A measurer of the rate at which a count increases over a sliding window
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Throughput {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    fn new(window: Duration) -> Self {
+        Throughput {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record that the count is `value` at time `now` and return the rate
+    /// per second over the window, if there are enough samples to tell
+    fn update(&mut self, now: Instant, value: u64) -> Option<u64> {
+        self.samples.push_back((now, value));
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|&(t, _)| now.saturating_duration_since(t) >= self.window)
+        {
+            self.samples.pop_front();
+        }
+        let &(start, start_value) = self.samples.front()?;
+        let millis = now.saturating_duration_since(start).as_millis();
+        if millis == 0 {
+            return None;
+        }
+        let delta = u128::from(value.saturating_sub(start_value));
+        u64::try_from(delta * 1000 / millis).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(0, "0s")]
+    #[case(59, "59s")]
+    #[case(61, "1m01s")]
+    #[case(3600, "1h00m00s")]
+    #[case(93784, "1d02h03m")]
+    fn fmt_eta(#[case] secs: u64, #[case] s: &str) {
+        assert_eq!(FmtEta(secs).to_string(), s);
+    }
+
+    #[test]
+    fn throughput() {
+        let start = Instant::now();
+        let mut meter = Throughput::new(Duration::from_secs(10));
+        assert_eq!(meter.update(start, 0), None);
+        assert_eq!(meter.update(start + Duration::from_secs(5), 500), Some(100));
+        assert_eq!(
+            meter.update(start + Duration::from_secs(10), 2000),
+            Some(200)
+        );
+        // The first sample has fallen out of the window:
+        assert_eq!(
+            meter.update(start + Duration::from_secs(15), 2000),
+            Some(150)
+        );
+    }
+
+    #[test]
+    fn status_with_estimate() {
+        let status = Status {
+            snapshot: Snapshot {
+                lists_total: 4,
+                list_bytes_total: 4000,
+                lists_done: 1,
+                list_bytes_done: 1000,
+                objects_discovered: 100,
+                bytes_discovered: 10240,
+                objects_completed: 50,
+                bytes_completed: 5120,
+            },
+            rate: Some(1024),
+        };
+        assert_eq!(
+            status.to_string(),
+            "lists 1/4, objects 50/100 (est. 400), bytes 5.0 KiB/10.0 KiB (est. 40.0 KiB), 1.0 KiB/s, ETA 35s"
+        );
+    }
+
+    #[test]
+    fn status_without_estimate() {
+        let status = Status {
+            snapshot: Snapshot {
+                lists_total: 4,
+                list_bytes_total: 4000,
+                objects_discovered: 10,
+                bytes_discovered: 1000,
+                ..Snapshot::default()
+            },
+            rate: None,
+        };
+        assert_eq!(
+            status.to_string(),
+            "lists 0/4, objects 0/10, bytes 0 B/1000 B, -/s, ETA unknown"
+        );
+    }
+
+    #[test]
+    fn tracked_list() {
+        let progress = Arc::new(Progress::default());
+        let entries: Vec<Result<InventoryEntry, ()>> = Vec::new();
+        let mut list = TrackedList::new(entries.into_iter(), 42, progress.clone());
+        assert!(list.next().is_none());
+        assert!(list.next().is_none());
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.lists_done, 1);
+        assert_eq!(snapshot.list_bytes_done, 42);
+    }
+}
@@ -0,0 +1,300 @@
+use super::treetracker::LocalPath;
+use crate::consts::RESERVED_PREFIX;
+use crate::inventory::{InventoryItem, ItemDetails};
+use crate::keypath::KeyPath;
+use crate::util::make_old_filename;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+/// A record of an object version that was backed up from a "List Object
+/// Versions" listing rather than from an inventory list
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(super) struct RecentRecord {
+    /// The object's key
+    pub(super) key: String,
+
+    /// The object's version ID
+    pub(super) version_id: Option<String>,
+
+    /// The object's etag, or `None` if the version is a delete marker
+    pub(super) etag: Option<String>,
+
+    /// Whether the version was the latest version of the key when listed
+    pub(super) is_latest: bool,
+
+    /// The version's date of last modification
+    #[serde(with = "time::serde::rfc3339")]
+    pub(super) last_modified: OffsetDateTime,
+
+    /// The local path (relative to the backup root) of the directory in which
+    /// the version is backed up, if it differs from the path implied by the
+    /// key alone due to a collision with another entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) local_dirname: Option<String>,
+
+    /// The filename at which the key's latest version is backed up, if it
+    /// differs from the filename implied by the key alone due to a conflict
+    /// with a directory or a collision with another entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) local_filename: Option<String>,
+}
+
+impl RecentRecord {
+    /// Construct a record for `item`, whose key's latest version is backed up
+    /// at `local`, where `portable` is the value of
+    /// [`NameOptions::portable`][super::NameOptions::portable].  Returns `None`
+    /// if `item` lacks a last-modified date.
+    pub(super) fn for_item(
+        item: &InventoryItem,
+        local: &LocalPath,
+        portable: bool,
+    ) -> Option<RecentRecord> {
+        let etag = match item.details {
+            ItemDetails::Present { ref etag, .. } => Some(etag.clone()),
+            ItemDetails::Deleted => None,
+        };
+        let (dirname, filename) = item.key.local_split(portable);
+        Some(RecentRecord {
+            key: String::from(&item.key),
+            version_id: item.version_id.clone(),
+            etag,
+            is_latest: item.is_latest,
+            last_modified: item.last_modified_date?,
+            local_dirname: local
+                .dirname
+                .clone()
+                .filter(|d| dirname.as_ref() != Some(d)),
+            local_filename: Some(local.filename.clone()).filter(|f| *f != filename),
+        })
+    }
+
+    /// Returns the path, relative to the backup root, at which the version is
+    /// backed up, or `None` if the version is a delete marker
+    fn local_path(&self, portable: bool) -> Option<String> {
+        let etag = self.etag.as_deref()?;
+        let key = KeyPath::try_from(self.key.clone()).ok()?;
+        let (dirname, basename) = key.local_split(portable);
+        let dirname = self.local_dirname.clone().or(dirname);
+        let filename = if !self.is_latest {
+            make_old_filename(&basename, self.version_id.as_deref(), etag)
+        } else if let Some(ref f) = self.local_filename {
+            f.clone()
+        } else {
+            basename.into_owned()
+        };
+        Some(match dirname {
+            Some(d) => format!("{d}/{filename}"),
+            None => filename,
+        })
+    }
+}
+
+/// An index of the [`RecentRecord`]s that are newer than the inventory
+/// currently being backed up, used to keep the inventory phase from undoing
+/// the work of a previous run's `--recent-changes` phase
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(super) struct RecentChanges {
+    /// Mapping from directory paths (relative to the backup root, with `""`
+    /// for the root itself) to the names of entries in those directories that
+    /// must not be deleted
+    protected: HashMap<String, HashSet<String>>,
+
+    /// Keys whose latest versions are newer than the inventory
+    superseded: HashSet<String>,
+}
+
+impl RecentChanges {
+    pub(super) fn new(records: &[RecentRecord], portable: bool) -> RecentChanges {
+        let mut changes = RecentChanges::default();
+        for rec in records {
+            if rec.is_latest {
+                changes.superseded.insert(rec.key.clone());
+            }
+            if let Some(path) = rec.local_path(portable) {
+                changes.protect(&path);
+            }
+        }
+        changes
+    }
+
+    /// Mark `path` and all of its ancestor directories as protected
+    fn protect(&mut self, path: &str) {
+        let mut dirpath = String::new();
+        let mut rest = path;
+        while let Some((name, post)) = rest.split_once('/') {
+            self.protected
+                .entry(dirpath.clone())
+                .or_default()
+                .insert(name.to_owned());
+            if !dirpath.is_empty() {
+                dirpath.push('/');
+            }
+            dirpath.push_str(name);
+            rest = post;
+        }
+        self.protected
+            .entry(dirpath)
+            .or_default()
+            .insert(rest.to_owned());
+    }
+
+    /// Returns true if the entry `name` in the directory at `dirpath` (`None`
+    /// for the backup root) was backed up from a listing that is newer than
+    /// the inventory and thus must not be deleted
+    pub(super) fn is_protected(&self, dirpath: Option<&str>, name: &str) -> bool {
+        self.protected
+            .get(dirpath.unwrap_or(""))
+            .is_some_and(|names| names.contains(name))
+    }
+
+    /// Returns true if the latest version of `key` is newer than the
+    /// inventory, in which case the inventory's "latest" version of `key`
+    /// should be treated as an old version
+    pub(super) fn supersedes(&self, key: &str) -> bool {
+        self.superseded.contains(key)
+    }
+}
+
+/// Handle for reading & writing the file in which [`RecentRecord`]s are
+/// stored between runs
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(super) struct RecentFileManager {
+    path: PathBuf,
+}
+
+impl RecentFileManager {
+    pub(super) fn new(outdir: &Path) -> Self {
+        RecentFileManager {
+            path: outdir.join(format!("{RESERVED_PREFIX}.recent.json")),
+        }
+    }
+
+    /// Read & parse the records file.  If the file does not exist, return an
+    /// empty list.
+    pub(super) fn load(&self) -> anyhow::Result<Vec<RecentRecord>> {
+        let content = match fs_err::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to deserialize contents of {}", self.path.display()))
+    }
+
+    /// Set the content of the records file to the serialized list.  If the
+    /// list is empty, the file is deleted instead.
+    pub(super) fn store(&self, records: &[RecentRecord]) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return match fs_err::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let fp = tempfile::Builder::new()
+            .prefix(&format!("{RESERVED_PREFIX}.recent."))
+            .tempfile_in(
+                self.path
+                    .parent()
+                    .expect("records file path should have a parent"),
+            )
+            .with_context(|| {
+                format!(
+                    "failed to create temporary records file for updating {}",
+                    self.path.display()
+                )
+            })?;
+        serde_json::to_writer_pretty(fp.as_file(), records)
+            .with_context(|| format!("failed to serialize records to {}", self.path.display()))?;
+        fp.as_file().write_all(b"\n").with_context(|| {
+            format!(
+                "failed to write terminating newline to {}",
+                self.path.display()
+            )
+        })?;
+        fp.persist(&self.path).with_context(|| {
+            format!(
+                "failed to persist temporary records file to {}",
+                self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn record(key: &str, etag: Option<&str>, is_latest: bool) -> RecentRecord {
+        RecentRecord {
+            key: key.into(),
+            version_id: Some("abc".into()),
+            etag: etag.map(Into::into),
+            is_latest,
+            last_modified: datetime!(2024-11-15 12:00:00 UTC),
+            local_dirname: None,
+            local_filename: None,
+        }
+    }
+
+    #[test]
+    fn protect_latest() {
+        let changes = RecentChanges::new(&[record("foo/bar/baz.txt", Some("0123"), true)], false);
+        assert!(changes.is_protected(None, "foo"));
+        assert!(changes.is_protected(Some("foo"), "bar"));
+        assert!(changes.is_protected(Some("foo/bar"), "baz.txt"));
+        assert!(!changes.is_protected(Some("foo/bar"), "quux.txt"));
+        assert!(!changes.is_protected(None, "bar"));
+        assert!(changes.supersedes("foo/bar/baz.txt"));
+    }
+
+    #[test]
+    fn protect_old() {
+        let changes = RecentChanges::new(&[record("baz.txt", Some("0123"), false)], false);
+        assert!(changes.is_protected(None, "baz.txt.old.abc.0123"));
+        assert!(!changes.is_protected(None, "baz.txt"));
+        assert!(!changes.supersedes("baz.txt"));
+    }
+
+    #[test]
+    fn protect_escaped() {
+        let changes = RecentChanges::new(&[record("foo/../baz.txt", Some("0123"), true)], false);
+        assert!(changes.is_protected(None, "foo"));
+        assert!(changes.is_protected(Some("foo"), ".s3invsync.esc.%2E%2E"));
+        assert!(changes.is_protected(Some("foo/.s3invsync.esc.%2E%2E"), "baz.txt"));
+        assert!(!changes.is_protected(None, "baz.txt"));
+    }
+
+    #[test]
+    fn protect_conflict_file() {
+        let mut rec = record("foo/bar", Some("0123"), true);
+        rec.local_filename = Some(".s3invsync.esc.bar%2F".into());
+        let changes = RecentChanges::new(&[rec], false);
+        assert!(changes.is_protected(None, "foo"));
+        assert!(changes.is_protected(Some("foo"), ".s3invsync.esc.bar%2F"));
+        assert!(!changes.is_protected(Some("foo"), "bar"));
+        assert!(changes.supersedes("foo/bar"));
+    }
+
+    #[test]
+    fn protect_collision() {
+        let mut rec = record("foo/data.nwb", Some("0123"), true);
+        rec.local_filename = Some("data.nwb~abc".into());
+        let changes = RecentChanges::new(&[rec], false);
+        assert!(changes.is_protected(Some("foo"), "data.nwb~abc"));
+        assert!(!changes.is_protected(Some("foo"), "data.nwb"));
+    }
+
+    #[test]
+    fn delete_marker() {
+        let changes = RecentChanges::new(&[record("foo/baz.txt", None, true)], false);
+        assert!(!changes.is_protected(None, "foo"));
+        assert!(!changes.is_protected(Some("foo"), "baz.txt"));
+        assert!(changes.supersedes("foo/baz.txt"));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::errorset::DownloadWarning;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+/// A thread-safe tally of the actions taken on objects & local files during a
+/// run, broken down by category
+#[derive(Debug, Default)]
+pub(crate) struct RunStats {
+    downloaded_latest: Tally,
+    downloaded_old: Tally,
+    renamed_latest_to_old: Tally,
+    renamed_old_to_latest: Tally,
+    unchanged: Tally,
+    filtered: Tally,
+    archived: Tally,
+    delete_markers: Tally,
+    deleted_files: Tally,
+    deleted_directories: Tally,
+    ignored_errors: Mutex<Vec<IgnoredError>>,
+}
+
+impl RunStats {
+    /// Record that an object of size `bytes` was downloaded
+    pub(super) fn downloaded(&self, is_old: bool, bytes: u64) {
+        if is_old {
+            self.downloaded_old.add(bytes);
+        } else {
+            self.downloaded_latest.add(bytes);
+        }
+    }
+
+    /// Record that a backup file of size `bytes` was renamed
+    pub(super) fn renamed(&self, rename: Rename, bytes: u64) {
+        match rename {
+            Rename::LatestToOld => self.renamed_latest_to_old.add(bytes),
+            Rename::OldToLatest => self.renamed_old_to_latest.add(bytes),
+        }
+    }
+
+    /// Record that an object of size `bytes` was already backed up
+    pub(super) fn unchanged(&self, bytes: u64) {
+        self.unchanged.add(bytes);
+    }
+
+    /// Record that an object of size `bytes` was skipped due to
+    /// `--path-filter`
+    pub(super) fn filtered(&self, bytes: u64) {
+        self.filtered.add(bytes);
+    }
+
+    /// Record that an object of size `bytes` was not downloaded due to being
+    /// archived
+    pub(super) fn archived(&self, bytes: u64) {
+        self.archived.add(bytes);
+    }
+
+    /// Record that a delete marker was processed
+    pub(super) fn delete_marker(&self) {
+        self.delete_markers.add(0);
+    }
+
+    /// Record that a local file of size `bytes` not belonging in the backup
+    /// was deleted
+    pub(super) fn deleted_file(&self, bytes: u64) {
+        self.deleted_files.add(bytes);
+    }
+
+    /// Record that a local directory not belonging in the backup was deleted
+    pub(super) fn deleted_directory(&self) {
+        self.deleted_directories.add(0);
+    }
+
+    /// Record that downloading the object at `url` failed with an error that
+    /// was ignored
+    pub(super) fn ignored(&self, kind: DownloadWarning, url: String, error: String) {
+        self.ignored_errors
+            .lock()
+            .expect("ignored_errors mutex should not be poisoned")
+            .push(IgnoredError { kind, url, error });
+    }
+
+    /// Return the current values of the tallies
+    pub(crate) fn summary(&self) -> RunSummary {
+        RunSummary {
+            downloaded_latest: self.downloaded_latest.totals(),
+            downloaded_old: self.downloaded_old.totals(),
+            renamed_latest_to_old: self.renamed_latest_to_old.totals(),
+            renamed_old_to_latest: self.renamed_old_to_latest.totals(),
+            unchanged: self.unchanged.totals(),
+            filtered: self.filtered.totals(),
+            archived: self.archived.totals(),
+            delete_markers: self.delete_markers.totals(),
+            deleted_files: self.deleted_files.totals(),
+            deleted_directories: self.deleted_directories.totals(),
+            ignored_errors: self
+                .ignored_errors
+                .lock()
+                .expect("ignored_errors mutex should not be poisoned")
+                .clone(),
+        }
+    }
+}
+
+/// The direction in which a backup file was renamed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(super) enum Rename {
+    /// A file at a key's "latest" path was moved to an "old" path
+    LatestToOld,
+
+    /// A file at an "old" path was moved to a key's "latest" path
+    OldToLatest,
+}
+
+/// A thread-safe count of items and their total size
+#[derive(Debug, Default)]
+struct Tally {
+    count: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Tally {
+    fn add(&self, bytes: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn totals(&self) -> Totals {
+        Totals {
+            count: self.count.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A count of items and their total size in bytes
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Totals {
+    pub(crate) count: u64,
+    pub(crate) bytes: u64,
+}
+
+/// A download error that was ignored due to `--ignore-errors`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct IgnoredError {
+    /// The type of error
+    #[serde(rename = "type")]
+    pub(crate) kind: DownloadWarning,
+
+    /// The URL of the object that could not be downloaded
+    pub(crate) url: String,
+
+    /// The error message
+    pub(crate) error: String,
+}
+
+/// The values of the tallies in a [`RunStats`] at a point in time
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct RunSummary {
+    /// Latest versions of keys that were downloaded
+    pub(crate) downloaded_latest: Totals,
+
+    /// Non-latest versions of keys that were downloaded
+    pub(crate) downloaded_old: Totals,
+
+    /// Backup files that were moved from "latest" paths to "old" paths
+    pub(crate) renamed_latest_to_old: Totals,
+
+    /// Backup files that were moved from "old" paths to "latest" paths
+    pub(crate) renamed_old_to_latest: Totals,
+
+    /// Objects that were already backed up
+    pub(crate) unchanged: Totals,
+
+    /// Objects skipped due to `--path-filter`
+    pub(crate) filtered: Totals,
+
+    /// Archived objects that were not downloaded
+    pub(crate) archived: Totals,
+
+    /// Delete markers processed
+    pub(crate) delete_markers: Totals,
+
+    /// Local files deleted for not belonging in the backup
+    pub(crate) deleted_files: Totals,
+
+    /// Local directories deleted for not belonging in the backup
+    pub(crate) deleted_directories: Totals,
+
+    /// Download errors ignored due to `--ignore-errors`
+    pub(crate) ignored_errors: Vec<IgnoredError>,
+}
+
+impl RunSummary {
+    /// Condense the summary into the counts recorded in the run history
+    pub(crate) fn counts(&self) -> RunCounts {
+        let mut ignored_errors = BTreeMap::new();
+        for e in &self.ignored_errors {
+            *ignored_errors.entry(e.kind).or_default() += 1;
+        }
+        RunCounts {
+            downloaded: self.downloaded_latest.count + self.downloaded_old.count,
+            renamed: self.renamed_latest_to_old.count + self.renamed_old_to_latest.count,
+            deleted: self.deleted_files.count + self.deleted_directories.count,
+            skipped: self.unchanged.count + self.filtered.count + self.archived.count,
+            bytes: self.downloaded_latest.bytes + self.downloaded_old.bytes,
+            ignored_errors,
+        }
+    }
+}
+
+/// The counts of actions taken on objects during a run, as recorded in the
+/// run history
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct RunCounts {
+    /// Number of objects downloaded
+    pub(crate) downloaded: u64,
+
+    /// Number of backup files renamed between their "latest" and "old" names
+    pub(crate) renamed: u64,
+
+    /// Number of files & directories deleted for not belonging in the backup
+    pub(crate) deleted: u64,
+
+    /// Number of objects that were already backed up or that were excluded
+    /// from the backup
+    pub(crate) skipped: u64,
+
+    /// Total size in bytes of the downloaded objects
+    pub(crate) bytes: u64,
+
+    /// Number of download errors ignored due to `--ignore-errors`, by type
+    #[serde(default)]
+    pub(crate) ignored_errors: BTreeMap<DownloadWarning, u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_counts() {
+        let stats = RunStats::default();
+        stats.downloaded(false, 100);
+        stats.downloaded(true, 50);
+        stats.renamed(Rename::LatestToOld, 10);
+        stats.unchanged(5);
+        stats.filtered(7);
+        stats.delete_marker();
+        stats.deleted_file(3);
+        stats.deleted_directory();
+        stats.ignored(
+            DownloadWarning::AccessDenied,
+            "s3://bucket/foo".into(),
+            "403".into(),
+        );
+        let summary = stats.summary();
+        assert_eq!(
+            summary.downloaded_latest,
+            Totals {
+                count: 1,
+                bytes: 100
+            }
+        );
+        assert_eq!(summary.delete_markers, Totals { count: 1, bytes: 0 });
+        assert_eq!(
+            summary.counts(),
+            RunCounts {
+                downloaded: 2,
+                renamed: 1,
+                deleted: 2,
+                skipped: 2,
+                bytes: 150,
+                ignored_errors: BTreeMap::from([(DownloadWarning::AccessDenied, 1)]),
+            }
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use crate::keypath::KeyPath;
+use super::NameOptions;
+use crate::keypath::{conflict_file_name, disambiguate_name, fold_name, local_name, KeyPath};
 use either::Either;
 use std::cmp::Ordering;
 use std::collections::HashMap;
@@ -15,14 +16,59 @@ pub(super) struct PartialDirectory<T> {
     /// The name of the subdirectory of this directory that is currently
     /// "open", if any
     pub(super) current_subdir: Option<String>,
+
+    /// Options for computing the local names of entries
+    pub(super) options: NameOptions,
+
+    /// If name folding is enabled, a mapping from the folded forms (see
+    /// [`fold_name()`]) of the names of all entries seen so far to the first
+    /// name seen with that folded form
+    pub(super) folds: Option<HashMap<String, String>>,
 }
 
 impl<T> PartialDirectory<T> {
     /// Create a new, empty `PartialDirectory`
-    pub(super) fn new() -> Self {
+    pub(super) fn new(options: NameOptions) -> Self {
         PartialDirectory {
             entries: Vec::new(),
             current_subdir: None,
+            options,
+            folds: options.fold.then(HashMap::new),
+        }
+    }
+
+    /// If name folding is enabled, record that an entry named `name` has been
+    /// seen
+    pub(super) fn register_name(&mut self, name: &str) {
+        if let Some(folds) = self.folds.as_mut() {
+            folds
+                .entry(fold_name(name))
+                .or_insert_with(|| name.to_owned());
+        }
+    }
+
+    /// Returns true if name folding is enabled and `name` folds to the same
+    /// value as a different name seen earlier
+    pub(super) fn collides(&self, name: &str) -> bool {
+        self.folds
+            .as_ref()
+            .and_then(|folds| folds.get(&fold_name(name)))
+            .is_some_and(|first| first != name)
+    }
+
+    /// Returns the local name for the entry named `name` in this directory,
+    /// where `is_dir` indicates whether the entry is a directory and `idx` is
+    /// the number of leading elements of `entries` that sort before or at it
+    pub(super) fn local_entry_name(&self, name: &str, is_dir: bool, idx: usize) -> String {
+        let local = if !is_dir && self.has_dir_after(idx, name) {
+            conflict_file_name(name)
+        } else {
+            local_name(name, self.options.portable).into_owned()
+        };
+        if self.collides(name) {
+            disambiguate_name(&local, name)
+        } else {
+            local
         }
     }
 
@@ -49,6 +95,22 @@ impl<T> PartialDirectory<T> {
         self.current_subdir.is_some()
     }
 
+    /// Returns true if a directory named `name` is among the entries of this
+    /// directory after the first `idx` or is the current subdirectory.
+    ///
+    /// This only looks at the leading entries (after the first `idx`) whose
+    /// names start with `name`, and so it is only accurate if no entries
+    /// sorting before a file named `name` are among them.
+    fn has_dir_after(&self, idx: usize, name: &str) -> bool {
+        self.entries
+            .get(idx..)
+            .unwrap_or_default()
+            .iter()
+            .take_while(|en| en.name().starts_with(name))
+            .any(|en| matches!(en, Entry::Dir { .. }) && en.name() == name)
+            || self.current_subdir.as_deref() == Some(name)
+    }
+
     /// Compare `cname` against the name of the last entry added to this
     /// directory
     pub(super) fn cmp_vs_last_entry(&self, cname: CmpName<'_>) -> Option<Ordering> {
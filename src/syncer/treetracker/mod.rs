@@ -1,8 +1,8 @@
 mod inner;
 use self::inner::*;
-use crate::keypath::KeyPath;
+use crate::keypath::{local_name, KeyPath};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use thiserror::Error;
 
 /// A type for tracking keys as they're encountered and — assuming the keys are
@@ -15,7 +15,16 @@ use thiserror::Error;
 /// sorted, but must be unique.
 ///
 /// Each key version additionally has a payload of type `T`, used by
-/// `s3invsync` for storing [`tokio::sync::Notify`] values.
+/// `s3invsync` for storing the signals sent once each version has been
+/// processed.
+///
+/// A key may be both a file and a directory prefix of later keys (e.g., `foo`
+/// and `foo/bar`).  In that case, the directory keeps its name, and the latest
+/// version of the file is stored under the name returned by
+/// [`conflict_file_name()`][crate::keypath::conflict_file_name].  As the file
+/// comes first in sorted order, its
+/// local name is not known until the following keys have been added; see
+/// [`HeldFiles`].
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(super) struct TreeTracker<T>(
     /// A stack of currently "open" directories, i.e., directories for which
@@ -37,12 +46,29 @@ pub(super) struct TreeTracker<T>(
     ///   `pd`, either `pd.entries.last()` or `pd.current_subdir` is
     ///   non-`None`.
     Vec<PartialDirectory<T>>,
+    /// Options for computing the local names of entries
+    NameOptions,
 );
 
+/// Options controlling how [`TreeTracker`] maps key components to local names
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct NameOptions {
+    /// If true, names in the same directory that differ only in case or
+    /// Unicode normalization are treated as colliding, and all but the first
+    /// such name (in sorted order) are given local names with
+    /// [`disambiguate_name()`][crate::keypath::disambiguate_name].
+    pub(crate) fold: bool,
+
+    /// If true, key components that are not valid Windows filenames are
+    /// escaped; see [`local_name()`].
+    pub(crate) portable: bool,
+}
+
 impl<T> TreeTracker<T> {
-    /// Create a new, empty `TreeTracker`
-    pub(super) fn new() -> Self {
-        TreeTracker(vec![PartialDirectory::new()])
+    /// Create a new, empty `TreeTracker` that computes local names using the
+    /// given options
+    pub(super) fn new(options: NameOptions) -> Self {
+        TreeTracker(vec![PartialDirectory::new(options)], options)
     }
 
     /// Register the key `key` with payload `value` and the given old filename
@@ -56,8 +82,7 @@ impl<T> TreeTracker<T> {
     ///
     /// This method returns `Err` if `key` is lexicographically less than the
     /// previous distinct key, if `key` has already been encountered as a
-    /// directory path, if a parent directory of `key` has already been
-    /// encountered as a file path, or if `key` equals the previous key and
+    /// directory path, or if `key` equals the previous key and
     /// this is the second time that `add()` was called with that key &
     /// `old_filename` value.
     pub(super) fn add(
@@ -124,7 +149,11 @@ impl<T> TreeTracker<T> {
                         }
                         (true, Some(Ordering::Equal)) => continue,
                         (false, Some(Ordering::Equal)) => {
-                            return Err(TreeTrackerError::Conflict(self.last_key()));
+                            // The directory has the same name as the
+                            // previous file; the file will be stored under
+                            // an escaped name.
+                            self.push_parts(name, partiter)?;
+                            break;
                         }
                         (_, Some(Ordering::Less)) => {
                             return Err(TreeTrackerError::Unsorted {
@@ -199,8 +228,9 @@ impl<T> TreeTracker<T> {
             pd.current_subdir.is_none(),
             "TreeTracker::push_dir() called when top dir has subdir"
         );
+        pd.register_name(name);
         pd.current_subdir = Some(name.to_owned());
-        self.0.push(PartialDirectory::new());
+        self.0.push(PartialDirectory::new(self.1));
     }
 
     /// Add the key with filename `name` to the current innermost open
@@ -260,9 +290,13 @@ impl<T> TreeTracker<T> {
                         return Err(TreeTrackerError::DuplicateFile(self.last_key()));
                     }
                 }
-                Ordering::Greater => pd.entries.push(Entry::file(name, value, old_filename)),
+                Ordering::Greater => {
+                    pd.register_name(name);
+                    pd.entries.push(Entry::file(name, value, old_filename));
+                }
             }
         } else {
+            pd.register_name(name);
             pd.entries.push(Entry::file(name, value, old_filename));
         }
         Ok(())
@@ -283,27 +317,63 @@ impl<T> TreeTracker<T> {
             pd.current_subdir.is_none(),
             "TreeTracker::pop() called when top dir has subdir"
         );
-        let entries = pd.entries;
-        let path = (!self.0.is_empty()).then(|| self.last_key());
+        let locals = pd
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, en)| pd.local_entry_name(en.name(), matches!(en, Entry::Dir { .. }), i + 1))
+            .collect::<Vec<_>>();
+        let collided = pd
+            .entries
+            .iter()
+            .map(|en| pd.collides(en.name()))
+            .collect::<Vec<_>>();
+        let path = (!self.0.is_empty()).then(|| self.local_dirpath());
+        let dirkey = (!self.0.is_empty()).then(|| self.last_key());
         if let Some(ppd) = self.0.last_mut() {
             ppd.close_current();
         }
         let mut files = HashMap::new();
         let mut directories = HashSet::new();
-        for en in entries {
+        let mut names = BTreeMap::new();
+        let mut collisions = Vec::new();
+        for ((en, local), collided) in pd.entries.into_iter().zip(locals).zip(collided) {
+            if collided {
+                let key = match dirkey {
+                    Some(ref dk) => format!("{dk}/{}", en.name()),
+                    None => en.name().to_owned(),
+                };
+                if matches!(en, Entry::Dir { .. }) {
+                    collisions.push(format!("{key}/"));
+                } else {
+                    collisions.push(key);
+                }
+            }
             match en {
                 Entry::File {
                     name,
                     value,
                     old_filenames,
                 } => {
-                    if let Some(value) = value {
-                        files.insert(name, value);
+                    let old_prefix = format!("{name}.old.");
+                    for of in old_filenames.keys() {
+                        if !of.starts_with(&old_prefix) {
+                            names.insert(of.clone(), name.clone());
+                        }
                     }
                     files.extend(old_filenames);
+                    if let Some(value) = value {
+                        if local != name {
+                            names.insert(local.clone(), name);
+                        }
+                        files.insert(local, value);
+                    }
                 }
                 Entry::Dir { name } => {
-                    directories.insert(name);
+                    if local != name {
+                        names.insert(local.clone(), name);
+                    }
+                    directories.insert(local);
                 }
             }
         }
@@ -311,6 +381,8 @@ impl<T> TreeTracker<T> {
             path,
             files,
             directories,
+            names,
+            collisions,
         }
     }
 
@@ -322,13 +394,13 @@ impl<T> TreeTracker<T> {
     /// more than one element yet one of them is empty.
     fn last_key(&self) -> String {
         let mut s = String::new();
-        for pd in &self.0 {
+        for (i, pd) in self.0.iter().enumerate() {
             if let Some(name) = pd
                 .current_subdir
                 .as_deref()
                 .or_else(|| pd.entries.last().map(Entry::name))
             {
-                if !s.is_empty() {
+                if i > 0 {
                     s.push('/');
                 }
                 s.push_str(name);
@@ -342,6 +414,139 @@ impl<T> TreeTracker<T> {
         }
         s
     }
+
+    /// Returns the local path (i.e., with each component converted via
+    /// [`local_name()`]) of the current innermost open directory, relative to
+    /// the root of the tree.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any element of the stack lacks a current subdirectory.
+    fn local_dirpath(&self) -> String {
+        let mut s = String::new();
+        for (i, pd) in self.0.iter().enumerate() {
+            let Some(name) = pd.current_subdir.as_deref() else {
+                panic!("TreeTracker::local_dirpath() called when stack has no open subdir");
+            };
+            if i > 0 {
+                s.push('/');
+            }
+            s.push_str(&pd.local_entry_name(name, true, pd.entries.len()));
+        }
+        s
+    }
+
+    /// Returns the local path, relative to the root of the tree, at which the
+    /// latest version of `key` is stored given the keys added to the tracker
+    /// so far.
+    ///
+    /// The result is only meaningful if `key` is not less than the previous
+    /// key added to the tracker.  If `key` is a file whose name may still be
+    /// taken by a directory, the result is only final once [`HeldFiles`]
+    /// releases it.
+    pub(super) fn local_path(&self, key: &KeyPath) -> LocalPath {
+        let mut dirname = None::<String>;
+        let mut stack = Some(self.0.iter());
+        let mut components = key.as_ref().split('/').peekable();
+        while let Some(name) = components.next() {
+            let is_dir = components.peek().is_some();
+            // Once `key` diverges from the currently-open directories, none
+            // of its further ancestors can have been seen yet, and so their
+            // entries cannot conflict or collide with anything.
+            let local = match stack.as_mut().and_then(Iterator::next) {
+                Some(pd) => {
+                    if pd.current_subdir.as_deref() != Some(name) {
+                        stack = None;
+                    }
+                    pd.local_entry_name(name, is_dir, pd.entries.len())
+                }
+                None => local_name(name, self.1.portable).into_owned(),
+            };
+            if is_dir {
+                match dirname {
+                    Some(ref mut d) => {
+                        d.push('/');
+                        d.push_str(&local);
+                    }
+                    None => dirname = Some(local),
+                }
+            } else {
+                return LocalPath {
+                    dirname,
+                    filename: local,
+                };
+            }
+        }
+        unreachable!("key should have at least one component")
+    }
+}
+
+/// The location at which the latest version of a key is backed up
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(super) struct LocalPath {
+    /// The forward-slash-separated path to the directory containing the
+    /// backup, relative to the root of the tree, or `None` for the root
+    pub(super) dirname: Option<String>,
+
+    /// The filename of the backup
+    pub(super) filename: String,
+}
+
+/// A collection of payloads for the latest versions of keys whose local paths
+/// are not yet known, as later keys may use the keys as directory prefixes.
+///
+/// A key `K` is held until a key is added to the [`TreeTracker`] that either
+/// starts with `K/` (in which case `K` is stored under
+/// [`conflict_file_name()`][crate::keypath::conflict_file_name]) or sorts
+/// after all keys of that form.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(super) struct HeldFiles<T>(Vec<(KeyPath, LocalPath, T)>);
+
+impl<T> HeldFiles<T> {
+    pub(super) fn new() -> Self {
+        HeldFiles(Vec::new())
+    }
+
+    /// Hold `value`, the payload for the latest version of `key`, which is to
+    /// be stored at `local` unless a directory with the same name follows.
+    /// `local` must have been computed before adding `key` to the tracker.
+    pub(super) fn hold(&mut self, key: KeyPath, local: LocalPath, value: T) {
+        self.0.push((key, local, value));
+    }
+
+    /// Release all held payloads whose local paths are determined by the
+    /// addition of `next` to `tracker`, paired with those paths.  This must
+    /// be called after each key is added to the tracker and before any new
+    /// payloads are held.
+    pub(super) fn release<U>(
+        &mut self,
+        next: &KeyPath,
+        tracker: &TreeTracker<U>,
+    ) -> Vec<(LocalPath, T)> {
+        let mut released = Vec::new();
+        let mut kept = Vec::new();
+        for (key, local, value) in self.0.drain(..) {
+            match next
+                .strip_prefix(key.as_ref())
+                .map(|rest| rest.chars().next())
+            {
+                Some(Some('/')) => released.push((tracker.local_path(&key), value)),
+                Some(None) => kept.push((key, local, value)),
+                Some(Some(c)) if c < '/' => kept.push((key, local, value)),
+                _ => released.push((local, value)),
+            }
+        }
+        self.0 = kept;
+        released
+    }
+
+    /// Release all remaining held payloads after the last key has been added
+    pub(super) fn finish(self) -> Vec<(LocalPath, T)> {
+        self.0
+            .into_iter()
+            .map(|(_, local, value)| (local, value))
+            .collect()
+    }
 }
 
 /// A directory, along with a collection of the names of the files &
@@ -359,6 +564,16 @@ pub(super) struct Directory<T> {
 
     /// A set of the names of the subdirectories within the directory
     directories: HashSet<String>,
+
+    /// A mapping from the names of files & subdirectories in the directory to
+    /// the key components they were derived from, for all entries whose names
+    /// differ from their key components (aside from the usual "old filename"
+    /// suffixes)
+    names: BTreeMap<String, String>,
+
+    /// The keys (with a trailing slash for directories) of all entries in the
+    /// directory that were given disambiguated names due to collisions
+    collisions: Vec<String>,
 }
 
 impl<T> Directory<T> {
@@ -379,6 +594,18 @@ impl<T> Directory<T> {
         self.directories.contains(name)
     }
 
+    /// Returns the mapping from local names of entries in the directory to
+    /// the original key components for entries whose names differ
+    pub(super) fn names(&self) -> &BTreeMap<String, String> {
+        &self.names
+    }
+
+    /// Returns the keys of entries in the directory that were given
+    /// disambiguated names due to colliding with other entries
+    pub(super) fn collisions(&self) -> &[String] {
+        &self.collisions
+    }
+
     /// Apply the given function to all file payloads in the directory
     pub(super) fn map<U, F: FnMut(T) -> U>(self, mut f: F) -> Directory<U> {
         Directory {
@@ -389,6 +616,8 @@ impl<T> Directory<T> {
                 .map(|(name, value)| (name, f(value)))
                 .collect(),
             directories: self.directories,
+            names: self.names,
+            collisions: self.collisions,
         }
     }
 }
@@ -406,7 +635,8 @@ pub(super) enum TreeTrackerError {
         after: String,
     },
 
-    /// A path was registered as both a file and a directory
+    /// A path was registered as a file after it was registered as a
+    /// directory
     #[error("path {0:?} is used as both a file and a directory")]
     Conflict(String),
 
@@ -424,10 +654,12 @@ pub(super) enum TreeTrackerError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::keypath::disambiguate_name;
+    use crate::util::make_old_filename;
 
     #[test]
     fn same_dir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -451,7 +683,7 @@ mod tests {
 
     #[test]
     fn different_dir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -478,7 +710,7 @@ mod tests {
 
     #[test]
     fn different_subdir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar/apple.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -508,7 +740,7 @@ mod tests {
 
     #[test]
     fn preslash_dir_then_toslash_dir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/apple!banana/gnusto.txt".parse::<KeyPath>().unwrap(),
@@ -542,7 +774,7 @@ mod tests {
 
     #[test]
     fn preslash_file_then_toslash_file() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/bar/apple!banana.txt".parse::<KeyPath>().unwrap(),
@@ -565,7 +797,7 @@ mod tests {
 
     #[test]
     fn tostash_file_then_preslash_file() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar/apple".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -596,7 +828,7 @@ mod tests {
 
     #[test]
     fn preslash_dir_then_toslash_file() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/apple!banana/gnusto.txt".parse::<KeyPath>().unwrap(),
@@ -619,7 +851,7 @@ mod tests {
 
     #[test]
     fn preslash_file_then_toslash_dir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/bar/apple!banana.txt".parse::<KeyPath>().unwrap(),
@@ -657,20 +889,145 @@ mod tests {
 
     #[test]
     fn path_conflict_file_then_dir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
+        let file = "foo/bar".parse::<KeyPath>().unwrap();
+        assert_eq!(tracker.add(&file, 1, None), Ok(Vec::new()));
+        let key = "foo/bar/apple.txt".parse::<KeyPath>().unwrap();
+        assert_eq!(tracker.local_path(&key).dirname.as_deref(), Some("foo/bar"));
+        assert_eq!(tracker.add(&key, 2, None), Ok(Vec::new()));
+        assert_eq!(
+            tracker.local_path(&file),
+            LocalPath {
+                dirname: Some("foo".into()),
+                filename: ".s3invsync.esc.bar%2F".into(),
+            }
+        );
+        let dirs = tracker.finish();
+        assert_eq!(dirs.len(), 3);
+        assert_eq!(dirs[0].path(), Some("foo/bar"));
+        assert_eq!(dirs[0].files, HashMap::from([("apple.txt".into(), 2)]));
+        assert!(dirs[0].directories.is_empty());
+        assert_eq!(dirs[1].path(), Some("foo"));
         assert_eq!(
-            tracker.add(&"foo/bar".parse::<KeyPath>().unwrap(), 1, None),
+            dirs[1].files,
+            HashMap::from([(".s3invsync.esc.bar%2F".into(), 1)])
+        );
+        assert_eq!(dirs[1].directories, HashSet::from(["bar".into()]));
+        assert_eq!(
+            dirs[1].names,
+            BTreeMap::from([(".s3invsync.esc.bar%2F".into(), "bar".into())])
+        );
+        assert_eq!(dirs[2].path(), None);
+        assert!(dirs[2].files.is_empty());
+        assert_eq!(dirs[2].directories, HashSet::from(["foo".into()]));
+        assert!(dirs[2].names.is_empty());
+    }
+
+    #[test]
+    fn path_conflict_file_then_other_file_then_dir() {
+        let mut tracker = TreeTracker::new(NameOptions::default());
+        assert_eq!(
+            tracker.add(&"foo".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
         );
-        let e = tracker
-            .add(&"foo/bar/apple.txt".parse::<KeyPath>().unwrap(), 2, None)
-            .unwrap_err();
-        assert_eq!(e, TreeTrackerError::Conflict("foo/bar".into()));
+        assert_eq!(
+            tracker.add(&"foo!bar".parse::<KeyPath>().unwrap(), 2, None),
+            Ok(Vec::new())
+        );
+        let key = "foo/quux/apple.txt".parse::<KeyPath>().unwrap();
+        assert_eq!(
+            tracker.local_path(&key).dirname.as_deref(),
+            Some("foo/quux")
+        );
+        assert_eq!(tracker.add(&key, 3, None), Ok(Vec::new()));
+        assert_eq!(
+            tracker
+                .local_path(&"foo0/banana.txt".parse::<KeyPath>().unwrap())
+                .dirname
+                .as_deref(),
+            Some("foo0")
+        );
+        let dirs = tracker.finish();
+        assert_eq!(dirs.len(), 3);
+        assert_eq!(dirs[0].path(), Some("foo/quux"));
+        assert_eq!(dirs[1].path(), Some("foo"));
+        assert_eq!(dirs[1].directories, HashSet::from(["quux".into()]));
+        assert_eq!(dirs[2].path(), None);
+        assert_eq!(
+            dirs[2].files,
+            HashMap::from([(".s3invsync.esc.foo%2F".into(), 1), ("foo!bar".into(), 2)])
+        );
+        assert_eq!(dirs[2].directories, HashSet::from(["foo".into()]));
+    }
+
+    #[test]
+    fn held_files() {
+        let mut tracker = TreeTracker::new(NameOptions::default());
+        let mut held = HeldFiles::new();
+        let mut add = |key: &str, held: &mut HeldFiles<&'static str>, name| {
+            let key = key.parse::<KeyPath>().unwrap();
+            let local = tracker.local_path(&key);
+            tracker.add(&key, (), None).unwrap();
+            let released = held.release(&key, &tracker);
+            held.hold(key, local, name);
+            released
+        };
+        assert_eq!(add("foo", &mut held, "foo"), Vec::new());
+        assert_eq!(add("foo!bar", &mut held, "foo!bar"), Vec::new());
+        assert_eq!(
+            add("foo!bar0", &mut held, "foo!bar0"),
+            vec![(
+                LocalPath {
+                    dirname: None,
+                    filename: "foo!bar".into()
+                },
+                "foo!bar"
+            )]
+        );
+        assert_eq!(
+            add("foo/apple.txt", &mut held, "foo/apple.txt"),
+            vec![
+                (
+                    LocalPath {
+                        dirname: None,
+                        filename: ".s3invsync.esc.foo%2F".into()
+                    },
+                    "foo"
+                ),
+                (
+                    LocalPath {
+                        dirname: None,
+                        filename: "foo!bar0".into()
+                    },
+                    "foo!bar0"
+                ),
+            ]
+        );
+        assert_eq!(
+            add("quux.txt", &mut held, "quux.txt"),
+            vec![(
+                LocalPath {
+                    dirname: Some("foo".into()),
+                    filename: "apple.txt".into()
+                },
+                "foo/apple.txt"
+            )]
+        );
+        assert_eq!(
+            held.finish(),
+            vec![(
+                LocalPath {
+                    dirname: None,
+                    filename: "quux.txt".into()
+                },
+                "quux.txt"
+            )]
+        );
     }
 
     #[test]
     fn path_conflict_dir_then_file() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar/quux.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -683,7 +1040,7 @@ mod tests {
 
     #[test]
     fn just_finish() {
-        let tracker = TreeTracker::<()>::new();
+        let tracker = TreeTracker::<()>::new(NameOptions::default());
         let dirs = tracker.finish();
         assert_eq!(dirs.len(), 1);
         assert_eq!(dirs[0].path(), None);
@@ -693,7 +1050,7 @@ mod tests {
 
     #[test]
     fn multidir_finish() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"apple/banana/coconut/date.txt".parse::<KeyPath>().unwrap(),
@@ -720,7 +1077,7 @@ mod tests {
 
     #[test]
     fn closedir_then_files_in_parent() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"apple/banana/coconut.txt".parse::<KeyPath>().unwrap(),
@@ -756,7 +1113,7 @@ mod tests {
 
     #[test]
     fn closedir_then_dirs_in_parent() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"apple/banana/coconut.txt".parse::<KeyPath>().unwrap(),
@@ -805,7 +1162,7 @@ mod tests {
 
     #[test]
     fn close_multiple_dirs() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"apple/banana/coconut/date.txt".parse::<KeyPath>().unwrap(),
@@ -836,7 +1193,7 @@ mod tests {
 
     #[test]
     fn same_file_twice() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar/quux.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -852,7 +1209,7 @@ mod tests {
 
     #[test]
     fn unsorted_parent_dirs() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/gnusto/quux.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -871,7 +1228,7 @@ mod tests {
 
     #[test]
     fn file_then_preceding_dir() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/gnusto.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -890,7 +1247,7 @@ mod tests {
 
     #[test]
     fn files_in_root() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo.txt".parse::<KeyPath>().unwrap(), 1, None),
             Ok(Vec::new())
@@ -918,7 +1275,7 @@ mod tests {
 
     #[test]
     fn old_filename() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/bar.txt".parse::<KeyPath>().unwrap(),
@@ -935,6 +1292,8 @@ mod tests {
                 path: Some("foo".into()),
                 files: HashMap::from([("bar.txt.old.1.2".into(), 1)]),
                 directories: HashSet::new(),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
         assert_eq!(
@@ -943,13 +1302,15 @@ mod tests {
                 path: None,
                 files: HashMap::new(),
                 directories: HashSet::from(["foo".into()]),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
     }
 
     #[test]
     fn multiple_old_filenames() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/bar.txt".parse::<KeyPath>().unwrap(),
@@ -977,6 +1338,8 @@ mod tests {
                     ("bar.txt.old.1.2".into(), 2),
                 ]),
                 directories: HashSet::new(),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
         assert_eq!(
@@ -985,13 +1348,15 @@ mod tests {
                 path: None,
                 files: HashMap::new(),
                 directories: HashSet::from(["foo".into()]),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
     }
 
     #[test]
     fn old_and_non_old() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/bar.txt".parse::<KeyPath>().unwrap(),
@@ -1012,6 +1377,8 @@ mod tests {
                 path: Some("foo".into()),
                 files: HashMap::from([("bar.txt.old.a.b".into(), 1), ("bar.txt".into(), 2),]),
                 directories: HashSet::new(),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
         assert_eq!(
@@ -1020,13 +1387,15 @@ mod tests {
                 path: None,
                 files: HashMap::new(),
                 directories: HashSet::from(["foo".into()]),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
     }
 
     #[test]
     fn non_old_and_old() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(&"foo/bar.txt".parse::<KeyPath>().unwrap(), 1, None,),
             Ok(Vec::new())
@@ -1047,6 +1416,8 @@ mod tests {
                 path: Some("foo".into()),
                 files: HashMap::from([("bar.txt".into(), 1), ("bar.txt.old.a.b".into(), 2),]),
                 directories: HashSet::new(),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
         assert_eq!(
@@ -1055,13 +1426,15 @@ mod tests {
                 path: None,
                 files: HashMap::new(),
                 directories: HashSet::from(["foo".into()]),
+                names: BTreeMap::new(),
+                collisions: Vec::new(),
             }
         );
     }
 
     #[test]
     fn duplicate_old_filenames() {
-        let mut tracker = TreeTracker::new();
+        let mut tracker = TreeTracker::new(NameOptions::default());
         assert_eq!(
             tracker.add(
                 &"foo/bar.txt".parse::<KeyPath>().unwrap(),
@@ -1085,4 +1458,187 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn escaped_names() {
+        let mut tracker = TreeTracker::new(NameOptions::default());
+        assert_eq!(
+            tracker.add(&"foo/../bar.txt".parse::<KeyPath>().unwrap(), 1, None),
+            Ok(Vec::new())
+        );
+        let dirs = tracker
+            .add(&"foo/quux/".parse::<KeyPath>().unwrap(), 2, None)
+            .unwrap();
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(dirs[0].path(), Some("foo/.s3invsync.esc.%2E%2E"));
+        assert_eq!(dirs[0].files, HashMap::from([("bar.txt".into(), 1)]));
+        assert!(dirs[0].directories.is_empty());
+        let dirs = tracker.finish();
+        assert_eq!(dirs.len(), 3);
+        assert_eq!(dirs[0].path(), Some("foo/quux"));
+        assert_eq!(
+            dirs[0].files,
+            HashMap::from([(".s3invsync.esc.".into(), 2)])
+        );
+        assert!(dirs[0].directories.is_empty());
+        assert_eq!(dirs[1].path(), Some("foo"));
+        assert!(dirs[1].files.is_empty());
+        assert_eq!(
+            dirs[1].directories,
+            HashSet::from([".s3invsync.esc.%2E%2E".into(), "quux".into()])
+        );
+        assert_eq!(dirs[2].path(), None);
+        assert!(dirs[2].files.is_empty());
+        assert_eq!(dirs[2].directories, HashSet::from(["foo".into()]));
+    }
+
+    #[test]
+    fn long_names() {
+        let long = "a".repeat(300);
+        let short = local_name(&long, false).into_owned();
+        assert_ne!(short, long);
+        let key = format!("{long}/{long}").parse::<KeyPath>().unwrap();
+        let mut tracker = TreeTracker::new(NameOptions::default());
+        assert_eq!(tracker.local_path(&key).dirname, Some(short.clone()));
+        assert_eq!(tracker.add(&key, 1, None), Ok(Vec::new()));
+        let old_filename = make_old_filename(&short, Some("abc"), "0123");
+        assert_eq!(
+            tracker.add(&key, 2, Some(old_filename.clone())),
+            Ok(Vec::new())
+        );
+        let dirs = tracker.finish();
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].path(), Some(&*short));
+        assert_eq!(
+            dirs[0].files,
+            HashMap::from([(short.clone(), 1), (old_filename.clone(), 2)])
+        );
+        assert_eq!(
+            dirs[0].names,
+            BTreeMap::from([(short.clone(), long.clone()), (old_filename, long.clone())])
+        );
+        assert_eq!(dirs[1].path(), None);
+        assert_eq!(dirs[1].directories, HashSet::from([short.clone()]));
+        assert_eq!(dirs[1].names, BTreeMap::from([(short, long)]));
+    }
+
+    #[test]
+    fn folded_collisions() {
+        let mut tracker = TreeTracker::new(NameOptions {
+            fold: true,
+            portable: false,
+        });
+        let mut dirs = Vec::new();
+        for (i, key) in ["Data.nwb", "Foo/bar.txt", "data.nwb", "foo/baz.txt"]
+            .into_iter()
+            .enumerate()
+        {
+            let key = key.parse::<KeyPath>().unwrap();
+            dirs.extend(tracker.add(&key, i, None).unwrap());
+        }
+        dirs.extend(tracker.finish());
+        assert_eq!(dirs.len(), 3);
+        let data = disambiguate_name("data.nwb", "data.nwb");
+        let foo = disambiguate_name("foo", "foo");
+        assert_eq!(dirs[0].path(), Some("Foo"));
+        assert_eq!(dirs[0].files, HashMap::from([("bar.txt".into(), 1)]));
+        assert_eq!(dirs[1].path(), Some(&*foo));
+        assert_eq!(dirs[1].files, HashMap::from([("baz.txt".into(), 3)]));
+        assert!(dirs[1].collisions.is_empty());
+        assert_eq!(dirs[2].path(), None);
+        assert_eq!(
+            dirs[2].files,
+            HashMap::from([("Data.nwb".into(), 0), (data.clone(), 2)])
+        );
+        assert_eq!(
+            dirs[2].directories,
+            HashSet::from(["Foo".into(), foo.clone()])
+        );
+        assert_eq!(
+            dirs[2].names,
+            BTreeMap::from([(data, "data.nwb".into()), (foo, "foo".into())])
+        );
+        assert_eq!(dirs[2].collisions, ["data.nwb", "foo/"]);
+    }
+
+    #[test]
+    fn folded_local_path() {
+        let mut tracker = TreeTracker::new(NameOptions {
+            fold: true,
+            portable: false,
+        });
+        tracker
+            .add(&"dir/caf\u{e9}".parse::<KeyPath>().unwrap(), 1, None)
+            .unwrap();
+        assert_eq!(
+            tracker.local_path(&"dir/caf\u{e9}".parse::<KeyPath>().unwrap()),
+            LocalPath {
+                dirname: Some("dir".into()),
+                filename: "caf\u{e9}".into(),
+            }
+        );
+        assert_eq!(
+            tracker.local_path(&"dir/cafe\u{301}".parse::<KeyPath>().unwrap()),
+            LocalPath {
+                dirname: Some("dir".into()),
+                filename: disambiguate_name("cafe\u{301}", "cafe\u{301}"),
+            }
+        );
+        assert_eq!(
+            tracker.local_path(&"dir/CAF\u{c9}/x".parse::<KeyPath>().unwrap()),
+            LocalPath {
+                dirname: Some(format!(
+                    "dir/{}",
+                    disambiguate_name("CAF\u{c9}", "CAF\u{c9}")
+                )),
+                filename: "x".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn unfolded_no_collisions() {
+        let mut tracker = TreeTracker::new(NameOptions::default());
+        tracker
+            .add(&"Data.nwb".parse::<KeyPath>().unwrap(), 1, None)
+            .unwrap();
+        tracker
+            .add(&"data.nwb".parse::<KeyPath>().unwrap(), 2, None)
+            .unwrap();
+        let dirs = tracker.finish();
+        assert_eq!(dirs.len(), 1);
+        assert_eq!(
+            dirs[0].files,
+            HashMap::from([("Data.nwb".into(), 1), ("data.nwb".into(), 2)])
+        );
+        assert!(dirs[0].collisions.is_empty());
+    }
+
+    #[test]
+    fn portable_names() {
+        let mut tracker = TreeTracker::new(NameOptions {
+            fold: false,
+            portable: true,
+        });
+        let key = "a:b/c?.txt".parse::<KeyPath>().unwrap();
+        assert_eq!(
+            tracker.local_path(&key),
+            LocalPath {
+                dirname: Some(".s3invsync.esc.a%3Ab".into()),
+                filename: ".s3invsync.esc.c%3F%2Etxt".into(),
+            }
+        );
+        assert_eq!(tracker.add(&key, 1, None), Ok(Vec::new()));
+        let dirs = tracker.finish();
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].path(), Some(".s3invsync.esc.a%3Ab"));
+        assert_eq!(
+            dirs[0].files,
+            HashMap::from([(".s3invsync.esc.c%3F%2Etxt".into(), 1)])
+        );
+        assert_eq!(
+            dirs[1].directories,
+            HashSet::from([".s3invsync.esc.a%3Ab".into()])
+        );
+    }
 }
@@ -1,4 +1,5 @@
 use super::util::Scanner;
+use serde::{de::Unexpected, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 use std::str::FromStr;
 use thiserror::Error;
@@ -50,6 +51,37 @@ impl FromStr for DateHM {
     }
 }
 
+impl Serialize for DateHM {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DateHM {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = DateHM;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a timestamp of the form YYYY-MM-DDTHH-MMZ")
+            }
+
+            fn visit_str<E>(self, input: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                input
+                    .parse::<DateHM>()
+                    .map_err(|_| E::invalid_value(Unexpected::Str(input), &self))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
 /// Error returned when parsing an invalid `DateHM` string
 #[derive(Copy, Clone, Debug, Eq, Error, PartialEq)]
 #[error("invalid timestamp format; expected YYYY-MM-DDTHH-MMZ")]
@@ -106,4 +138,19 @@ mod tests {
     fn display(#[case] it: DateHM, #[case] s: &str) {
         assert_eq!(it.to_string(), s);
     }
+
+    #[test]
+    fn serde_roundtrip() {
+        let d = DateHM {
+            year: 2025,
+            month: 3,
+            day: 14,
+            hour: 1,
+            minute: 2,
+        };
+        let json = serde_json::to_string(&d).unwrap();
+        assert_eq!(json, r#""2025-03-14T01-02Z""#);
+        assert_eq!(serde_json::from_str::<DateHM>(&json).unwrap(), d);
+        assert!(serde_json::from_str::<DateHM>(r#""2025-03-14""#).is_err());
+    }
 }
@@ -1,6 +1,11 @@
+use crate::consts::{MAX_NAME_LEN, RUN_TMPDIR_PREFIX};
+use crate::keypath::{is_temporary_name, shorten_name};
+use bytesize::ByteSize;
+use std::borrow::Cow;
 use std::fmt;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// An error type containing a collection of one or more errors that occurred
 /// concurrently during syncing
@@ -51,6 +56,87 @@ pub(crate) fn is_empty_dir(p: &Path) -> std::io::Result<bool> {
     }
 }
 
+/// Delete any per-run temporary directories (and files) left in `dir` by
+/// previous runs that did not exit cleanly
+pub(crate) fn sweep_tmpdir(dir: &Path) -> anyhow::Result<()> {
+    let entries = match fs_err::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|s| s.starts_with(RUN_TMPDIR_PREFIX))
+        {
+            continue;
+        }
+        let path = entry.path();
+        tracing::info!(path = %path.display(), "Deleting temporary files left by previous run");
+        if entry.file_type()?.is_dir() {
+            fs_err::remove_dir_all(&path)?;
+        } else {
+            fs_err::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// A running tally of the stale temporary files that have been deleted
+#[derive(Debug, Default)]
+pub(crate) struct SweepTally {
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl SweepTally {
+    /// Delete the stale temporary file at `path` and add it to the tally
+    pub(crate) fn delete(&self, path: &Path) {
+        let size = fs_err::symlink_metadata(path).map_or(0, |md| md.len());
+        match fs_err::remove_file(path) {
+            Ok(()) => {
+                tracing::debug!(path = %path.display(), "Deleted stale temporary file");
+                self.files.fetch_add(1, Ordering::Relaxed);
+                self.bytes.fetch_add(size, Ordering::Relaxed);
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, path = %path.display(), "Failed to delete stale temporary file");
+            }
+        }
+    }
+
+    /// Log the number & total size of the files deleted, if any
+    pub(crate) fn log_summary(&self) {
+        let files = self.files.load(Ordering::Relaxed);
+        if files > 0 {
+            let size = ByteSize(self.bytes.load(Ordering::Relaxed));
+            tracing::info!(files, size = %size, "Deleted stale temporary files left by previous runs");
+        }
+    }
+}
+
+/// Delete any temporary files directly inside `dir` left by previous runs
+/// that did not exit cleanly.  Temporary files in subdirectories are instead
+/// found when the directories are cleaned up and deleted at the end of the
+/// run.
+///
+/// This must only be called while holding the [`crate::runlock::RunLock`] for
+/// the backup, as otherwise the files could belong to a live run.
+pub(crate) fn sweep_temporaries(dir: &Path) -> anyhow::Result<()> {
+    let tally = SweepTally::default();
+    for entry in fs_err::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name().to_str().is_some_and(is_temporary_name)
+        {
+            tally.delete(&entry.path());
+        }
+    }
+    tally.log_summary();
+    Ok(())
+}
+
 /// If `p` is a directory or a symlink, delete it.  Returns `true` if `p`
 /// exists afterwards.
 pub(crate) async fn ensure_file(p: &Path) -> anyhow::Result<bool> {
@@ -108,11 +194,15 @@ pub(crate) fn force_create_dir_all<I: IntoIterator<Item: AsRef<Path>>>(
 }
 
 /// Construct the base filename for backing up an object that is not the latest
-/// version of its key, where `basename` is the filename portion of the key,
-/// `version_id` is the object's version ID, and `etag` is its etag.
+/// version of its key, where `basename` is the local filename of the key,
+/// `version_id` is the object's version ID, and `etag` is its etag.  If the
+/// result would be longer than [`MAX_NAME_LEN`] bytes, `basename` is shortened
+/// with [`shorten_name()`] so that it fits.
 pub(crate) fn make_old_filename(basename: &str, version_id: Option<&str>, etag: &str) -> String {
-    format!(
-        "{basename}.old.{v}.{etag}",
-        v = version_id.unwrap_or("null")
-    )
+    let suffix = format!(".old.{v}.{etag}", v = version_id.unwrap_or("null"));
+    let basename = shorten_name(
+        Cow::Borrowed(basename),
+        MAX_NAME_LEN.saturating_sub(suffix.len()),
+    );
+    format!("{basename}{suffix}")
 }